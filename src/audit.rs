@@ -0,0 +1,75 @@
+//! Recording changes to the registry in the audit log.
+//!
+//! Handlers record an entry once a change has been made. The entries
+//! themselves live in the database (see [`crate::database::AuditEntry`]).
+
+use crate::database::{Database, NewAuditEntry};
+use crate::Settings;
+use actix_web::{web, HttpRequest};
+
+/// The address the request came from.
+///
+/// Clients are free to set the `Forwarded` and `X-Forwarded-For` headers
+/// themselves, so those are only believed when the request came straight
+/// from one of the configured trusted proxies.
+pub fn source_ip(req: &HttpRequest) -> Option<String> {
+    let peer = req.peer_addr()?.ip();
+    let trusted = req
+        .app_data::<web::Data<Settings>>()
+        .is_some_and(|settings| settings.trusted_proxies.contains(&peer));
+    if trusted {
+        if let Some(forwarded) = req.connection_info().realip_remote_addr() {
+            return Some(forwarded.to_string());
+        }
+    }
+    Some(peer.to_string())
+}
+
+/// Write an entry to the audit log.
+///
+/// The change has already been made by the time it's recorded, so a failure
+/// here is logged rather than failing the request.
+pub async fn record(db: &dyn Database, entry: NewAuditEntry) {
+    if let Err(e) = db.add_audit_entry(entry.clone()).await {
+        log::error!("Failed to record audit entry {:?}: {}", entry, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers;
+    use actix_web::test::TestRequest;
+    use std::sync::Arc;
+
+    #[actix_rt::test]
+    async fn test_source_ip() {
+        let data_root = test_helpers::get_data_root();
+        let mut settings = Arc::try_unwrap(
+            test_helpers::get_test_settings(data_root.path())
+                .await
+                .into_inner(),
+        )
+        .ok()
+        .unwrap();
+        settings.trusted_proxies = vec!["10.0.0.1".parse().unwrap()];
+        let settings = web::Data::new(settings);
+        let req = |peer: &str| {
+            TestRequest::default()
+                .app_data(settings.clone())
+                .peer_addr(peer.parse().unwrap())
+                .insert_header(("X-Forwarded-For", "192.0.2.7"))
+                .to_http_request()
+        };
+
+        assert_eq!(
+            Some("192.0.2.7"),
+            source_ip(&req("10.0.0.1:1234")).as_deref()
+        );
+        assert_eq!(
+            Some("10.0.0.2"),
+            source_ip(&req("10.0.0.2:1234")).as_deref()
+        );
+        assert_eq!(None, source_ip(&TestRequest::default().to_http_request()));
+    }
+}
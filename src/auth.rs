@@ -5,7 +5,8 @@ use futures::{future::{LocalBoxFuture, Ready, ready}, FutureExt};
 
 use actix_web::{dev::{Transform, ServiceRequest, Service, ServiceResponse, forward_ready}, HttpMessage, HttpResponse, FromRequest, ResponseError, http::header, HttpRequest};
 
-use crate::database::{SqlDatabase, User, Database};
+use crate::database::{User, Database};
+use crate::errors::EstuaryError;
 
 #[derive(Debug, Clone)]
 pub enum AuthError {
@@ -28,7 +29,7 @@ impl ResponseError for AuthError {
                     .finish()
             },
             AuthError::Unauthorized => {
-                HttpResponse::Forbidden()
+                HttpResponse::Unauthorized()
                     .append_header(header::ContentType::json())
                     .body("{\"errors\": [{\"detail\": \"Unauthorized user\"}]}")
             },
@@ -102,11 +103,11 @@ impl Deref for OptionalAuthenticated {
 }
 
 pub struct AuthFactory {
-    db : Arc<SqlDatabase>
+    db : Arc<dyn Database>
 }
 
 impl AuthFactory {
-    pub fn new(db : Arc<SqlDatabase>) -> Self {
+    pub fn new(db : Arc<dyn Database>) -> Self {
         AuthFactory { db }
     }
 }
@@ -143,7 +144,7 @@ enum AuthMethod {
 
 pub struct AuthMiddelware<S> {
     service : Rc<S>,
-    db : Arc<SqlDatabase>
+    db : Arc<dyn Database>
 }
 
 impl<S, B> Service<ServiceRequest> for AuthMiddelware<S> 
@@ -163,7 +164,7 @@ where S : Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_w
             match auth_method(req.path()) {
                 AuthMethod::Session => {
                     if let Some(uid) = req.get_session().get::<i32>("uid")? {
-                        if let Some(user) = db.get_user_by_id(uid).await? {
+                        if let Some(user) = db.get_user_by_id(uid).await.map_err(EstuaryError::from)? {
                             req.extensions_mut().insert::<User>(user);
                         }
                     }
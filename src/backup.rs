@@ -0,0 +1,353 @@
+//! Backing up a whole registry to one file, and restoring it.
+//!
+//! A backup is a gzipped tarball holding:
+//!
+//! - `database.json`, every row of every table as a [`DatabaseDump`], which
+//!   can be loaded into any of the supported databases.
+//! - `index/`, the index repo, history and all.
+//! - `crates/`, the crate dir, with the `.crate` files and any docs.
+//!
+//! A restore unpacks the dirs into staging dirs next to their final place and
+//! only loads the database once everything unpacked cleanly, so a bad backup
+//! leaves the database untouched and the restore can be retried.
+
+use crate::database::{Database, DatabaseDump};
+use crate::errors::EstuaryError;
+use crate::storage::{invalid, sibling};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+
+const DATABASE_ENTRY: &str = "database.json";
+const INDEX_DIR: &str = "index";
+const CRATE_DIR: &str = "crates";
+
+/// Write a backup of the registry to `file`.
+///
+/// The backup is written next to `file` and only moved into place once it's
+/// complete, so an interrupted backup never leaves a truncated one behind. It
+/// holds every user's password hash and token, so only its owner can read it.
+pub async fn backup(
+    index_dir: &Path,
+    crate_dir: &Path,
+    db: &dyn Database,
+    file: &Path,
+) -> Result<(), EstuaryError> {
+    let dump = serde_json::to_vec(&db.dump().await?)?;
+
+    let mut partial = file.as_os_str().to_os_string();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let gz = GzEncoder::new(options.open(&partial)?, flate2::Compression::default());
+    let mut builder = tar::Builder::new(gz);
+    let mut header = tar::Header::new_gnu();
+    header.set_size(dump.len() as u64);
+    header.set_mode(0o600);
+    header.set_mtime(chrono::Utc::now().timestamp() as u64);
+    header.set_cksum();
+    builder.append_data(&mut header, DATABASE_ENTRY, &dump[..])?;
+    builder.append_dir_all(INDEX_DIR, index_dir)?;
+    if crate_dir.exists() {
+        builder.append_dir_all(CRATE_DIR, crate_dir)?;
+    }
+    builder.into_inner()?.finish()?.sync_all()?;
+
+    std::fs::rename(&partial, file)?;
+    Ok(())
+}
+
+/// What a restore put back.
+#[derive(Debug, Default)]
+pub struct Summary {
+    /// Rows loaded into the database.
+    pub rows: usize,
+    /// Files unpacked into the index and crate dirs.
+    pub files: usize,
+}
+
+/// Recreate a registry from a backup made by [`backup`].
+///
+/// The index and crate dirs must be empty or not exist yet, and the database
+/// must be freshly migrated. Only regular files and dirs are unpacked, links
+/// and anything else in the backup are refused.
+pub async fn restore(
+    file: &Path,
+    index_dir: &Path,
+    crate_dir: &Path,
+    db: &dyn Database,
+) -> Result<Summary, EstuaryError> {
+    for dir in [index_dir, crate_dir] {
+        if !is_empty_dir(dir)? {
+            return Err(invalid(format!(
+                "`{}` isn't empty, restore into fresh directories",
+                dir.display()
+            ))
+            .into());
+        }
+    }
+
+    let staging_index = sibling(index_dir, "restore");
+    let staging_crates = sibling(crate_dir, "restore");
+    let result = restore_staged(file, &staging_index, &staging_crates, db).await;
+    if result.is_ok() {
+        for (staging, dir) in [(&staging_index, index_dir), (&staging_crates, crate_dir)] {
+            // Only ever empty here, checked above.
+            if dir.exists() {
+                std::fs::remove_dir(dir)?;
+            }
+            std::fs::rename(staging, dir)?;
+        }
+    } else {
+        for staging in [&staging_index, &staging_crates] {
+            if staging.exists() {
+                std::fs::remove_dir_all(staging)?;
+            }
+        }
+    }
+    result
+}
+
+/// Unpack `file` into the staging dirs, then load the database.
+async fn restore_staged(
+    file: &Path,
+    index_dir: &Path,
+    crate_dir: &Path,
+    db: &dyn Database,
+) -> Result<Summary, EstuaryError> {
+    for dir in [index_dir, crate_dir] {
+        if dir.exists() {
+            std::fs::remove_dir_all(dir)?;
+        }
+        std::fs::create_dir_all(dir)?;
+    }
+
+    let mut summary = Summary::default();
+    let mut dump = None;
+    let mut archive = tar::Archive::new(GzDecoder::new(File::open(file)?));
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+
+        if path == Path::new(DATABASE_ENTRY) {
+            let mut json = vec![];
+            entry.read_to_end(&mut json)?;
+            dump = Some(serde_json::from_slice::<DatabaseDump>(&json)?);
+            continue;
+        }
+
+        let kind = entry.header().entry_type();
+        if !kind.is_file() && !kind.is_dir() {
+            return Err(invalid(format!("`{}` isn't a file or dir", path.display())).into());
+        }
+        let dest = destination(&path, index_dir, crate_dir)?;
+        if let Some(parent) = dest.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        entry.unpack(&dest)?;
+        if kind.is_file() {
+            summary.files += 1;
+        }
+    }
+
+    let dump = dump.ok_or_else(|| invalid(format!("`{}` has no database", file.display())))?;
+    summary.rows = dump.tables.values().map(Vec::len).sum();
+    db.restore(dump).await?;
+    Ok(summary)
+}
+
+/// Where an entry in the backup should be unpacked to.
+fn destination(path: &Path, index_dir: &Path, crate_dir: &Path) -> io::Result<PathBuf> {
+    let mut components = path.components();
+    let root = match components.next() {
+        Some(Component::Normal(top)) if top == INDEX_DIR => index_dir,
+        Some(Component::Normal(top)) if top == CRATE_DIR => crate_dir,
+        _ => return Err(invalid(format!("unexpected `{}`", path.display()))),
+    };
+    let rest = components.as_path();
+    if !rest
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return Err(invalid(format!("unsafe path `{}`", path.display())));
+    }
+    Ok(root.join(rest))
+}
+
+fn is_empty_dir(dir: &Path) -> io::Result<bool> {
+    match std::fs::read_dir(dir) {
+        Ok(mut entries) => Ok(entries.next().is_none()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(true),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{AuditAction, NewAuditEntry, NewCrateVersion};
+    use crate::package_index::{Config, PackageIndex};
+    use crate::storage;
+    use crate::test_helpers;
+
+    #[actix_rt::test]
+    async fn test_backup_and_restore() {
+        let config = Config {
+            dl: String::new(),
+            api: String::new(),
+        };
+        let data_root = test_helpers::get_data_root();
+        let settings = test_helpers::get_test_settings(data_root.path()).await;
+        let db = settings.db.as_ref();
+
+        let index = PackageIndex::init(&settings.index_dir, &config).unwrap();
+        let pkg = test_helpers::pkg("foo", "0.1.0");
+        index.publish(&pkg, None).unwrap();
+        drop(index);
+        storage::store_crate_file(&settings.crate_dir, "foo", &pkg.vers, b"foo").unwrap();
+
+        let admin = db.get_user(String::from("admin")).await.unwrap().unwrap();
+        let krate = db.create_crate(String::from("foo")).await.unwrap();
+        db.add_crate_version(
+            krate.id,
+            admin.id,
+            NewCrateVersion {
+                vers: String::from("0.1.0"),
+                description: Some(String::from("A crate")),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        db.add_audit_entry(NewAuditEntry {
+            uid: Some(admin.id),
+            action: AuditAction::Publish,
+            crate_name: Some(String::from("foo")),
+            vers: Some(String::from("0.1.0")),
+            detail: None,
+            ip: Some(String::from("127.0.0.1")),
+        })
+        .await
+        .unwrap();
+
+        let file = data_root.path().join("backup.tar.gz");
+        backup(&settings.index_dir, &settings.crate_dir, db, &file)
+            .await
+            .unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&file).unwrap().permissions().mode();
+            assert_eq!(0o600, mode & 0o777);
+        }
+
+        // Somewhere else entirely.
+        let new_root = test_helpers::get_data_root();
+        let new_settings = test_helpers::get_test_settings(new_root.path()).await;
+        let new_db = new_settings.db.as_ref();
+        let summary = restore(
+            &file,
+            &new_settings.index_dir,
+            &new_settings.crate_dir,
+            new_db,
+        )
+        .await
+        .unwrap();
+        assert!(summary.files > 2);
+
+        let dumped = db.dump().await.unwrap().tables;
+        assert_eq!(summary.rows, dumped.values().map(Vec::len).sum::<usize>());
+        assert_eq!(
+            serde_json::to_value(dumped).unwrap(),
+            serde_json::to_value(new_db.dump().await.unwrap().tables).unwrap()
+        );
+        assert_eq!(
+            b"foo".to_vec(),
+            std::fs::read(storage::get_crate_file_path(
+                &new_settings.crate_dir,
+                "foo",
+                &pkg.vers
+            ))
+            .unwrap()
+        );
+        let index = PackageIndex::init(&new_settings.index_dir, &config).unwrap();
+        assert_eq!(vec![pkg], index.get_package_versions("foo").unwrap());
+
+        // New rows carry on from the restored ones.
+        let bar = new_db.create_crate(String::from("bar")).await.unwrap();
+        assert_ne!(krate.id, bar.id);
+
+        // Restoring over a registry in use is refused.
+        assert!(restore(&file, &settings.index_dir, &settings.crate_dir, db)
+            .await
+            .is_err());
+        let empty = test_helpers::get_data_root();
+        assert!(matches!(
+            restore(
+                &file,
+                &empty.path().join("index"),
+                &empty.path().join("crates"),
+                db
+            )
+            .await,
+            Err(EstuaryError::Database(
+                crate::database::DatabaseError::NotEmpty
+            ))
+        ));
+        // Nothing is left behind when the database refuses the restore.
+        assert_eq!(0, std::fs::read_dir(empty.path()).unwrap().count());
+    }
+
+    #[actix_rt::test]
+    async fn test_restore_refuses_links() {
+        let data_root = test_helpers::get_data_root();
+        let settings = test_helpers::get_test_settings(data_root.path()).await;
+        let db = settings.db.as_ref();
+
+        let file = data_root.path().join("backup.tar.gz");
+        let gz = GzEncoder::new(File::create(&file).unwrap(), flate2::Compression::default());
+        let mut builder = tar::Builder::new(gz);
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        header.set_cksum();
+        builder
+            .append_link(&mut header, "crates/escape", "/etc")
+            .unwrap();
+        db.create_crate(String::from("foo")).await.unwrap();
+        let dump = serde_json::to_vec(&db.dump().await.unwrap()).unwrap();
+        let mut header = tar::Header::new_gnu();
+        header.set_size(dump.len() as u64);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, DATABASE_ENTRY, &dump[..])
+            .unwrap();
+        builder.into_inner().unwrap().finish().unwrap();
+
+        let new_root = test_helpers::get_data_root();
+        let new_settings = test_helpers::get_test_settings(new_root.path()).await;
+        let new_db = new_settings.db.as_ref();
+        assert!(restore(
+            &file,
+            &new_settings.index_dir,
+            &new_settings.crate_dir,
+            new_db
+        )
+        .await
+        .is_err());
+        assert!(is_empty_dir(&new_settings.crate_dir).unwrap());
+        assert!(!sibling(&new_settings.crate_dir, "restore").exists());
+        // The database was never touched, so a good backup can still go in.
+        assert!(new_db
+            .get_crate(String::from("foo"))
+            .await
+            .unwrap()
+            .is_none());
+    }
+}
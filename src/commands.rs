@@ -0,0 +1,238 @@
+//! Admin tasks run from the command line in place of the server.
+//!
+//! These work on the same storage as the server, so they take the same
+//! configuration.
+
+use crate::backup;
+use crate::cli::{Command, ConfigCommand};
+use crate::config::ServerConfig;
+use crate::database::{Database, SqlDatabase};
+use crate::deletion::{self, Deletion};
+use crate::errors::EstuaryError;
+use crate::fsck;
+use crate::import;
+use crate::package_index::{Config, IndexHandle, PackageIndex};
+use crate::Settings;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Run the given admin task to completion.
+pub async fn run(cmd: Command, args: &ServerConfig, config: &Config) -> Result<(), EstuaryError> {
+    match cmd {
+        Command::SquashIndex => squash_index(args, config),
+        Command::DeleteVersion {
+            crate_name,
+            version,
+            block,
+        } => delete_version(args, config, crate_name, version, block).await,
+        Command::Fsck { repair } => fsck(args, repair).await,
+        Command::Reindex => reindex(args, config).await,
+        Command::Backup { file } => backup(args, &file).await,
+        Command::Restore { file } => restore(args, &file).await,
+        Command::Import {
+            index,
+            dl,
+            crates,
+            owner,
+        } => {
+            let source = import::Source {
+                index,
+                dl,
+                crate_dir: crates,
+            };
+            import(args, config, &source, &owner).await
+        }
+        Command::Config(ConfigCommand::Check) => {
+            // Loading the configuration has already checked it.
+            print!("{}", args.to_masked_toml());
+            Ok(())
+        }
+    }
+}
+
+/// Connect to the database and open the index, as the server would.
+async fn settings(args: &ServerConfig) -> Result<Settings, EstuaryError> {
+    let db = SqlDatabase::new(
+        args.db_uri.clone(),
+        args.db_max_connections,
+        Duration::from_secs(args.db_timeout_s.into()),
+    )
+    .await?;
+    db.migrate().await?;
+    Ok(Settings {
+        base_url: args.base_url().to_string(),
+        crate_dir: args.crate_dir.clone(),
+        index_dir: args.index_dir.clone(),
+        publish_key: args.publish_key.clone(),
+        redis_uri: None,
+        trusted_proxies: vec![],
+        db: Arc::new(db),
+    })
+}
+
+/// Squash the index history.
+///
+/// This writes to the index directly rather than through the server's writer,
+/// so the server has to be stopped first.
+fn squash_index(args: &ServerConfig, config: &Config) -> Result<(), EstuaryError> {
+    let index = PackageIndex::init(&args.index_dir, config)?;
+    match index.squash()? {
+        Some(archive) => println!("Squashed index history, archived to `{}`.", archive),
+        None => println!("No index history to squash."),
+    }
+    Ok(())
+}
+
+/// Delete a version from the registry.
+async fn delete_version(
+    args: &ServerConfig,
+    config: &Config,
+    crate_name: String,
+    version: semver::Version,
+    block: bool,
+) -> Result<(), EstuaryError> {
+    let settings = settings(args).await?;
+    let index = IndexHandle::spawn(PackageIndex::init(&settings.index_dir, config)?);
+    let deletion = Deletion {
+        crate_name: crate_name.clone(),
+        version: version.clone(),
+        block,
+        uid: None,
+        author: None,
+        ip: None,
+    };
+    deletion::delete_version::<EstuaryError>(&index, &settings, deletion).await?;
+    println!("Deleted `{} v{}`.", crate_name, version);
+    Ok(())
+}
+
+/// Check the registry for inconsistencies, repairing the safe ones if asked.
+///
+/// Run this while the server is stopped, or it may catch a publish half way
+/// through.
+async fn fsck(args: &ServerConfig, repair: bool) -> Result<(), EstuaryError> {
+    let settings = settings(args).await?;
+    let index = PackageIndex::open(&settings.index_dir)?;
+    let problems = fsck::check(&index, &settings.crate_dir, settings.db.as_ref()).await?;
+
+    let mut left = 0;
+    for problem in &problems {
+        let repaired = if repair {
+            fsck::repair(problem, &settings.crate_dir, settings.db.as_ref()).await?
+        } else {
+            None
+        };
+        match repaired {
+            Some(action) => println!("{}: repaired, {}", problem, action),
+            None => {
+                left += 1;
+                println!("{}", problem);
+            }
+        }
+    }
+
+    let repairable = problems.iter().filter(|p| p.is_repairable()).count();
+    println!(
+        "{} problems found, {} repaired.",
+        problems.len(),
+        problems.len() - left
+    );
+    if !repair && repairable > 0 {
+        println!("{} can be repaired with `--repair`.", repairable);
+    }
+    if left > 0 {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// Rebuild the index from crate storage.
+async fn reindex(args: &ServerConfig, config: &Config) -> Result<(), EstuaryError> {
+    let settings = settings(args).await?;
+    let summary = crate::reindex::reindex(
+        &settings.index_dir,
+        &settings.crate_dir,
+        config,
+        settings.db.as_ref(),
+    )
+    .await?;
+
+    for (path, reason) in &summary.skipped {
+        println!("Skipped `{}`: {}", path.display(), reason);
+    }
+    println!(
+        "Indexed {} versions ({} yanked), skipped {}.",
+        summary.indexed,
+        summary.yanked,
+        summary.skipped.len()
+    );
+    if let Some(old) = summary.old_index {
+        println!("The old index was moved to `{}`.", old.display());
+    }
+    Ok(())
+}
+
+/// Back up the registry to `file`.
+async fn backup(args: &ServerConfig, file: &Path) -> Result<(), EstuaryError> {
+    let settings = settings(args).await?;
+    backup::backup(
+        &settings.index_dir,
+        &settings.crate_dir,
+        settings.db.as_ref(),
+        file,
+    )
+    .await?;
+    println!("Backed up to `{}`.", file.display());
+    Ok(())
+}
+
+/// Restore the registry from a backup.
+async fn restore(args: &ServerConfig, file: &Path) -> Result<(), EstuaryError> {
+    let settings = settings(args).await?;
+    let summary = backup::restore(
+        file,
+        &settings.index_dir,
+        &settings.crate_dir,
+        settings.db.as_ref(),
+    )
+    .await?;
+    println!(
+        "Restored {} files and {} database rows from `{}`.",
+        summary.files,
+        summary.rows,
+        file.display()
+    );
+    Ok(())
+}
+
+/// Import crates from another registry or a directory of `.crate` files.
+async fn import(
+    args: &ServerConfig,
+    config: &Config,
+    source: &import::Source,
+    owner: &str,
+) -> Result<(), EstuaryError> {
+    let settings = settings(args).await?;
+    let summary = import::import(
+        source,
+        &settings.index_dir,
+        &settings.crate_dir,
+        config,
+        settings.db.as_ref(),
+        owner,
+    )
+    .await?;
+
+    for (version, reason) in &summary.skipped {
+        println!("Skipped `{}`: {}", version, reason);
+    }
+    println!(
+        "Imported {} versions ({} yanked), {} already here, skipped {}.",
+        summary.imported,
+        summary.yanked,
+        summary.existing,
+        summary.skipped.len()
+    );
+    Ok(())
+}
@@ -0,0 +1,199 @@
+//! Looking inside stored `.crate` files.
+//!
+//! A `.crate` file is a gzipped tarball with every file under a
+//! `{name}-{version}/` directory. Rather than unpacking it anywhere, the
+//! tarball is read from storage as a stream each time, stopping as soon as the
+//! requested file turns up.
+
+use flate2::read::GzDecoder;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+/// Files bigger than this are listed, but not shown.
+pub const MAX_FILE_SIZE: u64 = 1024 * 1024;
+
+/// Something inside a directory of the crate.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SourceEntry {
+    /// The name of the entry within its directory.
+    pub name: String,
+    /// The path of the entry from the root of the crate.
+    pub path: String,
+    pub is_dir: bool,
+    /// The size of a file, in bytes. Always 0 for directories.
+    pub size: u64,
+}
+
+/// What's at a path inside the crate.
+#[derive(Debug, PartialEq)]
+pub enum Source {
+    /// A directory's entries, directories first, then by name.
+    Dir(Vec<SourceEntry>),
+    /// A file's size, and its contents unless it's bigger than
+    /// [`MAX_FILE_SIZE`].
+    File {
+        size: u64,
+        contents: Option<Vec<u8>>,
+    },
+}
+
+/// Look up a path inside a `.crate` file.
+///
+/// The path is relative to the root of the crate, with the empty string being
+/// the root itself. Returns `None` when there's nothing at the path.
+pub fn browse<P: AsRef<Path>>(
+    crate_file: P,
+    name: &str,
+    vers: &semver::Version,
+    path: &str,
+) -> io::Result<Option<Source>> {
+    let fh = BufReader::new(File::open(crate_file)?);
+    let mut archive = tar::Archive::new(GzDecoder::new(fh));
+
+    let prefix = format!("{}-{}/", name, vers);
+    let path = path.trim_matches('/');
+    let dir_prefix = if path.is_empty() {
+        String::new()
+    } else {
+        format!("{}/", path)
+    };
+    let mut children: BTreeMap<(bool, String), SourceEntry> = BTreeMap::new();
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let entry_path = entry.path()?.to_string_lossy().into_owned();
+        let entry_path = match entry_path.strip_prefix(&prefix) {
+            Some(entry_path) => entry_path.to_string(),
+            None => continue,
+        };
+
+        if entry_path == path {
+            let size = entry.size();
+            let contents = if size <= MAX_FILE_SIZE {
+                let mut buf = Vec::with_capacity(size as usize);
+                entry.read_to_end(&mut buf)?;
+                Some(buf)
+            } else {
+                None
+            };
+            return Ok(Some(Source::File { size, contents }));
+        }
+
+        if let Some(rest) = entry_path.strip_prefix(&dir_prefix) {
+            let (child, is_dir) = match rest.find('/') {
+                Some(idx) => (&rest[..idx], true),
+                None => (rest, false),
+            };
+            // Directories sort before files.
+            children
+                .entry((!is_dir, child.to_string()))
+                .or_insert_with(|| SourceEntry {
+                    name: child.to_string(),
+                    path: format!("{}{}", dir_prefix, child),
+                    is_dir,
+                    size: if is_dir { 0 } else { entry.size() },
+                });
+        }
+    }
+
+    if children.is_empty() && !path.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(Source::Dir(children.into_values().collect())))
+}
+
+/// Read the `Cargo.toml` of a `.crate` file.
+///
+/// This is the manifest as rewritten by `cargo publish`, so dependencies
+/// only have the fields that matter to a registry.
+pub fn read_manifest<P: AsRef<Path>>(
+    crate_file: P,
+    name: &str,
+    vers: &semver::Version,
+) -> io::Result<toml::Value> {
+    let contents = match browse(crate_file, name, vers, "Cargo.toml")? {
+        Some(Source::File {
+            contents: Some(contents),
+            ..
+        }) => contents,
+        Some(Source::File { contents: None, .. }) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Cargo.toml is too big",
+            ))
+        }
+        _ => return Err(io::Error::new(io::ErrorKind::NotFound, "no Cargo.toml")),
+    };
+    let contents = std::str::from_utf8(&contents)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    toml::from_str(contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use tempdir::TempDir;
+
+    fn write_crate(dir: &Path, files: &[(&str, &[u8])]) -> std::path::PathBuf {
+        let path = dir.join("foo-0.1.0.crate");
+        let gz = GzEncoder::new(File::create(&path).unwrap(), flate2::Compression::default());
+        let mut builder = tar::Builder::new(gz);
+        for (name, contents) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, format!("foo-0.1.0/{}", name), *contents)
+                .unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap();
+        path
+    }
+
+    #[test]
+    fn test_browse() {
+        let root = TempDir::new("test_browse").unwrap();
+        let crate_file = write_crate(
+            root.path(),
+            &[
+                ("Cargo.toml", b"[package]"),
+                ("src/lib.rs", b"pub mod a;"),
+                ("src/a/mod.rs", b""),
+            ],
+        );
+        let vers = "0.1.0".parse().unwrap();
+
+        let entries = match browse(&crate_file, "foo", &vers, "").unwrap() {
+            Some(Source::Dir(entries)) => entries,
+            other => panic!("expected a dir, got {:?}", other),
+        };
+        let names: Vec<_> = entries.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(vec!["src", "Cargo.toml"], names);
+        assert!(entries[0].is_dir);
+        assert_eq!(9, entries[1].size);
+
+        let entries = match browse(&crate_file, "foo", &vers, "src/").unwrap() {
+            Some(Source::Dir(entries)) => entries,
+            other => panic!("expected a dir, got {:?}", other),
+        };
+        let names: Vec<_> = entries.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(vec!["src/a", "src/lib.rs"], names);
+
+        assert_eq!(
+            Some(Source::File {
+                size: 10,
+                contents: Some(b"pub mod a;".to_vec())
+            }),
+            browse(&crate_file, "foo", &vers, "src/lib.rs").unwrap()
+        );
+        assert_eq!(None, browse(&crate_file, "foo", &vers, "src/b.rs").unwrap());
+        assert_eq!(None, browse(&crate_file, "foo", &vers, "src/lib").unwrap());
+    }
+}
@@ -1,10 +1,11 @@
-use std::{time::Duration, fmt::Display, str::Utf8Error};
+use std::{collections::{BTreeMap, HashMap, HashSet}, time::Duration, fmt::Display, str::Utf8Error};
 
-use actix_web::{ResponseError, HttpResponse};
+use actix_web::{ResponseError, HttpResponse, http::StatusCode};
 use argon2::{password_hash::{SaltString, rand_core::{OsRng, RngCore}}, Argon2, PasswordHasher, PasswordVerifier, PasswordHash};
 use base64::DecodeError;
 use futures::{future::{LocalBoxFuture}, FutureExt};
-use sqlx::{AnyPool, any::AnyPoolOptions};
+use once_cell::sync::Lazy;
+use sqlx::{AnyPool, FromRow, Row, any::{AnyKind, AnyPoolOptions, AnyRow}};
 
 #[derive(Clone, Debug)]
 pub struct SqlDatabase {
@@ -29,7 +30,35 @@ pub trait Database : std::fmt::Debug + Send + Sync {
     fn add_crate_owner(&self, cid : i32, uid : i32) -> LocalBoxFuture<Result<(), DatabaseError>>;
     fn remove_crate_owner(&self, cid : i32, uid : i32) -> LocalBoxFuture<Result<(), DatabaseError>>;
     fn get_crate_owners(&self, name : String) -> LocalBoxFuture<Result<Vec<User>, DatabaseError>>;
+    fn get_crate(&self, name : String) -> LocalBoxFuture<Result<Option<Crate>, DatabaseError>>;
+    fn add_crate_version(&self, cid : i32, uid : i32, version : NewCrateVersion) -> LocalBoxFuture<Result<(), DatabaseError>>;
+    fn get_crate_versions(&self, name : String) -> LocalBoxFuture<Result<Vec<CrateVersion>, DatabaseError>>;
+    /// Like `get_crate_versions`, for several crates in one go, keyed by crate name.
+    fn get_crate_versions_for(&self, names : Vec<String>) -> LocalBoxFuture<Result<HashMap<String, Vec<CrateVersion>>, DatabaseError>>;
+    fn delete_crate_version(&self, name : String, vers : String) -> LocalBoxFuture<Result<(), DatabaseError>>;
+    /// The name and version of every crate version, in the order they were published.
+    fn list_crate_versions(&self) -> LocalBoxFuture<Result<Vec<(String, String)>, DatabaseError>>;
+    fn block_version(&self, name : String, vers : String) -> LocalBoxFuture<Result<(), DatabaseError>>;
+    fn is_version_blocked(&self, name : String, vers : String) -> LocalBoxFuture<Result<bool, DatabaseError>>;
+    fn add_audit_entry(&self, entry : NewAuditEntry) -> LocalBoxFuture<Result<(), DatabaseError>>;
+    fn get_audit_entries(&self, query : AuditQuery) -> LocalBoxFuture<Result<Vec<AuditEntry>, DatabaseError>>;
+    /// Record whether a crate version is yanked, mirroring the index.
+    fn set_yanked(&self, name : String, vers : String, yanked : bool) -> LocalBoxFuture<Result<(), DatabaseError>>;
+    /// The name and version of every yanked crate version.
+    fn get_yanked_versions(&self) -> LocalBoxFuture<Result<HashSet<(String, String)>, DatabaseError>>;
+    fn add_webhook(&self, url : String, secret : String, events : String) -> LocalBoxFuture<Result<Webhook, DatabaseError>>;
+    fn get_webhooks(&self) -> LocalBoxFuture<Result<Vec<Webhook>, DatabaseError>>;
+    fn get_webhook(&self, id : i32) -> LocalBoxFuture<Result<Option<Webhook>, DatabaseError>>;
+    fn delete_webhook(&self, id : i32) -> LocalBoxFuture<Result<(), DatabaseError>>;
+    fn add_webhook_delivery(&self, delivery : NewWebhookDelivery) -> LocalBoxFuture<Result<(), DatabaseError>>;
+    fn get_webhook_deliveries(&self, webhook_id : i32, limit : i64) -> LocalBoxFuture<Result<Vec<WebhookDelivery>, DatabaseError>>;
     fn migrate(&self) -> LocalBoxFuture<Result<(), DatabaseError>>;
+    /// Read every row of every table.
+    fn dump(&self) -> LocalBoxFuture<Result<DatabaseDump, DatabaseError>>;
+    /// Load a dump into a freshly migrated database, replacing the default admin user.
+    fn restore(&self, dump : DatabaseDump) -> LocalBoxFuture<Result<(), DatabaseError>>;
+    fn pool_status(&self) -> PoolStatus;
+    fn ping(&self) -> LocalBoxFuture<Result<(), DatabaseError>>;
 }
 
 impl Database for SqlDatabase {
@@ -181,11 +210,15 @@ impl Database for SqlDatabase {
 
     fn revoke_api_key(&self, id : i32, uid : i32) -> LocalBoxFuture<Result<(), DatabaseError>>{
         async move {
-            sqlx::query("DELETE FROM api_keys WHERE id=$1 AND uid=$2")
+            let res = sqlx::query("DELETE FROM api_keys WHERE id=$1 AND uid=$2")
                 .bind(id)
                 .bind(uid)
                 .execute(&self.pool).await?;
 
+            if res.rows_affected() == 0 {
+                return Err(DatabaseError::NotFound);
+            }
+
             Ok(())
         }.boxed_local()
     }
@@ -247,16 +280,410 @@ impl Database for SqlDatabase {
         }.boxed_local()
     }
 
+    fn get_crate(&self, name : String) -> LocalBoxFuture<Result<Option<Crate>, DatabaseError>> {
+        async move {
+            let res : Option<Crate> = sqlx::query_as("SELECT * FROM crates WHERE name=$1")
+                .bind(name.as_str())
+                .fetch_optional(&self.pool).await?;
+
+            Ok(res)
+        }.boxed_local()
+    }
+
+    fn add_crate_version(&self, cid : i32, uid : i32, version : NewCrateVersion) -> LocalBoxFuture<Result<(), DatabaseError>> {
+        async move {
+            sqlx::query("INSERT INTO crate_versions
+                (cid, uid, vers, description, documentation, homepage, repository, license, keywords, categories, authors, crate_size, created_at)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)")
+                .bind(cid)
+                .bind(uid)
+                .bind(version.vers)
+                .bind(version.description)
+                .bind(version.documentation)
+                .bind(version.homepage)
+                .bind(version.repository)
+                .bind(version.license)
+                .bind(serde_json::to_string(&version.keywords).unwrap_or_default())
+                .bind(serde_json::to_string(&version.categories).unwrap_or_default())
+                .bind(serde_json::to_string(&version.authors).unwrap_or_default())
+                .bind(version.crate_size)
+                .bind(unix_now())
+                .execute(&self.pool).await?;
+
+            Ok(())
+        }.boxed_local()
+    }
+
+    fn get_crate_versions(&self, name : String) -> LocalBoxFuture<Result<Vec<CrateVersion>, DatabaseError>> {
+        async move {
+            let res : Vec<CrateVersion> = sqlx::query_as(
+                "SELECT crate_versions.*, users.name AS published_by
+                    FROM crate_versions INNER JOIN crates ON crate_versions.cid=crates.id
+                    LEFT JOIN users ON crate_versions.uid=users.id
+                    WHERE crates.name=$1
+                    ORDER BY crate_versions.id;")
+                .bind(name.as_str())
+                .fetch_all(&self.pool).await?;
+
+            Ok(res)
+        }.boxed_local()
+    }
+
+    fn get_crate_versions_for(&self, names : Vec<String>) -> LocalBoxFuture<Result<HashMap<String, Vec<CrateVersion>>, DatabaseError>> {
+        async move {
+            let mut res : HashMap<String, Vec<CrateVersion>> = HashMap::new();
+            if names.is_empty() {
+                return Ok(res);
+            }
+
+            let placeholders : Vec<String> = (1..=names.len()).map(|i| format!("${}", i)).collect();
+            let sql = format!(
+                "SELECT crate_versions.*, users.name AS published_by, crates.name AS crate_name
+                    FROM crate_versions INNER JOIN crates ON crate_versions.cid=crates.id
+                    LEFT JOIN users ON crate_versions.uid=users.id
+                    WHERE crates.name IN ({})
+                    ORDER BY crate_versions.id;",
+                placeholders.join(", "));
+
+            let mut q = sqlx::query(&sql);
+            for name in names {
+                q = q.bind(name);
+            }
+            for row in q.fetch_all(&self.pool).await? {
+                let name : String = row.try_get("crate_name")?;
+                res.entry(name).or_default().push(CrateVersion::from_row(&row)?);
+            }
+
+            Ok(res)
+        }.boxed_local()
+    }
+
+    fn delete_crate_version(&self, name : String, vers : String) -> LocalBoxFuture<Result<(), DatabaseError>> {
+        async move {
+            sqlx::query("DELETE FROM crate_versions
+                WHERE vers=$1 AND cid IN (SELECT id FROM crates WHERE name=$2)")
+                .bind(vers.as_str())
+                .bind(name.as_str())
+                .execute(&self.pool).await?;
+
+            Ok(())
+        }.boxed_local()
+    }
+
+    fn list_crate_versions(&self) -> LocalBoxFuture<Result<Vec<(String, String)>, DatabaseError>> {
+        async move {
+            let res : Vec<(String, String)> = sqlx::query_as(
+                "SELECT crates.name, crate_versions.vers
+                    FROM crate_versions INNER JOIN crates ON crate_versions.cid=crates.id
+                    ORDER BY crate_versions.id;")
+                .fetch_all(&self.pool).await?;
+
+            Ok(res)
+        }.boxed_local()
+    }
+
+    fn block_version(&self, name : String, vers : String) -> LocalBoxFuture<Result<(), DatabaseError>> {
+        async move {
+            if self.is_version_blocked(name.clone(), vers.clone()).await? {
+                return Ok(());
+            }
+
+            sqlx::query("INSERT INTO blocked_versions (crate_name, vers, created_at) VALUES ($1, $2, $3)")
+                .bind(name.as_str())
+                .bind(vers.as_str())
+                .bind(unix_now())
+                .execute(&self.pool).await?;
+
+            Ok(())
+        }.boxed_local()
+    }
+
+    fn is_version_blocked(&self, name : String, vers : String) -> LocalBoxFuture<Result<bool, DatabaseError>> {
+        async move {
+            let res : Option<(i32,)> = sqlx::query_as("SELECT id FROM blocked_versions WHERE crate_name=$1 AND vers=$2")
+                .bind(name.as_str())
+                .bind(vers.as_str())
+                .fetch_optional(&self.pool).await?;
+
+            Ok(res.is_some())
+        }.boxed_local()
+    }
+
+    fn add_audit_entry(&self, entry : NewAuditEntry) -> LocalBoxFuture<Result<(), DatabaseError>> {
+        async move {
+            sqlx::query("INSERT INTO audit_log
+                (uid, action, crate_name, vers, detail, ip, created_at)
+                VALUES ($1, $2, $3, $4, $5, $6, $7)")
+                .bind(entry.uid)
+                .bind(entry.action.as_str())
+                .bind(entry.crate_name)
+                .bind(entry.vers)
+                .bind(entry.detail)
+                .bind(entry.ip)
+                .bind(unix_now())
+                .execute(&self.pool).await?;
+
+            Ok(())
+        }.boxed_local()
+    }
+
+    fn get_audit_entries(&self, query : AuditQuery) -> LocalBoxFuture<Result<Vec<AuditEntry>, DatabaseError>> {
+        async move {
+            // Only the filters which were given end up in the query, each
+            // with the next placeholder number.
+            let mut filters = Vec::new();
+            let mut params = Vec::new();
+            for (column, value) in [
+                ("audit_log.crate_name", query.crate_name),
+                ("users.name", query.user),
+                ("audit_log.action", query.action.map(|a| a.as_str().to_string())),
+            ] {
+                if let Some(value) = value {
+                    params.push(value);
+                    filters.push(format!("{}=${}", column, params.len()));
+                }
+            }
+            let where_clause = if filters.is_empty() {
+                String::new()
+            } else {
+                format!("WHERE {}", filters.join(" AND "))
+            };
+
+            let sql = format!(
+                "SELECT audit_log.*, users.name AS user_name
+                    FROM audit_log LEFT JOIN users ON audit_log.uid=users.id
+                    {}
+                    ORDER BY audit_log.id DESC
+                    LIMIT ${} OFFSET ${};",
+                where_clause, params.len() + 1, params.len() + 2);
+
+            let mut q = sqlx::query_as::<_, AuditEntry>(&sql);
+            for param in params {
+                q = q.bind(param);
+            }
+            let res = q
+                .bind(query.limit)
+                .bind(query.offset)
+                .fetch_all(&self.pool).await?;
+
+            Ok(res)
+        }.boxed_local()
+    }
+
+    fn set_yanked(&self, name : String, vers : String, yanked : bool) -> LocalBoxFuture<Result<(), DatabaseError>> {
+        async move {
+            sqlx::query("UPDATE crate_versions SET yanked=$1
+                WHERE vers=$2 AND cid IN (SELECT id FROM crates WHERE name=$3)")
+                .bind(yanked as i32)
+                .bind(vers.as_str())
+                .bind(name.as_str())
+                .execute(&self.pool).await?;
+
+            Ok(())
+        }.boxed_local()
+    }
+
+    fn get_yanked_versions(&self) -> LocalBoxFuture<Result<HashSet<(String, String)>, DatabaseError>> {
+        async move {
+            let rows : Vec<(String, String)> = sqlx::query_as(
+                "SELECT crates.name, crate_versions.vers
+                    FROM crate_versions INNER JOIN crates ON crate_versions.cid=crates.id
+                    WHERE crate_versions.yanked=1;")
+                .fetch_all(&self.pool).await?;
+
+            Ok(rows.into_iter().collect())
+        }.boxed_local()
+    }
+
+    fn add_webhook(&self, url : String, secret : String, events : String) -> LocalBoxFuture<Result<Webhook, DatabaseError>> {
+        async move {
+            let created_at = unix_now();
+
+            sqlx::query("INSERT INTO webhooks (url, secret, events, created_at) VALUES ($1, $2, $3, $4)")
+                .bind(url.as_str())
+                .bind(secret.as_str())
+                .bind(events.as_str())
+                .bind(created_at)
+                .execute(&self.pool).await?;
+
+            let res : Webhook = sqlx::query_as("SELECT * FROM webhooks WHERE url=$1 AND secret=$2 ORDER BY id DESC")
+                .bind(url.as_str())
+                .bind(secret.as_str())
+                .fetch_one(&self.pool).await?;
+
+            Ok(res)
+        }.boxed_local()
+    }
+
+    fn get_webhooks(&self) -> LocalBoxFuture<Result<Vec<Webhook>, DatabaseError>> {
+        async move {
+            let res : Vec<Webhook> = sqlx::query_as("SELECT * FROM webhooks ORDER BY id")
+                .fetch_all(&self.pool).await?;
+
+            Ok(res)
+        }.boxed_local()
+    }
+
+    fn get_webhook(&self, id : i32) -> LocalBoxFuture<Result<Option<Webhook>, DatabaseError>> {
+        async move {
+            let res : Option<Webhook> = sqlx::query_as("SELECT * FROM webhooks WHERE id=$1")
+                .bind(id)
+                .fetch_optional(&self.pool).await?;
+
+            Ok(res)
+        }.boxed_local()
+    }
+
+    fn delete_webhook(&self, id : i32) -> LocalBoxFuture<Result<(), DatabaseError>> {
+        async move {
+            sqlx::query("DELETE FROM webhook_deliveries WHERE webhook_id=$1")
+                .bind(id)
+                .execute(&self.pool).await?;
+
+            sqlx::query("DELETE FROM webhooks WHERE id=$1")
+                .bind(id)
+                .execute(&self.pool).await?;
+
+            Ok(())
+        }.boxed_local()
+    }
+
+    fn add_webhook_delivery(&self, delivery : NewWebhookDelivery) -> LocalBoxFuture<Result<(), DatabaseError>> {
+        async move {
+            sqlx::query("INSERT INTO webhook_deliveries
+                (webhook_id, event, payload, attempt, status, error, created_at)
+                VALUES ($1, $2, $3, $4, $5, $6, $7)")
+                .bind(delivery.webhook_id)
+                .bind(delivery.event)
+                .bind(delivery.payload)
+                .bind(delivery.attempt)
+                .bind(delivery.status)
+                .bind(delivery.error)
+                .bind(unix_now())
+                .execute(&self.pool).await?;
+
+            Ok(())
+        }.boxed_local()
+    }
+
+    fn get_webhook_deliveries(&self, webhook_id : i32, limit : i64) -> LocalBoxFuture<Result<Vec<WebhookDelivery>, DatabaseError>> {
+        async move {
+            let res : Vec<WebhookDelivery> = sqlx::query_as("SELECT * FROM webhook_deliveries
+                WHERE webhook_id=$1
+                ORDER BY id DESC
+                LIMIT $2;")
+                .bind(webhook_id)
+                .bind(limit)
+                .fetch_all(&self.pool).await?;
+
+            Ok(res)
+        }.boxed_local()
+    }
+
+    fn ping(&self) -> LocalBoxFuture<Result<(), DatabaseError>> {
+        async move {
+            sqlx::query("SELECT 1")
+                .execute(&self.pool).await?;
+
+            Ok(())
+        }.boxed_local()
+    }
+
+    fn pool_status(&self) -> PoolStatus {
+        PoolStatus {
+            size : self.pool.size(),
+            idle : self.pool.num_idle(),
+        }
+    }
+
+    fn dump(&self) -> LocalBoxFuture<Result<DatabaseDump, DatabaseError>> {
+        async move {
+            let mut dump = DatabaseDump::default();
+            for (table, columns) in TABLES {
+                let names : Vec<&str> = columns.iter().map(|(name, _)| *name).collect();
+                let rows = sqlx::query(&format!("SELECT {} FROM {} ORDER BY {};", names.join(", "), table, names[0]))
+                    .fetch_all(&self.pool).await?;
+
+                let mut dumped = Vec::with_capacity(rows.len());
+                for row in rows {
+                    let mut values = serde_json::Map::new();
+                    for (name, column_type) in columns.iter() {
+                        values.insert(name.to_string(), column_type.read(&row, name)?);
+                    }
+                    dumped.push(values);
+                }
+                dump.tables.insert(table.to_string(), dumped);
+            }
+            Ok(dump)
+        }.boxed_local()
+    }
+
+    fn restore(&self, mut dump : DatabaseDump) -> LocalBoxFuture<Result<(), DatabaseError>> {
+        async move {
+            // Migrating leaves the default admin user behind, anything more
+            // means the database is in use.
+            for (table, _) in TABLES {
+                let (count,) : (i64,) = sqlx::query_as(&format!("SELECT COUNT(*) FROM {};", table))
+                    .fetch_one(&self.pool).await?;
+                if count > if *table == "users" { 1 } else { 0 } {
+                    return Err(DatabaseError::NotEmpty);
+                }
+            }
+            for table in dump.tables.keys() {
+                if !TABLES.iter().any(|(known, _)| known == table) {
+                    log::warn!("Skipping unknown table `{}` in the dump.", table);
+                }
+            }
+
+            let mut tx = self.pool.begin().await?;
+            sqlx::query("DELETE FROM users;")
+                .execute(&mut tx).await?;
+            for (table, columns) in TABLES {
+                let names : Vec<&str> = columns.iter().map(|(name, _)| *name).collect();
+                let placeholders : Vec<String> = (1..=names.len()).map(|i| format!("${}", i)).collect();
+                let sql = format!("INSERT INTO {} ({}) VALUES ({});", table, names.join(", "), placeholders.join(", "));
+                for row in dump.tables.remove(*table).unwrap_or_default() {
+                    let mut q = sqlx::query(&sql);
+                    for (name, column_type) in columns.iter() {
+                        let value = row.get(*name).unwrap_or(&serde_json::Value::Null);
+                        q = match column_type {
+                            ColumnType::Int => q.bind(value.as_i64().map(|v| v as i32)),
+                            ColumnType::BigInt => q.bind(value.as_i64()),
+                            ColumnType::Text => q.bind(value.as_str().map(String::from)),
+                        };
+                    }
+                    q.execute(&mut tx).await?;
+                }
+            }
+            tx.commit().await?;
+
+            // Postgres doesn't move its id sequences past ids given explicitly.
+            if let DatabaseType::PgSql = DatabaseType::from(self.pool.any_kind()) {
+                for (table, columns) in TABLES {
+                    if columns[0].0 != "id" {
+                        continue;
+                    }
+                    sqlx::query(&format!(
+                        "SELECT setval(pg_get_serial_sequence('{0}', 'id'), (SELECT COALESCE(MAX(id), 0) + 1 FROM {0}), false);",
+                        table))
+                        .execute(&self.pool).await?;
+                }
+            }
+            Ok(())
+        }.boxed_local()
+    }
+
     fn migrate(&self) -> LocalBoxFuture<Result<(), DatabaseError>> {
         async move {
+            let db_type = DatabaseType::from(self.pool.any_kind());
 
             let create_table_query = format!("CREATE TABLE IF NOT EXISTS users (
                 id {} PRIMARY KEY NOT NULL,
                 name VARCHAR(50) NOT NULL {},
                 password_hash VARCHAR(200) NOT NULL,
                 role INTEGER NOT NULL
-            );", id_type(DatabaseType::PgSql)
-            , unique(DatabaseType::PgSql));
+            );", id_type(db_type)
+            , unique(db_type));
 
             sqlx::query(&create_table_query)
                 .execute(&self.pool).await?;
@@ -267,8 +694,8 @@ impl Database for SqlDatabase {
                 uid INTEGER NOT NULL,
                 key VARCHAR(500) NOT NULL,
                 CONSTRAINT api_key_user FOREIGN KEY(uid) REFERENCES users(id)
-            );", id_type(DatabaseType::PgSql)
-            , unique(DatabaseType::PgSql));
+            );", id_type(db_type)
+            , unique(db_type));
 
             sqlx::query(&create_table_query)
                 .execute(&self.pool).await?;
@@ -276,7 +703,7 @@ impl Database for SqlDatabase {
             let create_table_query = format!("CREATE TABLE IF NOT EXISTS crates (
                 id {} PRIMARY KEY NOT NULL,
                 name VARCHAR(200)
-            );", id_type(DatabaseType::PgSql));
+            );", id_type(db_type));
 
             sqlx::query(&create_table_query)
                 .execute(&self.pool).await?;
@@ -288,6 +715,81 @@ impl Database for SqlDatabase {
                 CONSTRAINT user_id FOREIGN KEY(uid) REFERENCES users(id)
             );");
 
+            sqlx::query(&create_table_query)
+                .execute(&self.pool).await?;
+
+            let create_table_query = format!("CREATE TABLE IF NOT EXISTS crate_versions (
+                id {} PRIMARY KEY NOT NULL,
+                cid INTEGER NOT NULL,
+                uid INTEGER NOT NULL,
+                vers VARCHAR(100) NOT NULL,
+                description TEXT,
+                documentation VARCHAR(500),
+                homepage VARCHAR(500),
+                repository VARCHAR(500),
+                license VARCHAR(200),
+                keywords TEXT NOT NULL,
+                categories TEXT NOT NULL,
+                authors TEXT NOT NULL,
+                crate_size BIGINT NOT NULL,
+                created_at BIGINT NOT NULL,
+                yanked INTEGER NOT NULL DEFAULT 0,
+                CONSTRAINT version_crate FOREIGN KEY(cid) REFERENCES crates(id),
+                CONSTRAINT version_user FOREIGN KEY(uid) REFERENCES users(id)
+            );", id_type(db_type));
+
+            sqlx::query(&create_table_query)
+                .execute(&self.pool).await?;
+
+            let create_table_query = format!("CREATE TABLE IF NOT EXISTS audit_log (
+                id {} PRIMARY KEY NOT NULL,
+                uid INTEGER,
+                action VARCHAR(50) NOT NULL,
+                crate_name VARCHAR(200),
+                vers VARCHAR(100),
+                detail TEXT,
+                ip VARCHAR(100),
+                created_at BIGINT NOT NULL,
+                CONSTRAINT audit_user FOREIGN KEY(uid) REFERENCES users(id)
+            );", id_type(db_type));
+
+            sqlx::query(&create_table_query)
+                .execute(&self.pool).await?;
+
+            // Versions which were deleted and may not be published again.
+            let create_table_query = format!("CREATE TABLE IF NOT EXISTS blocked_versions (
+                id {} PRIMARY KEY NOT NULL,
+                crate_name VARCHAR(200) NOT NULL,
+                vers VARCHAR(100) NOT NULL,
+                created_at BIGINT NOT NULL
+            );", id_type(db_type));
+
+            sqlx::query(&create_table_query)
+                .execute(&self.pool).await?;
+
+            let create_table_query = format!("CREATE TABLE IF NOT EXISTS webhooks (
+                id {} PRIMARY KEY NOT NULL,
+                url VARCHAR(500) NOT NULL,
+                secret VARCHAR(200) NOT NULL,
+                events VARCHAR(200) NOT NULL,
+                created_at BIGINT NOT NULL
+            );", id_type(db_type));
+
+            sqlx::query(&create_table_query)
+                .execute(&self.pool).await?;
+
+            let create_table_query = format!("CREATE TABLE IF NOT EXISTS webhook_deliveries (
+                id {} PRIMARY KEY NOT NULL,
+                webhook_id INTEGER NOT NULL,
+                event VARCHAR(50) NOT NULL,
+                payload TEXT NOT NULL,
+                attempt INTEGER NOT NULL,
+                status INTEGER,
+                error TEXT,
+                created_at BIGINT NOT NULL,
+                CONSTRAINT delivery_webhook FOREIGN KEY(webhook_id) REFERENCES webhooks(id)
+            );", id_type(db_type));
+
             sqlx::query(&create_table_query)
                 .execute(&self.pool).await?;
 
@@ -308,6 +810,59 @@ impl Database for SqlDatabase {
     }
 }
 
+/// How a column is read and written when dumping and restoring.
+#[derive(Clone, Copy)]
+enum ColumnType {
+    Int,
+    BigInt,
+    Text
+}
+
+impl ColumnType {
+    fn read(self, row : &AnyRow, name : &str) -> Result<serde_json::Value, DatabaseError> {
+        Ok(match self {
+            ColumnType::Int => row.try_get::<Option<i32>, _>(name)?.into(),
+            ColumnType::BigInt => row.try_get::<Option<i64>, _>(name)?.into(),
+            ColumnType::Text => row.try_get::<Option<String>, _>(name)?.into(),
+        })
+    }
+}
+
+/// Every table and its columns, each table only referring to the ones before it.
+const TABLES : &[(&str, &[(&str, ColumnType)])] = {
+    use ColumnType::*;
+    &[
+        ("users", &[("id", Int), ("name", Text), ("password_hash", Text), ("role", Int)]),
+        ("api_keys", &[("id", Int), ("name", Text), ("uid", Int), ("key", Text)]),
+        ("crates", &[("id", Int), ("name", Text)]),
+        ("owners", &[("cid", Int), ("uid", Int)]),
+        ("crate_versions", &[
+            ("id", Int), ("cid", Int), ("uid", Int), ("vers", Text), ("description", Text),
+            ("documentation", Text), ("homepage", Text), ("repository", Text), ("license", Text),
+            ("keywords", Text), ("categories", Text), ("authors", Text), ("crate_size", BigInt),
+            ("created_at", BigInt), ("yanked", Int),
+        ]),
+        ("audit_log", &[
+            ("id", Int), ("uid", Int), ("action", Text), ("crate_name", Text), ("vers", Text),
+            ("detail", Text), ("ip", Text), ("created_at", BigInt),
+        ]),
+        ("blocked_versions", &[("id", Int), ("crate_name", Text), ("vers", Text), ("created_at", BigInt)]),
+        ("webhooks", &[("id", Int), ("url", Text), ("secret", Text), ("events", Text), ("created_at", BigInt)]),
+        ("webhook_deliveries", &[
+            ("id", Int), ("webhook_id", Int), ("event", Text), ("payload", Text), ("attempt", Int),
+            ("status", Int), ("error", Text), ("created_at", BigInt),
+        ]),
+    ]
+};
+
+/// The contents of every table, keyed by table name, in a form which can be
+/// loaded into any of the supported databases.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct DatabaseDump {
+    pub tables : BTreeMap<String, Vec<serde_json::Map<String, serde_json::Value>>>,
+}
+
+#[derive(Clone, Copy)]
 enum DatabaseType {
     MySql,
     MsSql,
@@ -315,44 +870,75 @@ enum DatabaseType {
     Sqlite
 }
 
+impl From<AnyKind> for DatabaseType {
+    fn from(kind : AnyKind) -> Self {
+        match kind {
+            AnyKind::Postgres => DatabaseType::PgSql,
+            AnyKind::MySql => DatabaseType::MySql,
+            AnyKind::Sqlite => DatabaseType::Sqlite,
+            AnyKind::Mssql => DatabaseType::MsSql,
+        }
+    }
+}
+
 fn id_type(t : DatabaseType) -> &'static str {
     match t {
         DatabaseType::PgSql => "SERIAL",
+        // Only an `INTEGER PRIMARY KEY` column becomes an alias for the rowid.
+        DatabaseType::Sqlite => "INTEGER",
         _ => "INTEGER AUTO_INCREMENT"
     }
 }
 
 fn unique(t : DatabaseType) -> &'static str {
     match t {
-        DatabaseType::PgSql => "UNIQUE",
+        DatabaseType::PgSql | DatabaseType::Sqlite => "UNIQUE",
         _ => "UNIQUE KEY"
     }
 }
 
+/// How many times to try connecting at startup before giving up.
+const CONNECT_ATTEMPTS : u32 = 8;
+/// How long to wait before the second attempt. Doubles after each attempt.
+const CONNECT_RETRY_DELAY : Duration = Duration::from_millis(500);
+/// The longest to wait between attempts.
+const CONNECT_MAX_RETRY_DELAY : Duration = Duration::from_secs(30);
+
 impl SqlDatabase {
-    pub async fn new<U>(uri : U, max_connections : u32, timeout : Duration) -> SqlDatabase
+    /// Connect to the database, retrying with exponential backoff while it's
+    /// unreachable (say it's still starting up alongside us).
+    ///
+    /// Gives up after a while, with the error from the last attempt.
+    pub async fn new<U>(uri : U, max_connections : u32, timeout : Duration) -> Result<SqlDatabase, DatabaseError>
     where U: AsRef<str>
     {
-        async fn inner(uri : &str, max_connections : u32, timeout : Duration) -> SqlDatabase {
-            let mut pool = AnyPoolOptions::new()
-                .max_connections(max_connections)
-                .acquire_timeout(timeout)
-                .connect(uri).await;
-
-            while pool.is_err() {
-                log::error!("Unable to connect to database at {}\t Retrying...", uri);
-                pool = AnyPoolOptions::new()
+        async fn inner(uri : &str, max_connections : u32, timeout : Duration) -> Result<SqlDatabase, DatabaseError> {
+            let mut delay = CONNECT_RETRY_DELAY;
+            let mut attempt = 1;
+
+            loop {
+                let pool = AnyPoolOptions::new()
                     .max_connections(max_connections)
                     .acquire_timeout(timeout)
                     .connect(uri).await;
-            }
 
-            SqlDatabase {
-                pool : pool.unwrap()
+                match pool {
+                    Ok(pool) => return Ok(SqlDatabase { pool }),
+                    Err(e) if attempt >= CONNECT_ATTEMPTS => return Err(e.into()),
+                    Err(e) => {
+                        log::error!(
+                            "Unable to connect to the database (attempt {} of {}): {}. Retrying in {:?}...",
+                            attempt, CONNECT_ATTEMPTS, e, delay
+                        );
+                        actix_web::rt::time::sleep(delay).await;
+                        delay = (delay * 2).min(CONNECT_MAX_RETRY_DELAY);
+                        attempt += 1;
+                    }
+                }
             }
         }
 
-        inner(uri.as_ref(), max_connections, timeout).await  
+        inner(uri.as_ref(), max_connections, timeout).await
     }
 }
 
@@ -362,13 +948,45 @@ pub enum DatabaseError {
     PWHashError(argon2::password_hash::Error),
     InvalidInput,
     UniqueAlreadyExists,
-    InvalidUri
+    InvalidUri,
+    NotFound,
+    NotEmpty
+}
+
+impl std::error::Error for DatabaseError {}
+
+impl DatabaseError {
+    /// What can safely be shown to the user. Failures of the database itself
+    /// get a generic message, their details only belong in the logs.
+    pub fn public_message(&self) -> String {
+        match self.status_code() {
+            StatusCode::UNAUTHORIZED => String::from("Invalid username or password"),
+            status if status.is_server_error() => String::from("Internal server error"),
+            _ => self.to_string(),
+        }
+    }
 }
 
+/// Only used directly by the JSON endpoints outside of the cargo API, the
+/// other handlers wrap it in their own error type.
 impl ResponseError for DatabaseError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            DatabaseError::PWHashError(argon2::password_hash::Error::Password) => StatusCode::UNAUTHORIZED,
+            DatabaseError::InvalidInput => StatusCode::BAD_REQUEST,
+            DatabaseError::UniqueAlreadyExists => StatusCode::CONFLICT,
+            DatabaseError::NotFound
+            | DatabaseError::SqlError(sqlx::Error::RowNotFound) => StatusCode::NOT_FOUND,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
     fn error_response(&self) -> HttpResponse<actix_web::body::BoxBody> {
-        HttpResponse::BadRequest()
-            .finish()
+        if self.status_code().is_server_error() {
+            log::error!("{}", self);
+        }
+        HttpResponse::build(self.status_code())
+            .json(crate::logging::error_body(&self.public_message()))
     }
 }
 
@@ -389,6 +1007,12 @@ impl Display for DatabaseError {
             },
             DatabaseError::InvalidInput => {
                 f.write_str("Invalid input")
+            },
+            DatabaseError::NotFound => {
+                f.write_str("Not found")
+            },
+            DatabaseError::NotEmpty => {
+                f.write_str("The database already has data in it")
             }
         }
         
@@ -453,6 +1077,33 @@ pub struct User {
     pub role : UserRole,
 }
 
+/// Hash of a password nobody knows, for [`User::dummy`].
+static DUMMY_PASSWORD_HASH : Lazy<String> = Lazy::new(|| {
+    let salt = SaltString::generate(&mut OsRng);
+    let password = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_str().as_bytes(), &salt)
+        .expect("hashing a random password")
+        .to_string()
+});
+
+impl User {
+    /// A stand in for a user that doesn't exist, which takes as long to check
+    /// a password against as a real one. Logging in as it always fails.
+    pub fn dummy() -> User {
+        User {
+            id : 0,
+            name : String::new(),
+            password_hash : DUMMY_PASSWORD_HASH.clone(),
+            role : UserRole::Viewer,
+        }
+    }
+
+    pub fn is_admin(&self) -> bool {
+        matches!(self.role, UserRole::Administrator)
+    }
+}
+
 #[derive(sqlx::FromRow, Debug, Clone)]
 pub struct Crate {
     pub id : i32,
@@ -465,4 +1116,217 @@ pub struct ApiKey {
     pub name : String,
     pub uid : i32,
     pub key : String,
-}
\ No newline at end of file
+}
+
+/// Metadata recorded for a version when it is published.
+///
+/// The index only holds what cargo needs to resolve dependencies, so the rest
+/// of the manifest details sent along with a publish are kept here.
+#[derive(Debug, Clone, Default)]
+pub struct NewCrateVersion {
+    pub vers : String,
+    pub description : Option<String>,
+    pub documentation : Option<String>,
+    pub homepage : Option<String>,
+    pub repository : Option<String>,
+    pub license : Option<String>,
+    pub keywords : Vec<String>,
+    pub categories : Vec<String>,
+    pub authors : Vec<String>,
+    pub crate_size : i64,
+}
+
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct CrateVersion {
+    pub id : i32,
+    pub cid : i32,
+    pub uid : i32,
+    pub vers : String,
+    pub description : Option<String>,
+    pub documentation : Option<String>,
+    pub homepage : Option<String>,
+    pub repository : Option<String>,
+    pub license : Option<String>,
+    /// JSON encoded list of strings.
+    pub keywords : String,
+    /// JSON encoded list of strings.
+    pub categories : String,
+    /// JSON encoded list of strings.
+    pub authors : String,
+    pub crate_size : i64,
+    /// Unix timestamp (seconds).
+    pub created_at : i64,
+    /// Name of the user who published the version.
+    pub published_by : Option<String>,
+}
+
+impl CrateVersion {
+    pub fn keyword_list(&self) -> Vec<String> {
+        serde_json::from_str(&self.keywords).unwrap_or_default()
+    }
+
+    pub fn category_list(&self) -> Vec<String> {
+        serde_json::from_str(&self.categories).unwrap_or_default()
+    }
+
+    pub fn author_list(&self) -> Vec<String> {
+        serde_json::from_str(&self.authors).unwrap_or_default()
+    }
+}
+
+/// The kinds of changes recorded in the audit log.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    Publish,
+    Yank,
+    Unyank,
+    OwnerAdd,
+    OwnerRemove,
+    ApiKeyCreate,
+    ApiKeyRevoke,
+    Delete,
+    DocsUpload,
+    WebhookCreate,
+    WebhookDelete,
+}
+
+impl AuditAction {
+    pub const ALL : [AuditAction; 11] = [
+        AuditAction::Publish,
+        AuditAction::Yank,
+        AuditAction::Unyank,
+        AuditAction::OwnerAdd,
+        AuditAction::OwnerRemove,
+        AuditAction::ApiKeyCreate,
+        AuditAction::ApiKeyRevoke,
+        AuditAction::Delete,
+        AuditAction::DocsUpload,
+        AuditAction::WebhookCreate,
+        AuditAction::WebhookDelete,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditAction::Publish => "publish",
+            AuditAction::Yank => "yank",
+            AuditAction::Unyank => "unyank",
+            AuditAction::OwnerAdd => "owner_add",
+            AuditAction::OwnerRemove => "owner_remove",
+            AuditAction::ApiKeyCreate => "api_key_create",
+            AuditAction::ApiKeyRevoke => "api_key_revoke",
+            AuditAction::Delete => "delete",
+            AuditAction::DocsUpload => "docs_upload",
+            AuditAction::WebhookCreate => "webhook_create",
+            AuditAction::WebhookDelete => "webhook_delete",
+        }
+    }
+}
+
+/// A change to the registry, about to be written to the audit log.
+#[derive(Debug, Clone)]
+pub struct NewAuditEntry {
+    /// The user who made the change, if it was made through the server.
+    pub uid : Option<i32>,
+    pub action : AuditAction,
+    pub crate_name : Option<String>,
+    pub vers : Option<String>,
+    /// Anything else worth knowing, like the name of an api key.
+    pub detail : Option<String>,
+    /// Where the request came from.
+    pub ip : Option<String>,
+}
+
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct AuditEntry {
+    pub id : i32,
+    pub uid : Option<i32>,
+    pub action : String,
+    pub crate_name : Option<String>,
+    pub vers : Option<String>,
+    pub detail : Option<String>,
+    pub ip : Option<String>,
+    /// Unix timestamp (seconds).
+    pub created_at : i64,
+    /// Name of the user who made the change.
+    pub user_name : Option<String>,
+}
+
+/// Filters for reading the audit log, newest entries first.
+#[derive(Debug, Clone)]
+pub struct AuditQuery {
+    pub crate_name : Option<String>,
+    pub user : Option<String>,
+    pub action : Option<AuditAction>,
+    pub limit : i64,
+    pub offset : i64,
+}
+
+impl Default for AuditQuery {
+    fn default() -> Self {
+        AuditQuery {
+            crate_name : None,
+            user : None,
+            action : None,
+            limit : 100,
+            offset : 0,
+        }
+    }
+}
+
+/// An endpoint to notify of changes to the registry.
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct Webhook {
+    pub id : i32,
+    pub url : String,
+    /// Key for signing payloads, so the receiver can check they came from us.
+    pub secret : String,
+    /// Comma separated names of the events to send.
+    pub events : String,
+    /// Unix timestamp (seconds).
+    pub created_at : i64,
+}
+
+/// An attempt to deliver a payload to a webhook, about to be logged.
+#[derive(Debug, Clone)]
+pub struct NewWebhookDelivery {
+    pub webhook_id : i32,
+    pub event : String,
+    pub payload : String,
+    /// Starts at 1, and goes up with each retry.
+    pub attempt : i32,
+    /// The http status the receiver responded with, if it responded at all.
+    pub status : Option<i32>,
+    /// Why the attempt failed, if it did.
+    pub error : Option<String>,
+}
+
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct WebhookDelivery {
+    pub id : i32,
+    pub webhook_id : i32,
+    pub event : String,
+    pub payload : String,
+    pub attempt : i32,
+    pub status : Option<i32>,
+    pub error : Option<String>,
+    /// Unix timestamp (seconds).
+    pub created_at : i64,
+}
+
+/// How busy the connection pool is.
+#[derive(Debug, Clone, Copy)]
+pub struct PoolStatus {
+    /// Connections currently open, whether in use or not.
+    pub size : u32,
+    /// Open connections which aren't in use.
+    pub idle : usize,
+}
+
+/// Seconds since the unix epoch, used for all timestamp columns.
+fn unix_now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
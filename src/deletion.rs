@@ -0,0 +1,110 @@
+//! Hard-deleting versions from the registry.
+//!
+//! Yanking is the usual way to retire a version, but it leaves everything in
+//! place. Sometimes a version has to go entirely, for example when a secret
+//! was published by mistake. Deleting removes the version from the index, the
+//! stored `.crate` file and docs, and its metadata in the database.
+//! Optionally, the same version number can be blocked from being published
+//! again.
+
+use crate::audit;
+use crate::database::{AuditAction, DatabaseError, NewAuditEntry};
+use crate::errors::PackageIndexError;
+use crate::package_index::IndexHandle;
+use crate::Settings;
+
+/// A version to delete, and who is deleting it.
+pub struct Deletion {
+    pub crate_name: String,
+    pub version: semver::Version,
+    /// Refuse future publishes of the same version.
+    pub block: bool,
+    /// The user deleting the version, if it's done through the server.
+    pub uid: Option<i32>,
+    /// The name the index commit is attributed to.
+    pub author: Option<String>,
+    /// Where the request came from, if it's done through the server.
+    pub ip: Option<String>,
+}
+
+/// Delete a version, recording it in the audit log.
+///
+/// The index goes first since it's what cargo looks at. If anything after
+/// that fails, the version is already gone as far as cargo is concerned and
+/// the leftovers can be cleaned up by running the deletion again.
+///
+/// This is shared by the api and the command line, which each have their own
+/// error type.
+pub async fn delete_version<E>(
+    index: &IndexHandle,
+    settings: &Settings,
+    deletion: Deletion,
+) -> Result<(), E>
+where
+    E: From<PackageIndexError> + From<DatabaseError> + From<std::io::Error>,
+{
+    let Deletion {
+        crate_name,
+        version,
+        block,
+        uid,
+        author,
+        ip,
+    } = deletion;
+
+    match index
+        .delete_version(crate_name.clone(), version.clone(), author)
+        .await
+    {
+        Ok(()) => {}
+        // Nothing left in the index, but there may be other leftovers.
+        Err(PackageIndexError::VersionNotFound(_, _))
+        | Err(PackageIndexError::CrateNotFound(_))
+            if settings
+                .db
+                .get_crate_versions(crate_name.clone())
+                .await?
+                .iter()
+                .any(|v| v.vers == version.to_string()) =>
+        {
+            log::warn!(
+                "`{} v{}` is missing from the index, cleaning up what's left.",
+                crate_name,
+                version
+            );
+        }
+        Err(e) => return Err(e.into()),
+    }
+
+    crate::storage::delete_docs(&settings.crate_dir, &crate_name, &version)?;
+    crate::storage::delete_crate_file(&settings.crate_dir, &crate_name, &version)?;
+    settings
+        .db
+        .delete_crate_version(crate_name.clone(), version.to_string())
+        .await?;
+    if block {
+        settings
+            .db
+            .block_version(crate_name.clone(), version.to_string())
+            .await?;
+    }
+
+    audit::record(
+        settings.db.as_ref(),
+        NewAuditEntry {
+            uid,
+            action: AuditAction::Delete,
+            crate_name: Some(crate_name),
+            vers: Some(version.to_string()),
+            detail: if block {
+                Some(String::from("republishing blocked"))
+            } else {
+                None
+            },
+            ip,
+        },
+    )
+    .await;
+
+    Ok(())
+}
@@ -0,0 +1,255 @@
+//! Hosting rustdoc output for published crates.
+//!
+//! Docs are uploaded as a tarball (optionally gzipped) of `target/doc`, and
+//! unpacked next to the `.crate` file (see [`crate::storage::get_docs_dir`]).
+//! Uploading again replaces the previous docs for the version.
+
+use crate::storage::invalid;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use flate2::read::GzDecoder;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// Uploads bigger than this are refused.
+pub const MAX_UPLOAD_SIZE: usize = 200 * 1024 * 1024;
+
+/// Tarballs which unpack to more than this are refused, whatever their
+/// compressed size.
+pub const MAX_UNPACKED_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// A name for scratch files and dirs next to `dest`, unique to one upload so
+/// concurrent uploads can't trip over each other.
+pub fn scratch_path(dest: &Path, suffix: &str) -> PathBuf {
+    let mut id = [0; 8];
+    OsRng.fill_bytes(&mut id);
+    let name = dest.file_name().unwrap().to_string_lossy();
+    dest.parent()
+        .unwrap()
+        .join(format!(".{}.{}.{}", name, hex::encode(id), suffix))
+}
+
+/// Unpack a docs tarball into `dest`, replacing whatever was there.
+///
+/// The tarball may hold the contents of `target/doc` directly, or the `doc`
+/// directory itself. Only regular files and directories are unpacked, nothing
+/// may land outside of `dest`, and at most [`MAX_UNPACKED_SIZE`] is written.
+///
+/// Problems with the tarball itself are reported as
+/// [`io::ErrorKind::InvalidData`].
+pub fn unpack(archive: &Path, dest: &Path) -> io::Result<()> {
+    fs::create_dir_all(dest.parent().unwrap())?;
+    let staging = scratch_path(dest, "upload");
+    let old = scratch_path(dest, "old");
+    fs::create_dir(&staging)?;
+
+    let res = unpack_into(archive, &staging).and_then(|()| {
+        let root = docs_root(&staging)?;
+        if dest.exists() {
+            fs::rename(dest, &old)?;
+        }
+        fs::rename(&root, dest)
+    });
+
+    // The staging dir is left behind when the docs were in a `doc` dir.
+    let _ = fs::remove_dir_all(&staging);
+    let _ = fs::remove_dir_all(&old);
+    res
+}
+
+fn unpack_into(archive: &Path, dir: &Path) -> io::Result<()> {
+    let mut fh = BufReader::new(File::open(archive)?);
+    let mut magic = [0; 2];
+    let gzipped = fh.read_exact(&mut magic).is_ok() && magic == [0x1f, 0x8b];
+    fh.seek(SeekFrom::Start(0))?;
+    let reader: Box<dyn Read> = if gzipped {
+        Box::new(GzDecoder::new(fh))
+    } else {
+        Box::new(fh)
+    };
+
+    let mut archive = tar::Archive::new(reader);
+    let mut files = 0;
+    let mut size = 0;
+    let entries = archive
+        .entries()
+        .map_err(|_| invalid("Docs must be uploaded as a tarball."))?;
+    for entry in entries {
+        let mut entry = entry.map_err(|e| invalid(format!("Bad tarball: {}", e)))?;
+        let kind = entry.header().entry_type();
+        if !(kind.is_file() || kind.is_dir()) {
+            continue;
+        }
+        size += entry
+            .header()
+            .size()
+            .map_err(|e| invalid(format!("Bad tarball: {}", e)))?;
+        if size > MAX_UNPACKED_SIZE {
+            return Err(invalid(format!(
+                "Docs can be at most {} MiB unpacked.",
+                MAX_UNPACKED_SIZE / 1024 / 1024
+            )));
+        }
+        if !entry.unpack_in(dir)? {
+            return Err(invalid("Tarball contains paths outside of the docs."));
+        }
+        if kind.is_file() {
+            files += 1;
+        }
+    }
+    if files == 0 {
+        return Err(invalid("Tarball contains no files."));
+    }
+    Ok(())
+}
+
+/// The dir holding the docs, which is `dir` itself unless the tarball was of
+/// the `doc` dir.
+fn docs_root(dir: &Path) -> io::Result<PathBuf> {
+    let entries: Vec<_> = fs::read_dir(dir)?.collect::<io::Result<_>>()?;
+    match &entries[..] {
+        [entry] if entry.file_name() == "doc" && entry.file_type()?.is_dir() => Ok(entry.path()),
+        _ => Ok(dir.to_path_buf()),
+    }
+}
+
+/// The page to start from when browsing the docs for a crate.
+///
+/// That's the index page for the crate's library, which rustdoc names with
+/// underscores in place of dashes. Failing that, the first crate found with an
+/// index page.
+pub fn entry_point(docs_dir: &Path, name: &str) -> Option<String> {
+    let lib_name = name.replace('-', "_");
+    if docs_dir.join(&lib_name).join("index.html").is_file() {
+        return Some(format!("{}/index.html", lib_name));
+    }
+    if docs_dir.join("index.html").is_file() {
+        return Some(String::from("index.html"));
+    }
+    let mut dirs: Vec<_> = fs::read_dir(docs_dir)
+        .ok()?
+        .filter_map(|e| e.ok())
+        .filter(|e| e.path().join("index.html").is_file())
+        .map(|e| e.file_name().to_string_lossy().into_owned())
+        .collect();
+    dirs.sort();
+    dirs.into_iter()
+        .next()
+        .map(|dir| format!("{}/index.html", dir))
+}
+
+/// Find a file inside the docs, refusing paths which would escape them.
+///
+/// Directories resolve to their `index.html`.
+pub fn resolve(docs_dir: &Path, path: &str) -> Option<PathBuf> {
+    let mut resolved = docs_dir.to_path_buf();
+    for part in path.split('/').filter(|p| !p.is_empty()) {
+        if part == "." || part == ".." || part.contains('\\') {
+            return None;
+        }
+        resolved.push(part);
+    }
+    if resolved.is_dir() {
+        resolved.push("index.html");
+    }
+    if resolved.is_file() {
+        Some(resolved)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use tempdir::TempDir;
+
+    /// A gzipped tarball of rustdoc-ish output, with each path prefixed.
+    pub fn make_docs_tarball(prefix: &str) -> Vec<u8> {
+        let gz = GzEncoder::new(vec![], flate2::Compression::default());
+        let mut builder = tar::Builder::new(gz);
+        for (name, contents) in &[
+            ("my_crate/index.html", "<h1>my_crate</h1>"),
+            ("my_crate/fn.foo.html", "<h1>foo</h1>"),
+            ("static.files/main.js", ""),
+        ] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(
+                    &mut header,
+                    format!("{}{}", prefix, name),
+                    contents.as_bytes(),
+                )
+                .unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    #[test]
+    fn test_unpack() {
+        let root = TempDir::new("test_unpack").unwrap();
+        let dest = root.path().join("docs").join("0.1.0");
+        for prefix in &["", "doc/", "./"] {
+            let archive = root.path().join("docs.tgz");
+            fs::write(&archive, make_docs_tarball(prefix)).unwrap();
+            unpack(&archive, &dest).unwrap();
+
+            assert!(dest.join("my_crate/index.html").is_file());
+            assert_eq!(
+                Some(String::from("my_crate/index.html")),
+                entry_point(&dest, "my-crate")
+            );
+            // Nothing is left lying around.
+            assert_eq!(1, fs::read_dir(dest.parent().unwrap()).unwrap().count());
+        }
+    }
+
+    #[test]
+    fn test_unpack_bad_archive() {
+        let root = TempDir::new("test_unpack_bad_archive").unwrap();
+        let dest = root.path().join("docs").join("0.1.0");
+        let archive = root.path().join("docs.tgz");
+        fs::write(&archive, b"not a tarball").unwrap();
+        let err = unpack(&archive, &dest).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        assert!(!dest.exists());
+    }
+
+    #[test]
+    fn test_unpack_too_big() {
+        let root = TempDir::new("test_unpack_too_big").unwrap();
+        let dest = root.path().join("docs").join("0.1.0");
+        let archive = root.path().join("docs.tar");
+        // Only the header is needed, the size is checked before any content.
+        let mut header = tar::Header::new_gnu();
+        header.set_path("my_crate/index.html").unwrap();
+        header.set_size(MAX_UNPACKED_SIZE + 1);
+        header.set_mode(0o644);
+        header.set_cksum();
+        fs::write(&archive, header.as_bytes()).unwrap();
+
+        let err = unpack(&archive, &dest).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        assert!(err.to_string().contains("unpacked"));
+        assert!(!dest.exists());
+        assert_eq!(0, fs::read_dir(dest.parent().unwrap()).unwrap().count());
+    }
+
+    #[test]
+    fn test_resolve() {
+        let root = TempDir::new("test_resolve").unwrap();
+        fs::create_dir_all(root.path().join("my_crate")).unwrap();
+        fs::write(root.path().join("my_crate/index.html"), "").unwrap();
+        fs::write(root.path().join("secret"), "").unwrap();
+        let docs = root.path().join("my_crate");
+
+        assert_eq!(Some(docs.join("index.html")), resolve(&docs, ""));
+        assert_eq!(Some(docs.join("index.html")), resolve(&docs, "index.html"));
+        assert_eq!(None, resolve(&docs, "../secret"));
+        assert_eq!(None, resolve(&docs, "missing.html"));
+    }
+}
@@ -1,10 +1,12 @@
 #![cfg(not(tarpaulin_include))]
 
+use crate::database::{DatabaseError, User};
 use actix_web::HttpResponseBuilder;
+use actix_web::http::header::ContentType;
+use askama::Template;
 use actix_web::error::{BlockingError, ResponseError};
 use actix_web::http::StatusCode;
 use actix_web::{HttpResponse};
-use serde_json::json;
 use std::fmt::{Debug};
 use thiserror::Error;
 
@@ -16,19 +18,52 @@ pub enum ApiError {
     JSON(#[from] serde_json::Error),
     #[error("Package Index failure: `{0}`")]
     PackageIndex(#[from] PackageIndexError),
+    #[error("Database failure: `{0}`")]
+    Database(#[from] DatabaseError),
+    #[error("Not Found")]
+    NotFound,
+    #[error("Forbidden")]
+    Forbidden,
+    #[error("{0}")]
+    BadRequest(String),
+    #[error("Blocking task failed: `{0}`")]
+    Blocking(#[from] BlockingError),
 }
 
 /// For the Api Errors, cargo wants them converted to a 200 OK response with a
 /// json body (eugh).
 /// Cargo will present "detail" keys to the user.
+///
+/// `NotFound`, `Forbidden` and `BadRequest` are the exceptions since they're only produced by
+/// endpoints cargo doesn't use, where other clients expect a real status code. `Blocking` is a
+/// 503 so cargo treats it as the temporary failure it is.
 impl ResponseError for ApiError {
     fn status_code(&self) -> StatusCode {
-        StatusCode::OK
+        match self {
+            ApiError::NotFound => StatusCode::NOT_FOUND,
+            ApiError::Forbidden => StatusCode::FORBIDDEN,
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::Blocking(_) => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::OK,
+        }
     }
 
     fn error_response(&self) -> HttpResponse {
+        let detail = match self {
+            ApiError::Database(e) => {
+                if e.status_code().is_server_error() {
+                    log::error!("{}", self);
+                }
+                e.public_message()
+            }
+            ApiError::Blocking(_) => {
+                log::error!("{}", self);
+                String::from("Service temporarily unavailable")
+            }
+            _ => self.to_string(),
+        };
         HttpResponseBuilder::new(self.status_code())
-            .json(json!({"errors": [{ "detail": self.to_string() }]}))
+            .json(crate::logging::error_body(&detail))
     }
 }
 
@@ -44,10 +79,44 @@ pub enum PackageIndexError {
     Publish(String),
     #[error("Invalid package name: `{0}`")]
     InvalidPackageName(String),
+    #[error("Crate not found: `{0}`")]
+    CrateNotFound(String),
+    #[error("Version not found: `{0} v{1}`")]
+    VersionNotFound(String, semver::Version),
     #[error("Glob failed: `{0}`")]
     Glob(#[from] glob::GlobError),
     #[error("Glob pattern failed: `{0}`")]
     GlobPattern(#[from] glob::PatternError),
+    #[error("Index writer unavailable")]
+    WriterUnavailable,
+    #[error("Squash failed: `{0}`")]
+    Squash(String),
+}
+
+#[derive(Debug, Error)]
+pub enum GitProtocolError {
+    #[error("Git error: `{0}`")]
+    Git2(#[from] git2::Error),
+    #[error("Malformed request: `{0}`")]
+    Malformed(String),
+    #[error("Unknown object: `{0}`")]
+    UnknownObject(git2::Oid),
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Failed to read config file `{0}`: {1}")]
+    Read(std::path::PathBuf, std::io::Error),
+    #[error("Failed to parse config file `{0}`: {1}")]
+    Parse(std::path::PathBuf, toml::de::Error),
+    #[error("`{key}` is not set. Use `--{flag}`, `{env}`, or `{key}` in the config file.")]
+    Missing {
+        key: &'static str,
+        flag: &'static str,
+        env: &'static str,
+    },
+    #[error("Invalid `{0}`: {1}")]
+    Invalid(&'static str, String),
 }
 
 #[derive(Debug, Error)]
@@ -58,28 +127,111 @@ pub enum EstuaryError {
     IO(#[from] std::io::Error),
     #[error("Package Index failure: `{0}`")]
     PackageIndex(#[from] PackageIndexError),
+    #[error("Git protocol failure: `{0}`")]
+    GitProtocol(#[from] GitProtocolError),
     #[error("Not Found")]
     NotFound,
+    #[error("Forbidden")]
+    Forbidden,
+    #[error("Database failure: `{0}`")]
+    Database(#[from] DatabaseError),
+    #[error("Configuration error: {0}")]
+    Config(#[from] ConfigError),
+    #[error("Session failure: `{0}`")]
+    Session(#[from] actix_session::SessionInsertError),
     #[error("Invalid Version: `{0}`")]
     InvalidVersion(#[from] semver::SemVerError),
+    #[error("Template render failed: `{0}`")]
+    Template(#[from] askama::Error),
+    /// The closure given to `web::block` panicked or was cancelled.
+    #[error("Blocking task failed: `{0}`")]
+    Blocking(#[from] BlockingError),
+}
+
+#[derive(Template)]
+#[template(path = "error.html")]
+struct ErrorTemplate {
+    title: String,
+    user: Option<User>,
+    message: String,
+    request_id: Option<String>,
 }
 
-impl From<BlockingError> for EstuaryError
-{
-    fn from(e: BlockingError) -> Self {
-        e.into()
+impl EstuaryError {
+    /// What can safely be shown to the user. Anything which went wrong on our
+    /// end only gets a generic message, the details are logged instead.
+    fn public_message(&self) -> String {
+        match self {
+            EstuaryError::Database(e) => e.public_message(),
+            _ if self.status_code().is_server_error() => String::from("Something went wrong on our end."),
+            _ => self.to_string(),
+        }
     }
 }
 
+/// Errors are rendered as an HTML page, since these handlers serve browsers.
+/// Git clients only look at the status code.
 impl ResponseError for EstuaryError {
     fn status_code(&self) -> StatusCode {
         match self {
             EstuaryError::NotFound => StatusCode::NOT_FOUND,
+            EstuaryError::Forbidden => StatusCode::FORBIDDEN,
+            EstuaryError::InvalidVersion(_)
+            | EstuaryError::GitProtocol(GitProtocolError::Malformed(_))
+            | EstuaryError::GitProtocol(GitProtocolError::UnknownObject(_)) => {
+                StatusCode::BAD_REQUEST
+            }
+            EstuaryError::PackageIndex(PackageIndexError::CrateNotFound(_))
+            | EstuaryError::PackageIndex(PackageIndexError::VersionNotFound(..)) => {
+                StatusCode::NOT_FOUND
+            }
+            EstuaryError::Database(e) => e.status_code(),
+            EstuaryError::Blocking(_) => StatusCode::SERVICE_UNAVAILABLE,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 
     fn error_response(&self) -> HttpResponse {
-        HttpResponseBuilder::new(self.status_code()).body(self.to_string())
+        let status = self.status_code();
+        if status.is_server_error() {
+            log::error!("{}", self);
+        }
+
+        let page = ErrorTemplate {
+            title: status.canonical_reason().unwrap_or("Error").to_string(),
+            user: None,
+            message: self.public_message(),
+            request_id: crate::logging::current_request_id(),
+        };
+        match page.render() {
+            Ok(body) => HttpResponseBuilder::new(status)
+                .content_type(ContentType::html())
+                .body(body),
+            Err(e) => {
+                log::error!("Failed to render error page: {}", e);
+                HttpResponseBuilder::new(status).body(page.message)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::web;
+
+    async fn failed_block() -> BlockingError {
+        web::block(|| panic!("this panic is expected"))
+            .await
+            .unwrap_err()
+    }
+
+    #[actix_rt::test]
+    async fn test_blocking_error_is_unavailable() {
+        let err = EstuaryError::from(failed_block().await);
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, err.error_response().status());
+
+        let err = ApiError::from(failed_block().await);
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, err.error_response().status());
     }
 }
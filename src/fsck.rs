@@ -0,0 +1,333 @@
+//! Checking that the index, crate storage and database agree.
+//!
+//! A publish writes to the index, then crate storage, then the database, and
+//! a deletion goes through them in the same order. Each is only undone by
+//! hand, so a crash part way through (or someone tidying up the wrong dir)
+//! leaves them disagreeing. This finds where they do.
+//!
+//! Only problems where nothing of value can be lost are repaired:
+//!
+//! - `.crate` files for versions in neither the index nor the database are
+//!   moved to [`LOST_AND_FOUND`](crate::storage::LOST_AND_FOUND) in the crate
+//!   dir.
+//! - Versions in the database which are in neither the index nor crate
+//!   storage are removed from the database.
+//!
+//! Everything else needs someone to decide what the right answer is.
+
+use crate::database::Database;
+use crate::errors::EstuaryError;
+use crate::package_index::{BadLine, PackageIndex};
+use crate::storage;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Problem {
+    /// A line of a package file which can't be parsed.
+    UnparsableLine(BadLine),
+    /// A version in the index without a `.crate` file.
+    MissingCrateFile { crate_name: String, vers: String },
+    /// A `.crate` file which doesn't match the checksum in the index.
+    ChecksumMismatch {
+        crate_name: String,
+        vers: String,
+        expected: String,
+        actual: String,
+    },
+    /// A `.crate` file for a version which is in neither the index nor the
+    /// database.
+    OrphanCrateFile { path: PathBuf },
+    /// A version in the index which the database knows nothing about.
+    MissingFromDatabase { crate_name: String, vers: String },
+    /// A version in the database which isn't in the index.
+    MissingFromIndex {
+        crate_name: String,
+        vers: String,
+        has_crate_file: bool,
+    },
+}
+
+impl Problem {
+    /// Whether `--repair` deals with this problem.
+    pub fn is_repairable(&self) -> bool {
+        matches!(
+            self,
+            Problem::OrphanCrateFile { .. }
+                | Problem::MissingFromIndex {
+                    has_crate_file: false,
+                    ..
+                }
+        )
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::UnparsableLine(bad) => write!(
+                f,
+                "line {} of the package file for `{}` can't be parsed: {}",
+                bad.line, bad.crate_name, bad.error
+            ),
+            Problem::MissingCrateFile { crate_name, vers } => {
+                write!(f, "`{} v{}` has no .crate file", crate_name, vers)
+            }
+            Problem::ChecksumMismatch {
+                crate_name,
+                vers,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "the .crate file for `{} v{}` has checksum {}, the index expects {}",
+                crate_name, vers, actual, expected
+            ),
+            Problem::OrphanCrateFile { path } => {
+                write!(f, "`{}` isn't a known version", path.display())
+            }
+            Problem::MissingFromDatabase { crate_name, vers } => {
+                write!(f, "`{} v{}` is missing from the database", crate_name, vers)
+            }
+            Problem::MissingFromIndex {
+                crate_name,
+                vers,
+                has_crate_file,
+            } => {
+                write!(
+                    f,
+                    "`{} v{}` is in the database but not the index",
+                    crate_name, vers
+                )?;
+                if *has_crate_file {
+                    write!(f, " (its .crate file is still stored)")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Compare the index, crate storage and database.
+pub async fn check(
+    index: &PackageIndex,
+    crate_dir: &Path,
+    db: &dyn Database,
+) -> Result<Vec<Problem>, EstuaryError> {
+    let mut problems = vec![];
+
+    let (versions, bad_lines) = index.scan()?;
+    problems.extend(bad_lines.into_iter().map(Problem::UnparsableLine));
+
+    let stored = storage::list_crate_files(crate_dir)?;
+    let stored_keys: HashSet<(&str, &str)> = stored
+        .iter()
+        .map(|c| (c.name.as_str(), c.vers.as_str()))
+        .collect();
+    let index_keys: HashSet<(String, String)> = versions
+        .iter()
+        .map(|pkg| (pkg.name.clone(), pkg.vers.to_string()))
+        .collect();
+    let db_versions = db.list_crate_versions().await?;
+    let db_keys: HashSet<&(String, String)> = db_versions.iter().collect();
+
+    for pkg in &versions {
+        let crate_name = pkg.name.clone();
+        let vers = pkg.vers.to_string();
+        let path = storage::get_crate_file_path(crate_dir, &crate_name, &pkg.vers);
+        match std::fs::read(&path) {
+            Ok(bytes) => {
+                let actual = format!("{:x}", Sha256::digest(&bytes));
+                if actual != pkg.cksum {
+                    problems.push(Problem::ChecksumMismatch {
+                        crate_name: crate_name.clone(),
+                        vers: vers.clone(),
+                        expected: pkg.cksum.clone(),
+                        actual,
+                    });
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                problems.push(Problem::MissingCrateFile {
+                    crate_name: crate_name.clone(),
+                    vers: vers.clone(),
+                });
+            }
+            Err(e) => return Err(e.into()),
+        }
+        if !db_keys.contains(&(crate_name.clone(), vers.clone())) {
+            problems.push(Problem::MissingFromDatabase { crate_name, vers });
+        }
+    }
+
+    for stored in &stored {
+        let key = (stored.name.clone(), stored.vers.clone());
+        if !index_keys.contains(&key) && !db_keys.contains(&key) {
+            problems.push(Problem::OrphanCrateFile {
+                path: stored.path.clone(),
+            });
+        }
+    }
+
+    for (crate_name, vers) in &db_versions {
+        if !index_keys.contains(&(crate_name.clone(), vers.clone())) {
+            problems.push(Problem::MissingFromIndex {
+                crate_name: crate_name.clone(),
+                vers: vers.clone(),
+                has_crate_file: stored_keys.contains(&(crate_name.as_str(), vers.as_str())),
+            });
+        }
+    }
+
+    Ok(problems)
+}
+
+/// Repair a problem found by [`check`], if it's one of the safe cases.
+///
+/// Returns a description of what was done, or `None` when the problem was
+/// left alone.
+pub async fn repair(
+    problem: &Problem,
+    crate_dir: &Path,
+    db: &dyn Database,
+) -> Result<Option<String>, EstuaryError> {
+    match problem {
+        Problem::OrphanCrateFile { path } => {
+            let dest = storage::move_to_lost_and_found(crate_dir, path)?;
+            Ok(Some(format!("moved to `{}`", dest.display())))
+        }
+        Problem::MissingFromIndex {
+            crate_name,
+            vers,
+            has_crate_file: false,
+        } => {
+            db.delete_crate_version(crate_name.clone(), vers.clone())
+                .await?;
+            Ok(Some(String::from("removed from the database")))
+        }
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::NewCrateVersion;
+    use crate::package_index::{Config, PackageVersion};
+    use crate::test_helpers;
+
+    #[actix_rt::test]
+    async fn test_check_and_repair() {
+        let data_root = test_helpers::get_data_root();
+        let settings = test_helpers::get_test_settings(data_root.path()).await;
+        let index = PackageIndex::init(
+            &settings.index_dir,
+            &Config {
+                dl: String::new(),
+                api: String::new(),
+            },
+        )
+        .unwrap();
+        let db = settings.db.as_ref();
+        let admin = db.get_user(String::from("admin")).await.unwrap().unwrap();
+        let krate = db.create_crate(String::from("foo")).await.unwrap();
+        let add_version = |vers: &str| {
+            db.add_crate_version(
+                krate.id,
+                admin.id,
+                NewCrateVersion {
+                    vers: vers.to_string(),
+                    ..Default::default()
+                },
+            )
+        };
+        let store = |vers: &str, content: &[u8]| {
+            storage::store_crate_file(&settings.crate_dir, "foo", &vers.parse().unwrap(), content)
+                .unwrap()
+        };
+        let cksum = |content: &[u8]| format!("{:x}", Sha256::digest(content));
+        let pkg = |vers: &str, content: &[u8]| PackageVersion {
+            cksum: cksum(content),
+            ..test_helpers::pkg("foo", vers)
+        };
+
+        // Nothing but a .crate file.
+        store("0.6.0", b"six");
+        // All in order.
+        index.publish(&pkg("0.1.0", b"one"), None).unwrap();
+        store("0.1.0", b"one");
+        add_version("0.1.0").await.unwrap();
+        // The .crate file was changed.
+        index.publish(&pkg("0.2.0", b"two"), None).unwrap();
+        store("0.2.0", b"not two");
+        add_version("0.2.0").await.unwrap();
+        // Cut off after the index.
+        index.publish(&pkg("0.3.0", b"three"), None).unwrap();
+        // A deletion cut off after the index.
+        store("0.4.0", b"four");
+        add_version("0.4.0").await.unwrap();
+        // A deletion cut off after crate storage.
+        add_version("0.5.0").await.unwrap();
+        // A package file that's been edited by hand.
+        let bar = settings.index_dir.join("3/b/bar");
+        std::fs::create_dir_all(bar.parent().unwrap()).unwrap();
+        std::fs::write(&bar, "{\"name\":").unwrap();
+
+        let problems = check(&index, &settings.crate_dir, db).await.unwrap();
+        let expected = vec![
+            Problem::UnparsableLine(BadLine {
+                crate_name: String::from("bar"),
+                line: 1,
+                error: String::from("EOF while parsing a value at line 1 column 8"),
+            }),
+            Problem::ChecksumMismatch {
+                crate_name: String::from("foo"),
+                vers: String::from("0.2.0"),
+                expected: cksum(b"two"),
+                actual: cksum(b"not two"),
+            },
+            Problem::MissingCrateFile {
+                crate_name: String::from("foo"),
+                vers: String::from("0.3.0"),
+            },
+            Problem::MissingFromDatabase {
+                crate_name: String::from("foo"),
+                vers: String::from("0.3.0"),
+            },
+            Problem::OrphanCrateFile {
+                path: storage::get_crate_file_path(
+                    &settings.crate_dir,
+                    "foo",
+                    &"0.6.0".parse().unwrap(),
+                ),
+            },
+            Problem::MissingFromIndex {
+                crate_name: String::from("foo"),
+                vers: String::from("0.4.0"),
+                has_crate_file: true,
+            },
+            Problem::MissingFromIndex {
+                crate_name: String::from("foo"),
+                vers: String::from("0.5.0"),
+                has_crate_file: false,
+            },
+        ];
+        assert_eq!(expected, problems);
+
+        for problem in &problems {
+            let repaired = repair(problem, &settings.crate_dir, db).await.unwrap();
+            assert_eq!(problem.is_repairable(), repaired.is_some());
+        }
+
+        let problems = check(&index, &settings.crate_dir, db).await.unwrap();
+        assert!(problems.iter().all(|problem| !problem.is_repairable()));
+        assert_eq!(expected.len() - 2, problems.len());
+        assert!(settings
+            .crate_dir
+            .join(storage::LOST_AND_FOUND)
+            .join("foo/foo-0.6.0.crate")
+            .exists());
+    }
+}
@@ -0,0 +1,566 @@
+//! The server side of git's "smart" transport, built on libgit2.
+//!
+//! Cargo only ever fetches from the index, so this covers the read-only half
+//! of the protocol: advertising refs and answering `upload-pack` requests with
+//! a packfile. Pushes are not supported.
+//!
+//! Everything here is synchronous and works on a plain [`Repository`], leaving
+//! the HTTP side of things to the handlers.
+//!
+//! See: <https://git-scm.com/docs/pack-protocol> and
+//! <https://git-scm.com/docs/http-protocol>
+
+use crate::errors::GitProtocolError;
+use git2::{Oid, Repository};
+
+mod v2;
+pub use v2::{advertise_capabilities as advertise_capabilities_v2, upload_pack as upload_pack_v2};
+
+type Result<T> = std::result::Result<T, GitProtocolError>;
+
+/// How we identify ourselves to clients in the capability list.
+const AGENT: &str = concat!("estuary/", env!("CARGO_PKG_VERSION"));
+
+/// Marks the end of a section in a request or response.
+pub const FLUSH_PKT: &[u8] = b"0000";
+/// Separates the sections of a message in protocol v2.
+const DELIM_PKT: &[u8] = b"0001";
+
+/// The largest pkt-line allowed when sending on a side-band channel,
+/// including the length prefix.
+const SIDE_BAND_MAX: usize = 1000;
+const SIDE_BAND_64K_MAX: usize = 65520;
+
+/// Prefixes the string with 4 bytes representing the hex length of the string.
+///
+/// The lines git's response bodies use a packet protocol where the first 4
+/// bytes are the hex value of the length of the line (including the hex prefix).
+///
+/// Lines that include newline characters should have a literal `\n` in the
+/// string so it can be included in the length computed here.
+pub fn pkt_line(s: &str) -> String {
+    format!("{:04x}{}", s.len() + 4, s)
+}
+
+/// Append `data` to `out` as a single pkt-line.
+fn write_pkt(out: &mut Vec<u8>, data: &[u8]) {
+    out.extend(format!("{:04x}", data.len() + 4).as_bytes());
+    out.extend(data);
+}
+
+/// A single packet read from a request body.
+#[derive(Debug, PartialEq)]
+enum Pkt<'a> {
+    Flush,
+    Delim,
+    /// The payload of a line, without its trailing newline.
+    Line(&'a [u8]),
+}
+
+/// Split a request body into its packets.
+fn read_pkts(mut buf: &[u8]) -> Result<Vec<Pkt<'_>>> {
+    let mut pkts = vec![];
+    while !buf.is_empty() {
+        let len = buf
+            .get(..4)
+            .and_then(|prefix| std::str::from_utf8(prefix).ok())
+            .and_then(|prefix| usize::from_str_radix(prefix, 16).ok())
+            .ok_or_else(|| malformed("invalid pkt-line length"))?;
+
+        match len {
+            0 => {
+                pkts.push(Pkt::Flush);
+                buf = &buf[4..];
+            }
+            1 => {
+                pkts.push(Pkt::Delim);
+                buf = &buf[4..];
+            }
+            2..=3 => return Err(malformed("invalid pkt-line length")),
+            _ if len > buf.len() => return Err(malformed("truncated pkt-line")),
+            _ => {
+                let line = &buf[4..len];
+                pkts.push(Pkt::Line(line.strip_suffix(b"\n").unwrap_or(line)));
+                buf = &buf[len..];
+            }
+        }
+    }
+    Ok(pkts)
+}
+
+fn malformed(msg: &str) -> GitProtocolError {
+    GitProtocolError::Malformed(msg.to_string())
+}
+
+fn parse_oid(s: &str) -> Result<Oid> {
+    Oid::from_str(s).map_err(|_| GitProtocolError::Malformed(format!("invalid object id `{}`", s)))
+}
+
+/// The protocol versions we know how to speak.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProtocolVersion {
+    /// Versions 0 and 1 only differ by a `version 1` line in the ref
+    /// advertisement, so they're treated as one.
+    V0,
+    V2,
+}
+
+impl ProtocolVersion {
+    /// Pick a version based on the `Git-Protocol` header sent by the client.
+    ///
+    /// The header is a colon separated list of `key=value` parameters, with
+    /// the client asking for v2 by including `version=2`.
+    pub fn from_header(header: Option<&str>) -> Self {
+        match header {
+            Some(params) if params.split(':').any(|param| param == "version=2") => Self::V2,
+            _ => Self::V0,
+        }
+    }
+}
+
+/// The branches and tags we serve, sorted by name.
+fn served_refs(repo: &Repository) -> std::result::Result<Vec<(String, Oid)>, git2::Error> {
+    let mut refs = vec![];
+    for reference in repo.references()? {
+        let reference = reference?;
+        let name = match reference.name() {
+            Some(name) if name.starts_with("refs/heads/") || name.starts_with("refs/tags/") => {
+                name.to_string()
+            }
+            _ => continue,
+        };
+        let oid = match reference.resolve()?.target() {
+            Some(oid) => oid,
+            None => continue,
+        };
+        refs.push((name, oid));
+    }
+    refs.sort();
+    Ok(refs)
+}
+
+/// The refs we serve, sorted by name, in the form used by `info/refs`.
+///
+/// Only branches and tags are included. Annotated tags are followed by a
+/// `^{}` entry for the object they point to.
+pub fn list_refs(repo: &Repository) -> std::result::Result<Vec<(String, Oid)>, git2::Error> {
+    let refs = served_refs(repo)?;
+    let mut peeled = Vec::with_capacity(refs.len());
+    for (name, oid) in refs {
+        let tag_target = repo.find_tag(oid).map(|tag| tag.target_id()).ok();
+        if let Some(target) = tag_target {
+            peeled.push((name.clone(), oid));
+            peeled.push((format!("{}^{{}}", name), target));
+        } else {
+            peeled.push((name, oid));
+        }
+    }
+    Ok(peeled)
+}
+
+/// The capabilities we advertise for `upload-pack`.
+fn capabilities(repo: &Repository) -> Result<String> {
+    let mut caps = vec![
+        "side-band".to_string(),
+        "side-band-64k".to_string(),
+        "ofs-delta".to_string(),
+    ];
+    if let Some(target) = repo
+        .find_reference("HEAD")
+        .ok()
+        .and_then(|head| head.symbolic_target().map(String::from))
+    {
+        caps.push(format!("symref=HEAD:{}", target));
+    }
+    caps.push(format!("agent={}", AGENT));
+    Ok(caps.join(" "))
+}
+
+/// Build the ref advertisement sent in response to `info/refs`.
+///
+/// This doesn't include the `# service=...` header, which is particular to
+/// the HTTP transport.
+pub fn advertise_refs(repo: &Repository) -> Result<Vec<u8>> {
+    let mut refs = vec![];
+    if let Ok(head) = repo.head() {
+        if let Some(oid) = head.target() {
+            refs.push(("HEAD".to_string(), oid));
+        }
+    }
+    refs.extend(list_refs(repo)?);
+
+    let caps = capabilities(repo)?;
+    let mut out = vec![];
+    match refs.split_first() {
+        None => write_pkt(
+            &mut out,
+            format!("{} capabilities^{{}}\0{}\n", Oid::zero(), caps).as_bytes(),
+        ),
+        Some(((name, oid), rest)) => {
+            write_pkt(&mut out, format!("{} {}\0{}\n", oid, name, caps).as_bytes());
+            for (name, oid) in rest {
+                write_pkt(&mut out, format!("{} {}\n", oid, name).as_bytes());
+            }
+        }
+    }
+    out.extend(FLUSH_PKT);
+    Ok(out)
+}
+
+/// What a client asked for in an `upload-pack` request.
+#[derive(Debug, Default)]
+struct UploadPackRequest {
+    wants: Vec<Oid>,
+    haves: Vec<Oid>,
+    capabilities: Vec<String>,
+    done: bool,
+}
+
+impl UploadPackRequest {
+    fn parse(pkts: &[Pkt]) -> Result<Self> {
+        let mut req = Self::default();
+        for pkt in pkts {
+            let line = match pkt {
+                Pkt::Flush => continue,
+                Pkt::Delim => return Err(malformed("unexpected delim-pkt")),
+                Pkt::Line(line) => {
+                    std::str::from_utf8(line).map_err(|_| malformed("request is not utf-8"))?
+                }
+            };
+
+            let (cmd, arg) = match line.find(' ') {
+                Some(idx) => (&line[..idx], &line[idx + 1..]),
+                None => (line, ""),
+            };
+            match cmd {
+                "want" => {
+                    let mut parts = arg.split(' ');
+                    req.wants.push(parse_oid(parts.next().unwrap_or_default())?);
+                    // Capabilities are only sent along with the first want.
+                    if req.wants.len() == 1 {
+                        req.capabilities = parts.map(String::from).collect();
+                    }
+                }
+                "have" => req.haves.push(parse_oid(arg)?),
+                "done" => req.done = true,
+                "shallow" | "deepen" | "deepen-since" | "deepen-not" => {
+                    return Err(malformed("shallow fetches are not supported"))
+                }
+                _ => {
+                    return Err(GitProtocolError::Malformed(format!(
+                        "unexpected line `{}`",
+                        line
+                    )))
+                }
+            }
+        }
+        Ok(req)
+    }
+
+    /// The max pkt-line size for sending the pack, if the client asked for it
+    /// to be multiplexed.
+    fn side_band(&self) -> Option<usize> {
+        if self.capabilities.iter().any(|c| c == "side-band-64k") {
+            Some(SIDE_BAND_64K_MAX)
+        } else if self.capabilities.iter().any(|c| c == "side-band") {
+            Some(SIDE_BAND_MAX)
+        } else {
+            None
+        }
+    }
+}
+
+/// Answer a single (stateless) `upload-pack` request.
+///
+/// We don't offer any of the `multi_ack` modes so negotiation is as simple as
+/// it gets: we ACK the first object the client has in common with us, or NAK
+/// if there isn't one. Once the client says it's done, the packfile follows.
+pub fn upload_pack(repo: &Repository, body: &[u8]) -> Result<Vec<u8>> {
+    let pkts = read_pkts(body)?;
+    if pkts.is_empty() {
+        return Err(malformed("empty request"));
+    }
+    let req = UploadPackRequest::parse(&pkts)?;
+
+    let mut out = vec![];
+    if req.wants.is_empty() {
+        return Ok(out);
+    }
+
+    let odb = repo.odb()?;
+    if let Some(unknown) = req.wants.iter().find(|oid| !odb.exists(**oid)) {
+        return Err(GitProtocolError::UnknownObject(*unknown));
+    }
+
+    let common: Vec<Oid> = req
+        .haves
+        .iter()
+        .copied()
+        .filter(|oid| odb.exists(*oid))
+        .collect();
+
+    match common.first() {
+        Some(oid) => write_pkt(&mut out, format!("ACK {}\n", oid).as_bytes()),
+        None => write_pkt(&mut out, b"NAK\n"),
+    }
+    if !req.done {
+        return Ok(out);
+    }
+
+    let pack = build_pack(repo, &req.wants, &common)?;
+    write_pack(&mut out, &pack, req.side_band());
+    Ok(out)
+}
+
+/// Build a packfile with everything reachable from `wants` that isn't
+/// reachable from `haves`.
+fn build_pack(repo: &Repository, wants: &[Oid], haves: &[Oid]) -> Result<Vec<u8>> {
+    let mut builder = repo.packbuilder()?;
+    let mut walk = repo.revwalk()?;
+    for want in wants {
+        // Annotated tags have to be sent along with the commit they point to.
+        if repo.find_tag(*want).is_ok() {
+            builder.insert_object(*want, None)?;
+        }
+        walk.push(*want)?;
+    }
+    for have in haves {
+        if repo.find_commit(*have).is_ok() {
+            walk.hide(*have)?;
+        }
+    }
+    builder.insert_walk(&mut walk)?;
+
+    let mut buf = git2::Buf::new();
+    builder.write_buf(&mut buf)?;
+    Ok(buf.to_vec())
+}
+
+/// Append the pack to the response, split over side-band channel 1 when the
+/// client asked for that.
+fn write_pack(out: &mut Vec<u8>, pack: &[u8], side_band: Option<usize>) {
+    match side_band {
+        Some(max) => {
+            // 4 bytes for the length prefix and 1 for the channel number.
+            for chunk in pack.chunks(max - 5) {
+                let mut data = Vec::with_capacity(chunk.len() + 1);
+                data.push(1);
+                data.extend(chunk);
+                write_pkt(out, &data);
+            }
+            out.extend(FLUSH_PKT);
+        }
+        None => out.extend(pack),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use git2::Signature;
+    use std::path::Path;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_pkt_line_from_example() {
+        let input = "d049f6c27a2244e12041955e262a404c7faba355 refs/heads/master\n";
+        let expected = "003fd049f6c27a2244e12041955e262a404c7faba355 refs/heads/master\n";
+        assert_eq!(expected, pkt_line(input));
+    }
+
+    #[test]
+    fn test_pkt_line_empty() {
+        let input = "";
+        let expected = "0004";
+        assert_eq!(expected, pkt_line(input));
+    }
+
+    #[test]
+    fn test_read_pkts() {
+        let body = b"0009want\n0000000bhave 1\n0008done";
+        assert_eq!(
+            vec![
+                Pkt::Line(b"want"),
+                Pkt::Flush,
+                Pkt::Line(b"have 1"),
+                Pkt::Line(b"done"),
+            ],
+            read_pkts(body).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_read_pkts_truncated() {
+        assert!(read_pkts(b"0009wa").is_err());
+        assert!(read_pkts(b"00").is_err());
+        assert!(read_pkts(b"zzzz").is_err());
+    }
+
+    /// Commit a file to the repo's `HEAD`, returning the new commit.
+    pub(crate) fn commit_file(repo: &Repository, name: &str, contents: &str) -> Oid {
+        let workdir = repo.workdir().unwrap();
+        std::fs::write(workdir.join(name), contents).unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new(name)).unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let sig = Signature::now("test", "test@localhost").unwrap();
+        let parent = repo.head().ok().map(|head| head.peel_to_commit().unwrap());
+        let parents: Vec<_> = parent.iter().collect();
+        repo.commit(Some("HEAD"), &sig, &sig, name, &tree, &parents)
+            .unwrap()
+    }
+
+    /// Pull the packfile out of an `upload-pack` response sent over
+    /// side-band, along with the lines that came before it.
+    pub(crate) fn split_response(mut body: &[u8]) -> (Vec<String>, Vec<u8>) {
+        let mut lines = vec![];
+        let mut pack = vec![];
+        while !body.is_empty() {
+            let len = usize::from_str_radix(std::str::from_utf8(&body[..4]).unwrap(), 16).unwrap();
+            if len == 0 {
+                body = &body[4..];
+                continue;
+            }
+            let data = &body[4..len];
+            match data[0] {
+                1 => pack.extend(&data[1..]),
+                2 | 3 => {}
+                _ => lines.push(String::from_utf8(data.to_vec()).unwrap()),
+            }
+            body = &body[len..];
+        }
+        (lines, pack)
+    }
+
+    /// Write a pack into a repo's object database.
+    pub(crate) fn index_pack(repo: &Repository, pack: &[u8]) {
+        use std::io::Write;
+        let odb = repo.odb().unwrap();
+        let mut writer = odb.packwriter().unwrap();
+        writer.write_all(pack).unwrap();
+        writer.commit().unwrap();
+    }
+
+    fn request(lines: &[&str]) -> Vec<u8> {
+        let mut body = vec![];
+        for line in lines {
+            if line.is_empty() {
+                body.extend(FLUSH_PKT);
+            } else {
+                body.extend(pkt_line(&format!("{}\n", line)).as_bytes());
+            }
+        }
+        body
+    }
+
+    #[test]
+    fn test_advertise_refs() {
+        let root = TempDir::new("test_advertise_refs").unwrap();
+        let repo = Repository::init(root.path()).unwrap();
+        let head = commit_file(&repo, "a", "a");
+        let branch = repo.head().unwrap().name().unwrap().to_string();
+
+        let body = String::from_utf8(advertise_refs(&repo).unwrap()).unwrap();
+        let lines: Vec<_> = body.split('\n').collect();
+
+        assert!(lines[0][4..].starts_with(&format!("{} HEAD\0", head)));
+        assert!(lines[0].contains(&format!("symref=HEAD:{}", branch)));
+        assert!(lines[0].contains("side-band-64k"));
+        assert!(lines[1].ends_with(&format!("{} {}", head, branch)));
+        assert_eq!("0000", lines[2]);
+    }
+
+    #[test]
+    fn test_upload_pack_clone() {
+        let root = TempDir::new("test_upload_pack_clone").unwrap();
+        let repo = Repository::init(root.path().join("server")).unwrap();
+        commit_file(&repo, "a", "a");
+        let head = commit_file(&repo, "b", "b");
+
+        let body = request(&[
+            &format!("want {} side-band-64k ofs-delta", head),
+            "",
+            "done",
+        ]);
+        let (lines, pack) = split_response(&upload_pack(&repo, &body).unwrap());
+        assert_eq!(vec!["NAK\n"], lines);
+
+        let client = Repository::init(root.path().join("client")).unwrap();
+        index_pack(&client, &pack);
+        let commit = client.find_commit(head).unwrap();
+        assert_eq!(1, commit.parent_count());
+        assert!(commit.tree().unwrap().get_name("a").is_some());
+    }
+
+    #[test]
+    fn test_upload_pack_incremental_fetch() {
+        let root = TempDir::new("test_upload_pack_incremental_fetch").unwrap();
+        let repo = Repository::init(root.path()).unwrap();
+        let first = commit_file(&repo, "a", "a");
+        let second = commit_file(&repo, "b", "b");
+
+        let body = request(&[&format!("want {} side-band-64k", second), "", &format!("have {}", first), ""]);
+        let out = upload_pack(&repo, &body).unwrap();
+        assert_eq!(pkt_line(&format!("ACK {}\n", first)).as_bytes(), &out[..]);
+
+        let body = request(&[
+            &format!("want {} side-band-64k", second),
+            "",
+            &format!("have {}", first),
+            "done",
+        ]);
+        let (lines, pack) = split_response(&upload_pack(&repo, &body).unwrap());
+        assert_eq!(vec![format!("ACK {}\n", first)], lines);
+
+        // The new commit, its tree and the new blob.
+        let full = build_pack(&repo, &[second], &[]).unwrap();
+        assert!(pack.len() < full.len());
+        assert_eq!(&pack[8..12], &3u32.to_be_bytes());
+    }
+
+    #[test]
+    fn test_upload_pack_no_side_band() {
+        let root = TempDir::new("test_upload_pack_no_side_band").unwrap();
+        let repo = Repository::init(root.path()).unwrap();
+        let head = commit_file(&repo, "a", "a");
+
+        let body = request(&[&format!("want {}", head), "", "done"]);
+        let out = upload_pack(&repo, &body).unwrap();
+        assert!(out.starts_with(b"0008NAK\nPACK"));
+    }
+
+    #[test]
+    fn test_upload_pack_not_done() {
+        let root = TempDir::new("test_upload_pack_not_done").unwrap();
+        let repo = Repository::init(root.path()).unwrap();
+        let head = commit_file(&repo, "a", "a");
+
+        let body = request(&[&format!("want {}", head), "", &format!("have {}", Oid::zero()), ""]);
+        assert_eq!(b"0008NAK\n", &upload_pack(&repo, &body).unwrap()[..]);
+    }
+
+    #[test]
+    fn test_upload_pack_bad_requests() {
+        let root = TempDir::new("test_upload_pack_bad_requests").unwrap();
+        let repo = Repository::init(root.path()).unwrap();
+        let head = commit_file(&repo, "a", "a");
+
+        assert!(matches!(
+            upload_pack(&repo, b""),
+            Err(GitProtocolError::Malformed(_))
+        ));
+        assert!(matches!(
+            upload_pack(&repo, &request(&["want nope", "", "done"])),
+            Err(GitProtocolError::Malformed(_))
+        ));
+        assert!(matches!(
+            upload_pack(&repo, &request(&[&format!("want {}", head), "deepen 1", "", "done"])),
+            Err(GitProtocolError::Malformed(_))
+        ));
+        assert!(matches!(
+            upload_pack(&repo, &request(&[&format!("want {}", Oid::zero()), "", "done"])),
+            Err(GitProtocolError::UnknownObject(_))
+        ));
+    }
+}
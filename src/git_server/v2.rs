@@ -0,0 +1,388 @@
+//! Protocol v2 for `upload-pack`.
+//!
+//! Instead of advertising every ref up front, v2 advertises a list of
+//! commands. Clients then send a request per command: `ls-refs` to list the
+//! refs they're interested in, and `fetch` to negotiate and receive a pack.
+//!
+//! See: <https://git-scm.com/docs/protocol-v2>
+
+use super::{
+    build_pack, malformed, parse_oid, read_pkts, served_refs, write_pack, write_pkt, Pkt,
+    Result, AGENT, DELIM_PKT, FLUSH_PKT, SIDE_BAND_64K_MAX,
+};
+use crate::errors::GitProtocolError;
+use git2::{Oid, Repository};
+
+/// Build the capability advertisement sent in response to `info/refs`.
+pub fn advertise_capabilities() -> Vec<u8> {
+    let mut out = vec![];
+    for line in &[
+        "version 2".to_string(),
+        format!("agent={}", AGENT),
+        "ls-refs".to_string(),
+        "fetch".to_string(),
+        "object-format=sha1".to_string(),
+    ] {
+        write_pkt(&mut out, format!("{}\n", line).as_bytes());
+    }
+    out.extend(FLUSH_PKT);
+    out
+}
+
+/// A single v2 command sent by the client.
+#[derive(Debug, PartialEq)]
+struct Command<'a> {
+    name: &'a str,
+    /// The lines following the delim-pkt.
+    args: Vec<&'a str>,
+}
+
+impl<'a> Command<'a> {
+    fn parse(pkts: &[Pkt<'a>]) -> Result<Option<Self>> {
+        let mut name = None;
+        let mut args = vec![];
+        let mut in_args = false;
+        for pkt in pkts {
+            match pkt {
+                Pkt::Flush => break,
+                Pkt::Delim if !in_args => in_args = true,
+                Pkt::Delim => return Err(malformed("unexpected delim-pkt")),
+                Pkt::Line(line) => {
+                    let line = std::str::from_utf8(line)
+                        .map_err(|_| malformed("request is not utf-8"))?;
+                    if in_args {
+                        args.push(line);
+                    } else if let Some(command) = line.strip_prefix("command=") {
+                        name = Some(command);
+                    }
+                    // Any other capabilities (`agent`, `object-format`) don't
+                    // change how we respond.
+                }
+            }
+        }
+
+        match name {
+            Some(name) => Ok(Some(Command { name, args })),
+            // A lone flush-pkt is how the client ends the session.
+            None if matches!(pkts, [Pkt::Flush]) => Ok(None),
+            None => Err(malformed("missing command")),
+        }
+    }
+}
+
+/// Answer a single v2 `upload-pack` request.
+pub fn upload_pack(repo: &Repository, body: &[u8]) -> Result<Vec<u8>> {
+    let pkts = read_pkts(body)?;
+    if pkts.is_empty() {
+        return Err(malformed("empty request"));
+    }
+
+    match Command::parse(&pkts)? {
+        None => Ok(vec![]),
+        Some(Command {
+            name: "ls-refs",
+            args,
+        }) => ls_refs(repo, &args),
+        Some(Command {
+            name: "fetch",
+            args,
+        }) => fetch(repo, &args),
+        Some(Command { name, .. }) => Err(GitProtocolError::Malformed(format!(
+            "unknown command `{}`",
+            name
+        ))),
+    }
+}
+
+/// List `HEAD` and the refs we serve, limited to those matching any
+/// `ref-prefix` given by the client.
+fn ls_refs(repo: &Repository, args: &[&str]) -> Result<Vec<u8>> {
+    let mut symrefs = false;
+    let mut peel = false;
+    let mut prefixes = vec![];
+    for arg in args {
+        match *arg {
+            "symrefs" => symrefs = true,
+            "peel" => peel = true,
+            _ => match arg.strip_prefix("ref-prefix ") {
+                Some(prefix) => prefixes.push(prefix),
+                None => {
+                    return Err(GitProtocolError::Malformed(format!(
+                        "unexpected argument `{}`",
+                        arg
+                    )))
+                }
+            },
+        }
+    }
+
+    let mut refs = vec![];
+    if let Ok(oid) = repo.refname_to_id("HEAD") {
+        refs.push(("HEAD".to_string(), oid));
+    }
+    refs.extend(served_refs(repo)?);
+
+    let mut out = vec![];
+    for (name, oid) in refs {
+        if !prefixes.is_empty() && !prefixes.iter().any(|prefix| name.starts_with(prefix)) {
+            continue;
+        }
+
+        let mut line = format!("{} {}", oid, name);
+        if symrefs {
+            if let Some(target) = repo
+                .find_reference(&name)
+                .ok()
+                .and_then(|r| r.symbolic_target().map(String::from))
+            {
+                line.push_str(&format!(" symref-target:{}", target));
+            }
+        }
+        if peel {
+            if let Ok(tag) = repo.find_tag(oid) {
+                line.push_str(&format!(" peeled:{}", tag.target_id()));
+            }
+        }
+        line.push('\n');
+        write_pkt(&mut out, line.as_bytes());
+    }
+    out.extend(FLUSH_PKT);
+    Ok(out)
+}
+
+/// Negotiate with the client, sending the pack once there's enough common
+/// ground or the client says it's done.
+///
+/// Like with v0, we don't try to find the best common commits. As soon as the
+/// client has shown us anything we have, we're ready to send the pack.
+fn fetch(repo: &Repository, args: &[&str]) -> Result<Vec<u8>> {
+    let mut wants = vec![];
+    let mut haves = vec![];
+    let mut done = false;
+    for arg in args {
+        let (cmd, value) = match arg.find(' ') {
+            Some(idx) => (&arg[..idx], &arg[idx + 1..]),
+            None => (*arg, ""),
+        };
+        match cmd {
+            "want" => wants.push(parse_oid(value)?),
+            "have" => haves.push(parse_oid(value)?),
+            "done" => done = true,
+            // We always send ofs-deltas, never send thin packs or progress,
+            // and the index has no tags to include.
+            "ofs-delta" | "thin-pack" | "no-progress" | "include-tag" => {}
+            _ => {
+                return Err(GitProtocolError::Malformed(format!(
+                    "unexpected argument `{}`",
+                    arg
+                )))
+            }
+        }
+    }
+
+    if wants.is_empty() {
+        return Err(malformed("no wants in fetch request"));
+    }
+
+    let odb = repo.odb()?;
+    if let Some(unknown) = wants.iter().find(|oid| !odb.exists(**oid)) {
+        return Err(GitProtocolError::UnknownObject(*unknown));
+    }
+    let common: Vec<Oid> = haves.into_iter().filter(|oid| odb.exists(*oid)).collect();
+
+    let mut out = vec![];
+    if !done {
+        write_pkt(&mut out, b"acknowledgments\n");
+        if common.is_empty() {
+            write_pkt(&mut out, b"NAK\n");
+            out.extend(FLUSH_PKT);
+            return Ok(out);
+        }
+        for oid in &common {
+            write_pkt(&mut out, format!("ACK {}\n", oid).as_bytes());
+        }
+        write_pkt(&mut out, b"ready\n");
+        out.extend(DELIM_PKT);
+    }
+
+    let pack = build_pack(repo, &wants, &common)?;
+    write_pkt(&mut out, b"packfile\n");
+    write_pack(&mut out, &pack, Some(SIDE_BAND_64K_MAX));
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{commit_file, index_pack};
+    use super::*;
+    use crate::git_server::pkt_line;
+    use tempdir::TempDir;
+
+    fn request(command: &str, args: &[&str]) -> Vec<u8> {
+        let mut body = pkt_line(&format!("command={}\n", command)).into_bytes();
+        body.extend(pkt_line("object-format=sha1\n").as_bytes());
+        body.extend(DELIM_PKT);
+        for arg in args {
+            body.extend(pkt_line(&format!("{}\n", arg)).as_bytes());
+        }
+        body.extend(FLUSH_PKT);
+        body
+    }
+
+    fn lines(body: &[u8]) -> Vec<String> {
+        read_pkts(body)
+            .unwrap()
+            .into_iter()
+            .map(|pkt| match pkt {
+                Pkt::Flush => "0000".to_string(),
+                Pkt::Delim => "0001".to_string(),
+                Pkt::Line(line) => String::from_utf8_lossy(line).into_owned(),
+            })
+            .collect()
+    }
+
+    /// Pull the pack out of a `packfile` section.
+    fn unpack(body: &[u8]) -> Vec<u8> {
+        let pkts = read_pkts(body).unwrap();
+        let start = pkts
+            .iter()
+            .position(|pkt| *pkt == Pkt::Line(b"packfile"))
+            .unwrap();
+        let mut pack = vec![];
+        for pkt in &pkts[start + 1..] {
+            if let Pkt::Line(data) = pkt {
+                assert_eq!(1, data[0]);
+                pack.extend(&data[1..]);
+            }
+        }
+        pack
+    }
+
+    #[test]
+    fn test_protocol_version_from_header() {
+        use crate::git_server::ProtocolVersion;
+        assert_eq!(ProtocolVersion::V0, ProtocolVersion::from_header(None));
+        assert_eq!(
+            ProtocolVersion::V0,
+            ProtocolVersion::from_header(Some("version=1"))
+        );
+        assert_eq!(
+            ProtocolVersion::V2,
+            ProtocolVersion::from_header(Some("version=2"))
+        );
+        assert_eq!(
+            ProtocolVersion::V2,
+            ProtocolVersion::from_header(Some("object-format=sha1:version=2"))
+        );
+    }
+
+    #[test]
+    fn test_advertise_capabilities() {
+        let lines = lines(&advertise_capabilities());
+        assert_eq!("version 2", lines[0]);
+        assert!(lines.contains(&"ls-refs".to_string()));
+        assert!(lines.contains(&"fetch".to_string()));
+        assert_eq!("0000", lines[lines.len() - 1]);
+    }
+
+    #[test]
+    fn test_ls_refs() {
+        let root = TempDir::new("test_ls_refs").unwrap();
+        let repo = Repository::init(root.path()).unwrap();
+        let head = commit_file(&repo, "a", "a");
+        let branch = repo.head().unwrap().name().unwrap().to_string();
+
+        let out = upload_pack(&repo, &request("ls-refs", &["symrefs", "peel"])).unwrap();
+        assert_eq!(
+            vec![
+                format!("{} HEAD symref-target:{}", head, branch),
+                format!("{} {}", head, branch),
+                "0000".to_string(),
+            ],
+            lines(&out)
+        );
+    }
+
+    #[test]
+    fn test_ls_refs_prefix() {
+        let root = TempDir::new("test_ls_refs_prefix").unwrap();
+        let repo = Repository::init(root.path()).unwrap();
+        let head = commit_file(&repo, "a", "a");
+        repo.tag_lightweight("v1", &repo.find_object(head, None).unwrap(), false)
+            .unwrap();
+
+        let out = upload_pack(&repo, &request("ls-refs", &["ref-prefix refs/tags/"])).unwrap();
+        assert_eq!(
+            vec![format!("{} refs/tags/v1", head), "0000".to_string()],
+            lines(&out)
+        );
+    }
+
+    #[test]
+    fn test_fetch_done() {
+        let root = TempDir::new("test_fetch_done").unwrap();
+        let repo = Repository::init(root.path().join("server")).unwrap();
+        commit_file(&repo, "a", "a");
+        let head = commit_file(&repo, "b", "b");
+
+        let body = request("fetch", &[&format!("want {}", head), "ofs-delta", "done"]);
+        let out = upload_pack(&repo, &body).unwrap();
+        assert_eq!("packfile", lines(&out)[0]);
+
+        let client = Repository::init(root.path().join("client")).unwrap();
+        index_pack(&client, &unpack(&out));
+        assert_eq!(1, client.find_commit(head).unwrap().parent_count());
+    }
+
+    #[test]
+    fn test_fetch_negotiation() {
+        let root = TempDir::new("test_fetch_negotiation").unwrap();
+        let repo = Repository::init(root.path()).unwrap();
+        let first = commit_file(&repo, "a", "a");
+        let second = commit_file(&repo, "b", "b");
+
+        let body = request(
+            "fetch",
+            &[&format!("want {}", second), &format!("have {}", Oid::zero())],
+        );
+        assert_eq!(
+            vec!["acknowledgments", "NAK", "0000"],
+            lines(&upload_pack(&repo, &body).unwrap())
+        );
+
+        let body = request(
+            "fetch",
+            &[&format!("want {}", second), &format!("have {}", first)],
+        );
+        let out = upload_pack(&repo, &body).unwrap();
+        assert_eq!(
+            vec![
+                "acknowledgments".to_string(),
+                format!("ACK {}", first),
+                "ready".to_string(),
+                "0001".to_string(),
+                "packfile".to_string(),
+            ],
+            lines(&out)[..5].to_vec()
+        );
+        // The new commit, its tree and the new blob.
+        assert_eq!(&unpack(&out)[8..12], &3u32.to_be_bytes());
+    }
+
+    #[test]
+    fn test_bad_requests() {
+        let root = TempDir::new("test_bad_requests").unwrap();
+        let repo = Repository::init(root.path()).unwrap();
+        let head = commit_file(&repo, "a", "a");
+
+        assert!(upload_pack(&repo, b"").is_err());
+        assert!(upload_pack(&repo, &request("push", &[])).is_err());
+        assert!(upload_pack(&repo, &request("fetch", &["done"])).is_err());
+        assert!(upload_pack(&repo, &request("fetch", &[&format!("want {}", head), "deepen 1"])).is_err());
+        assert!(matches!(
+            upload_pack(&repo, &request("fetch", &[&format!("want {}", Oid::zero()), "done"])),
+            Err(GitProtocolError::UnknownObject(_))
+        ));
+        assert!(upload_pack(&repo, FLUSH_PKT).unwrap().is_empty());
+    }
+}
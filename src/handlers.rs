@@ -1,33 +1,74 @@
-use actix_web::web;
+use actix_web::error::{InternalError, JsonPayloadError};
+use actix_web::{web, HttpRequest, HttpResponse};
+pub mod admin;
 pub mod frontend;
 pub mod git;
+pub mod monitoring;
 pub mod registry;
 
+/// Bad json bodies get the same json errors as the rest of the API, rather
+/// than actix's plain text.
+fn json_error(err: JsonPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    let resp = HttpResponse::BadRequest().json(crate::logging::error_body(&err.to_string()));
+    InternalError::from_response(err, resp).into()
+}
+
 pub fn configure_routes(cfg: &mut web::ServiceConfig) {
-    cfg.service(
+    cfg.app_data(web::JsonConfig::default().error_handler(json_error))
+    .service(
         web::scope("/git/index")
             .service(git::get_info_refs)
             .service(git::upload_pack),
     )
+    .service(web::scope("/api/v1/audit").service(admin::audit_log))
+    .service(
+        web::scope("/api/v1/webhooks")
+            .service(admin::list_webhooks)
+            .service(admin::add_webhook)
+            .service(admin::delete_webhook)
+            .service(admin::webhook_deliveries),
+    )
     .service(
         web::scope("/api/v1/crates")
             .service(registry::publish)
             .service(registry::yank)
             .service(registry::unyank)
+            .service(registry::delete_version)
+            .service(registry::upload_docs)
             .service(registry::download)
-            .service(registry::search),
+            .service(registry::search)
+            // `versions` has to be matched before it can be mistaken for a
+            // version number.
+            .service(registry::crate_versions)
+            .service(registry::reverse_dependencies)
+            .service(registry::crate_version_dependencies)
+            .service(registry::crate_version)
+            .service(registry::crate_info),
     )
+    .service(monitoring::get_metrics)
+    .service(monitoring::healthz)
+    .service(monitoring::readyz)
     .service(frontend::styles)
     .service(frontend::login)
     .service(frontend::me_redirect)
     .service(frontend::landing)
+    .service(frontend::releases_feed)
     .service(frontend::login_req)
     .service(frontend::get_user)
     .service(frontend::gen_api_key)
     .service(frontend::revoke_api_key)
+    .service(admin::audit_log_page)
+    .service(
+        web::scope("/docs/{crate_name}/{version}")
+            .route("", web::get().to(frontend::docs))
+            .route("/", web::get().to(frontend::docs))
+            .route("/{path:.*}", web::get().to(frontend::docs)),
+    )
     .service(
         web::scope("/crates/{crate_name}")
             .route("/versions", web::get().to(frontend::version_list))
+            .route("/{version}/source", web::get().to(frontend::crate_source))
+            .route("/{version}/source/{path:.*}", web::get().to(frontend::crate_source))
             .route("/{version}", web::get().to(frontend::crate_detail))
             .route("", web::get().to(frontend::crate_detail)),
     );
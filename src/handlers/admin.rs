@@ -0,0 +1,664 @@
+//! Views of the registry which only administrators may see.
+//!
+//! - Audit log `GET /api/v1/audit` (json) and `GET /admin/audit` (html), both
+//!   accepting the query params `crate`, `user`, `action`, `page` and
+//!   `per_page` (default 100, max 100).
+//! - Webhooks List `GET /api/v1/webhooks`.
+//! - Webhooks Add `POST /api/v1/webhooks`, taking a json body with `url`,
+//!   and optionally the `events` to send (default all) and a `secret` (default
+//!   random). The secret is only ever shown in the response to this request.
+//! - Webhooks Remove `DELETE /api/v1/webhooks/{id}`.
+//! - Webhook Deliveries `GET /api/v1/webhooks/{id}/deliveries`, the most
+//!   recent 100 attempts.
+
+use crate::audit;
+use crate::auth::Authenticated;
+use crate::database::{
+    AuditAction, AuditEntry, AuditQuery, NewAuditEntry, User, Webhook, WebhookDelivery,
+};
+use crate::errors::{ApiError, EstuaryError};
+use crate::handlers::registry::format_timestamp;
+use crate::webhooks::{self, WebhookEvent};
+use crate::Settings;
+use actix_web::{delete, get, post, web, HttpRequest, HttpResponse};
+use askama::Template;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+const MAX_PER_PAGE: i64 = 100;
+
+/// Filters for the audit log, shared by the json and html views.
+#[derive(Deserialize)]
+pub struct AuditParams {
+    #[serde(rename = "crate")]
+    crate_name: Option<String>,
+    user: Option<String>,
+    action: Option<AuditAction>,
+    /// Starts at 1.
+    page: Option<i64>,
+    per_page: Option<i64>,
+}
+
+impl AuditParams {
+    fn page(&self) -> i64 {
+        self.page.unwrap_or(1).max(1)
+    }
+
+    fn per_page(&self) -> i64 {
+        self.per_page.unwrap_or(MAX_PER_PAGE).clamp(1, MAX_PER_PAGE)
+    }
+
+    fn to_query(&self) -> AuditQuery {
+        AuditQuery {
+            crate_name: self.crate_name.clone().filter(|s| !s.is_empty()),
+            user: self.user.clone().filter(|s| !s.is_empty()),
+            action: self.action,
+            limit: self.per_page(),
+            offset: (self.page() - 1) * self.per_page(),
+        }
+    }
+
+    /// The query string for another page of the same results.
+    fn page_query(&self, page: i64) -> String {
+        let mut query = url::form_urlencoded::Serializer::new(String::new());
+        if let Some(crate_name) = &self.crate_name {
+            query.append_pair("crate", crate_name);
+        }
+        if let Some(user) = &self.user {
+            query.append_pair("user", user);
+        }
+        if let Some(action) = &self.action {
+            query.append_pair("action", action.as_str());
+        }
+        query.append_pair("page", &page.to_string());
+        query.append_pair("per_page", &self.per_page().to_string());
+        query.finish()
+    }
+}
+
+#[derive(Serialize)]
+pub struct ApiAuditEntry {
+    id: i32,
+    user: Option<String>,
+    action: String,
+    #[serde(rename = "crate")]
+    crate_name: Option<String>,
+    version: Option<String>,
+    detail: Option<String>,
+    ip: Option<String>,
+    created_at: Option<String>,
+}
+
+impl From<AuditEntry> for ApiAuditEntry {
+    fn from(entry: AuditEntry) -> Self {
+        ApiAuditEntry {
+            id: entry.id,
+            user: entry.user_name,
+            action: entry.action,
+            crate_name: entry.crate_name,
+            version: entry.vers,
+            detail: entry.detail,
+            ip: entry.ip,
+            created_at: format_timestamp(entry.created_at),
+        }
+    }
+}
+
+#[get("")]
+pub async fn audit_log(
+    params: web::Query<AuditParams>,
+    settings: web::Data<Settings>,
+    auth: Authenticated,
+) -> Result<HttpResponse, ApiError> {
+    if !auth.is_admin() {
+        return Err(ApiError::Forbidden);
+    }
+
+    let entries: Vec<ApiAuditEntry> = settings
+        .db
+        .get_audit_entries(params.to_query())
+        .await?
+        .into_iter()
+        .map(ApiAuditEntry::from)
+        .collect();
+
+    Ok(HttpResponse::Ok().json(json!({ "entries": entries })))
+}
+
+#[derive(Serialize)]
+pub struct ApiWebhook {
+    id: i32,
+    url: String,
+    events: Vec<String>,
+    created_at: Option<String>,
+    /// Only included when the webhook is created.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    secret: Option<String>,
+}
+
+impl From<Webhook> for ApiWebhook {
+    fn from(webhook: Webhook) -> Self {
+        ApiWebhook {
+            id: webhook.id,
+            url: webhook.url,
+            events: webhook.events.split(',').map(String::from).collect(),
+            created_at: format_timestamp(webhook.created_at),
+            secret: None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ApiWebhookDelivery {
+    id: i32,
+    event: String,
+    attempt: i32,
+    status: Option<i32>,
+    error: Option<String>,
+    payload: serde_json::Value,
+    created_at: Option<String>,
+}
+
+impl From<WebhookDelivery> for ApiWebhookDelivery {
+    fn from(delivery: WebhookDelivery) -> Self {
+        ApiWebhookDelivery {
+            id: delivery.id,
+            event: delivery.event,
+            attempt: delivery.attempt,
+            status: delivery.status,
+            error: delivery.error,
+            payload: serde_json::from_str(&delivery.payload).unwrap_or_default(),
+            created_at: format_timestamp(delivery.created_at),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct NewWebhook {
+    url: String,
+    events: Option<Vec<WebhookEvent>>,
+    secret: Option<String>,
+}
+
+#[get("")]
+pub async fn list_webhooks(
+    settings: web::Data<Settings>,
+    auth: Authenticated,
+) -> Result<HttpResponse, ApiError> {
+    if !auth.is_admin() {
+        return Err(ApiError::Forbidden);
+    }
+
+    let webhooks: Vec<ApiWebhook> = settings
+        .db
+        .get_webhooks()
+        .await?
+        .into_iter()
+        .map(ApiWebhook::from)
+        .collect();
+
+    Ok(HttpResponse::Ok().json(json!({ "webhooks": webhooks })))
+}
+
+#[post("")]
+pub async fn add_webhook(
+    req: HttpRequest,
+    new_webhook: web::Json<NewWebhook>,
+    settings: web::Data<Settings>,
+    auth: Authenticated,
+) -> Result<HttpResponse, ApiError> {
+    if !auth.is_admin() {
+        return Err(ApiError::Forbidden);
+    }
+
+    let new_webhook = new_webhook.into_inner();
+    match url::Url::parse(&new_webhook.url) {
+        Ok(url) if url.scheme() == "http" || url.scheme() == "https" => {}
+        _ => {
+            return Err(ApiError::BadRequest(format!(
+                "`{}` is not an http(s) url.",
+                new_webhook.url
+            )))
+        }
+    }
+    let events = match new_webhook.events {
+        Some(events) if events.is_empty() => {
+            return Err(ApiError::BadRequest(String::from(
+                "A webhook needs at least one event.",
+            )))
+        }
+        Some(events) => events,
+        None => WebhookEvent::ALL.to_vec(),
+    };
+    let secret = new_webhook.secret.unwrap_or_else(webhooks::generate_secret);
+
+    let webhook = settings
+        .db
+        .add_webhook(new_webhook.url, secret, webhooks::format_events(&events))
+        .await?;
+
+    audit::record(
+        settings.db.as_ref(),
+        NewAuditEntry {
+            uid: Some(auth.id),
+            action: AuditAction::WebhookCreate,
+            crate_name: None,
+            vers: None,
+            detail: Some(webhook.url.clone()),
+            ip: audit::source_ip(&req),
+        },
+    )
+    .await;
+
+    let secret = webhook.secret.clone();
+    Ok(HttpResponse::Ok().json(ApiWebhook {
+        secret: Some(secret),
+        ..ApiWebhook::from(webhook)
+    }))
+}
+
+#[delete("/{id}")]
+pub async fn delete_webhook(
+    req: HttpRequest,
+    path: web::Path<i32>,
+    settings: web::Data<Settings>,
+    auth: Authenticated,
+) -> Result<HttpResponse, ApiError> {
+    if !auth.is_admin() {
+        return Err(ApiError::Forbidden);
+    }
+
+    let webhook = settings
+        .db
+        .get_webhook(*path)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    settings.db.delete_webhook(webhook.id).await?;
+
+    audit::record(
+        settings.db.as_ref(),
+        NewAuditEntry {
+            uid: Some(auth.id),
+            action: AuditAction::WebhookDelete,
+            crate_name: None,
+            vers: None,
+            detail: Some(webhook.url),
+            ip: audit::source_ip(&req),
+        },
+    )
+    .await;
+
+    Ok(HttpResponse::Ok().json(json!({ "ok": true })))
+}
+
+#[get("/{id}/deliveries")]
+pub async fn webhook_deliveries(
+    path: web::Path<i32>,
+    settings: web::Data<Settings>,
+    auth: Authenticated,
+) -> Result<HttpResponse, ApiError> {
+    if !auth.is_admin() {
+        return Err(ApiError::Forbidden);
+    }
+
+    let webhook = settings
+        .db
+        .get_webhook(*path)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    let deliveries: Vec<ApiWebhookDelivery> = settings
+        .db
+        .get_webhook_deliveries(webhook.id, MAX_PER_PAGE)
+        .await?
+        .into_iter()
+        .map(ApiWebhookDelivery::from)
+        .collect();
+
+    Ok(HttpResponse::Ok().json(json!({ "deliveries": deliveries })))
+}
+
+/// An entry in the audit log page's action filter.
+pub struct ActionOption {
+    name: &'static str,
+    selected: bool,
+}
+
+#[derive(Template)]
+#[template(path = "admin_audit.html")]
+pub struct AuditLogTemplate {
+    title: &'static str,
+    user: Option<User>,
+    entries: Vec<ApiAuditEntry>,
+    crate_name: String,
+    user_name: String,
+    actions: Vec<ActionOption>,
+    newer: Option<String>,
+    older: Option<String>,
+}
+
+#[get("/admin/audit")]
+pub async fn audit_log_page(
+    params: web::Query<AuditParams>,
+    settings: web::Data<Settings>,
+    auth: Authenticated,
+) -> Result<AuditLogTemplate, EstuaryError> {
+    if !auth.is_admin() {
+        return Err(EstuaryError::Forbidden);
+    }
+
+    let entries: Vec<ApiAuditEntry> = settings
+        .db
+        .get_audit_entries(params.to_query())
+        .await?
+        .into_iter()
+        .map(ApiAuditEntry::from)
+        .collect();
+
+    let page = params.page();
+    let newer = if page > 1 {
+        Some(params.page_query(page - 1))
+    } else {
+        None
+    };
+    let older = if entries.len() as i64 == params.per_page() {
+        Some(params.page_query(page + 1))
+    } else {
+        None
+    };
+
+    Ok(AuditLogTemplate {
+        title: "Audit Log",
+        user: Some((*auth).clone()),
+        entries,
+        crate_name: params.crate_name.clone().unwrap_or_default(),
+        user_name: params.user.clone().unwrap_or_default(),
+        actions: AuditAction::ALL
+            .iter()
+            .map(|a| ActionOption {
+                name: a.as_str(),
+                selected: params.action == Some(*a),
+            })
+            .collect(),
+        newer,
+        older,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::database::UserRole;
+    use crate::test_helpers;
+    use crate::webhooks;
+    use actix_web::dev::ServerHandle;
+    use actix_web::http::{header, StatusCode};
+    use actix_web::{test, web, App, HttpRequest, HttpResponse, HttpServer};
+    use serde_json::Value;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    /// A request received by [`start_receiver`]: its signature header and body.
+    type Received = Arc<Mutex<Vec<(String, Vec<u8>)>>>;
+
+    /// Run a webhook receiver which fails the first request it gets, then
+    /// accepts the rest.
+    fn start_receiver() -> (String, Received, ServerHandle) {
+        let received = Received::default();
+        let data = web::Data::new(received.clone());
+        let server = HttpServer::new(move || {
+            App::new().app_data(data.clone()).default_service(web::to(
+                |req: HttpRequest, body: web::Bytes, received: web::Data<Received>| async move {
+                    let signature = req
+                        .headers()
+                        .get(webhooks::SIGNATURE_HEADER)
+                        .and_then(|v| v.to_str().ok())
+                        .unwrap_or_default()
+                        .to_string();
+                    let mut received = received.lock().unwrap();
+                    received.push((signature, body.to_vec()));
+                    if received.len() == 1 {
+                        HttpResponse::InternalServerError().finish()
+                    } else {
+                        HttpResponse::Ok().finish()
+                    }
+                },
+            ))
+        })
+        .workers(1)
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let url = format!("http://{}/hook", server.addrs()[0]);
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+        (url, received, handle)
+    }
+
+    #[actix_rt::test]
+    async fn test_audit_log_records_changes() {
+        let data_root = test_helpers::get_data_root();
+        let settings = test_helpers::get_test_settings(data_root.path()).await;
+        let package_index = test_helpers::get_test_package_index(&settings.index_dir);
+        let api_key = test_helpers::get_test_api_key(&settings).await;
+        let app = test::init_service(
+            App::new()
+                .wrap(crate::auth::AuthFactory::new(settings.db.clone()))
+                .app_data(package_index.clone())
+                .app_data(settings.clone())
+                .configure(crate::handlers::configure_routes),
+        )
+        .await;
+
+        let metadata = test_helpers::make_publish_metadata("foo", "0.1.0", serde_json::json!([]));
+        let req = test::TestRequest::put()
+            .uri("/api/v1/crates/new")
+            .append_header((header::AUTHORIZATION, api_key.clone()))
+            .append_header(("X-Forwarded-For", "10.1.2.3"))
+            .peer_addr("10.4.5.6:4321".parse().unwrap())
+            .set_payload(test_helpers::make_publish_body(&metadata, b""))
+            .to_request();
+        assert_eq!(StatusCode::OK, test::call_service(&app, req).await.status());
+
+        let req = test::TestRequest::delete()
+            .uri("/api/v1/crates/foo/0.1.0/yank")
+            .append_header((header::AUTHORIZATION, api_key.clone()))
+            .to_request();
+        assert_eq!(StatusCode::OK, test::call_service(&app, req).await.status());
+
+        let req = test::TestRequest::get()
+            .uri("/api/v1/audit")
+            .append_header((header::AUTHORIZATION, api_key.clone()))
+            .to_request();
+        let resp: Value = test::call_and_read_body_json(&app, req).await;
+        let entries = resp["entries"].as_array().unwrap();
+        let actions: Vec<_> = entries
+            .iter()
+            .map(|e| e["action"].as_str().unwrap())
+            .collect();
+        assert_eq!(vec!["yank", "publish", "owner_add"], actions);
+        assert_eq!("admin", entries[1]["user"]);
+        assert_eq!("foo", entries[1]["crate"]);
+        assert_eq!("0.1.0", entries[1]["version"]);
+        // Not from a trusted proxy, so the header is ignored.
+        assert_eq!("10.4.5.6", entries[1]["ip"]);
+        assert!(entries[1]["created_at"].is_string());
+
+        let req = test::TestRequest::get()
+            .uri("/api/v1/audit?action=yank&crate=foo")
+            .append_header((header::AUTHORIZATION, api_key.clone()))
+            .to_request();
+        let resp: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(1, resp["entries"].as_array().unwrap().len());
+
+        let req = test::TestRequest::get()
+            .uri("/api/v1/audit?user=nobody")
+            .append_header((header::AUTHORIZATION, api_key.clone()))
+            .to_request();
+        let resp: Value = test::call_and_read_body_json(&app, req).await;
+        assert!(resp["entries"].as_array().unwrap().is_empty());
+
+        let req = test::TestRequest::get()
+            .uri("/api/v1/audit?per_page=1&page=2")
+            .append_header((header::AUTHORIZATION, api_key))
+            .to_request();
+        let resp: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!("publish", resp["entries"][0]["action"]);
+    }
+
+    #[actix_rt::test]
+    async fn test_audit_log_admin_only() {
+        let data_root = test_helpers::get_data_root();
+        let settings = test_helpers::get_test_settings(data_root.path()).await;
+        let package_index = test_helpers::get_test_package_index(&settings.index_dir);
+        let user = settings
+            .db
+            .create_user(
+                String::from("bob"),
+                String::from("bob"),
+                UserRole::Publisher,
+            )
+            .await
+            .unwrap();
+        let api_key = settings
+            .db
+            .generate_api_key(String::from("bob's key"), &user)
+            .await
+            .unwrap();
+        let app = test::init_service(
+            App::new()
+                .wrap(crate::auth::AuthFactory::new(settings.db.clone()))
+                .app_data(package_index.clone())
+                .app_data(settings.clone())
+                .configure(crate::handlers::configure_routes),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/api/v1/audit")
+            .append_header((header::AUTHORIZATION, api_key.clone()))
+            .to_request();
+        assert_eq!(
+            StatusCode::FORBIDDEN,
+            test::call_service(&app, req).await.status()
+        );
+
+        let req = test::TestRequest::get()
+            .uri("/api/v1/webhooks")
+            .append_header((header::AUTHORIZATION, api_key))
+            .to_request();
+        assert_eq!(
+            StatusCode::FORBIDDEN,
+            test::call_service(&app, req).await.status()
+        );
+
+        // Without credentials at all it's a 401 instead.
+        let req = test::TestRequest::get().uri("/api/v1/audit").to_request();
+        assert_eq!(
+            StatusCode::UNAUTHORIZED,
+            test::call_service(&app, req).await.status()
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_webhook_delivery() {
+        let data_root = test_helpers::get_data_root();
+        let settings = test_helpers::get_test_settings(data_root.path()).await;
+        let package_index = test_helpers::get_test_package_index(&settings.index_dir);
+        let api_key = test_helpers::get_test_api_key(&settings).await;
+        let (url, received, receiver) = start_receiver();
+        let app = test::init_service(
+            App::new()
+                .wrap(crate::auth::AuthFactory::new(settings.db.clone()))
+                .app_data(package_index.clone())
+                .app_data(settings.clone())
+                .configure(crate::handlers::configure_routes),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/api/v1/webhooks")
+            .append_header((header::AUTHORIZATION, api_key.clone()))
+            .set_json(serde_json::json!({ "url": url, "secret": "s3cret", "events": ["publish"] }))
+            .to_request();
+        let resp: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!("s3cret", resp["secret"]);
+        assert_eq!(serde_json::json!(["publish"]), resp["events"]);
+        let id = resp["id"].as_i64().unwrap();
+
+        let req = test::TestRequest::post()
+            .uri("/api/v1/webhooks")
+            .append_header((header::AUTHORIZATION, api_key.clone()))
+            .set_json(serde_json::json!({ "url": "ftp://example.com" }))
+            .to_request();
+        assert_eq!(
+            StatusCode::BAD_REQUEST,
+            test::call_service(&app, req).await.status()
+        );
+
+        let metadata = test_helpers::make_publish_metadata("foo", "0.1.0", serde_json::json!([]));
+        let req = test::TestRequest::put()
+            .uri("/api/v1/crates/new")
+            .append_header((header::AUTHORIZATION, api_key.clone()))
+            .set_payload(test_helpers::make_publish_body(&metadata, b""))
+            .to_request();
+        assert_eq!(StatusCode::OK, test::call_service(&app, req).await.status());
+
+        // The first attempt fails, so wait for the retry.
+        for _ in 0..50 {
+            if received.lock().unwrap().len() >= 2 {
+                break;
+            }
+            actix_web::rt::time::sleep(Duration::from_millis(100)).await;
+        }
+        let (signature, body) = received.lock().unwrap()[1].clone();
+        assert_eq!(webhooks::sign("s3cret", &body), signature);
+        let payload: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!("publish", payload["event"]);
+        assert_eq!("foo", payload["crate"]);
+        assert_eq!("0.1.0", payload["version"]);
+        assert_eq!("admin", payload["user"]);
+
+        // The delivery is logged just after the receiver responds.
+        let mut deliveries = Value::Null;
+        for _ in 0..50 {
+            let req = test::TestRequest::get()
+                .uri(&format!("/api/v1/webhooks/{}/deliveries", id))
+                .append_header((header::AUTHORIZATION, api_key.clone()))
+                .to_request();
+            let resp: Value = test::call_and_read_body_json(&app, req).await;
+            deliveries = resp["deliveries"].clone();
+            if deliveries.as_array().unwrap().len() >= 2 {
+                break;
+            }
+            actix_web::rt::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(2, deliveries[0]["attempt"]);
+        assert_eq!(200, deliveries[0]["status"]);
+        assert!(deliveries[0]["error"].is_null());
+        assert_eq!(1, deliveries[1]["attempt"]);
+        assert_eq!(500, deliveries[1]["status"]);
+        assert_eq!("foo", deliveries[1]["payload"]["crate"]);
+
+        let req = test::TestRequest::get()
+            .uri("/api/v1/webhooks")
+            .append_header((header::AUTHORIZATION, api_key.clone()))
+            .to_request();
+        let resp: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(1, resp["webhooks"].as_array().unwrap().len());
+        assert!(resp["webhooks"][0].get("secret").is_none());
+
+        let req = test::TestRequest::delete()
+            .uri(&format!("/api/v1/webhooks/{}", id))
+            .append_header((header::AUTHORIZATION, api_key.clone()))
+            .to_request();
+        assert_eq!(StatusCode::OK, test::call_service(&app, req).await.status());
+
+        let req = test::TestRequest::get()
+            .uri("/api/v1/webhooks")
+            .append_header((header::AUTHORIZATION, api_key))
+            .to_request();
+        let resp: Value = test::call_and_read_body_json(&app, req).await;
+        assert!(resp["webhooks"].as_array().unwrap().is_empty());
+
+        receiver.stop(false).await;
+    }
+}
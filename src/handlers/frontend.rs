@@ -1,14 +1,18 @@
 use crate::Settings;
-use crate::auth::Authenticated;
-use crate::database::{ApiKey, User};
+use crate::audit;
+use crate::crate_source::{self, Source, SourceEntry};
+use crate::docs;
+use crate::auth::{Authenticated, OptionalAuthenticated};
+use crate::database::{ApiKey, AuditAction, DatabaseError, NewAuditEntry, User};
 use crate::errors::{EstuaryError, PackageIndexError};
-use crate::package_index::{Dependency, DependencyKind, PackageIndex, PackageVersion};
+use crate::handlers::registry::format_timestamp;
+use crate::package_index::{Dependency, DependencyKind, Dependent, IndexHandle, PackageVersion, Publish};
 use actix_session::Session;
-use actix_web::{get, web, HttpRequest, HttpResponse, post, delete};
+use actix_web::{get, web, HttpRequest, HttpResponse, ResponseError, post, delete};
+use actix_web::http::{header, StatusCode};
 use askama::Template;
 use serde::Deserialize;
 use serde_json::json;
-use std::sync::Mutex;
 
 type Result<T> = std::result::Result<T, EstuaryError>;
 
@@ -23,12 +27,47 @@ pub async fn styles(_req: HttpRequest) -> HttpResponse {
         )))
 }
 
+/// How many crates to list under each of the landing page's recent activity
+/// headings.
+const RECENT_CRATES: usize = 10;
+
+/// How many releases to include in the feed.
+const FEED_ENTRIES: usize = 50;
+
+/// A publish, ready for display.
+pub struct Release {
+    name: String,
+    vers: String,
+    /// When it was published, in RFC 3339 format.
+    published: String,
+}
+
+impl From<Publish> for Release {
+    fn from(publish: Publish) -> Self {
+        Release {
+            name: publish.name,
+            vers: publish.vers.to_string(),
+            published: format_timestamp(publish.timestamp).unwrap_or_default(),
+        }
+    }
+}
+
 #[derive(Template)]
 #[template(path = "landing.html")]
 pub struct LandingTemplate<'a> {
     title: &'a str,
     user: Option<User>,
     packages: Vec<String>,
+    new_crates: Vec<Release>,
+    updated_crates: Vec<Release>,
+}
+
+#[derive(Template)]
+#[template(path = "feed.xml")]
+pub struct FeedTemplate {
+    base_url: String,
+    updated: String,
+    releases: Vec<Release>,
 }
 
 #[derive(Template)]
@@ -36,6 +75,7 @@ pub struct LandingTemplate<'a> {
 pub struct LoginTemplate<'a> {
     title: &'a str,
     user: Option<User>,
+    error: Option<&'a str>,
 }
 
 #[derive(Template)]
@@ -56,23 +96,59 @@ pub struct CrateDetailTemplate {
     dev_deps: Vec<Dependency>,
     non_dev_deps: Vec<Dependency>,
     releases: Vec<PackageVersion>,
+    dependents: Vec<Dependent>,
+    has_docs: bool,
 }
 
 #[get("/")]
-pub async fn landing(index: web::Data<Mutex<PackageIndex>>) -> Result<LandingTemplate<'static>> {
-    let index = index.lock().unwrap();
-    let mut names = index.list_crates()?;
+pub async fn landing(index: web::Data<IndexHandle>) -> Result<LandingTemplate<'static>> {
+    let mut names = index.list_crates();
     names.sort();
 
     Ok(LandingTemplate {
         title: "Crate List",
         user : None,
         packages: names,
+        new_crates: index.get_new_crates(RECENT_CRATES).into_iter().map(Release::from).collect(),
+        updated_crates: index.get_updated_crates(RECENT_CRATES).into_iter().map(Release::from).collect(),
     })
 }
 
+/// An Atom feed of the latest releases.
+#[get("/releases.atom")]
+pub async fn releases_feed(index: web::Data<IndexHandle>, settings : web::Data<Settings>) -> Result<HttpResponse> {
+    let releases: Vec<Release> = index.get_publishes(FEED_ENTRIES).into_iter().map(Release::from).collect();
+    let feed = FeedTemplate {
+        base_url: settings.base_url.clone(),
+        // The feed changes whenever something is published, and not otherwise.
+        updated: releases
+            .first()
+            .map(|r| r.published.clone())
+            .or_else(|| format_timestamp(0))
+            .unwrap_or_default(),
+        releases,
+    };
+    Ok(HttpResponse::Ok()
+        .content_type("application/atom+xml")
+        .body(feed.render()?))
+}
+
+/// Where `cargo login` sends people for a token. Logged in users go straight
+/// to their api keys, everyone else gets the login page.
 #[get("/me")]
-pub async fn me_redirect(_auth : Authenticated) -> Result<HttpResponse> {
+pub async fn me_redirect(auth : OptionalAuthenticated) -> Result<HttpResponse> {
+    if auth.is_none() {
+        return Ok(
+            HttpResponse::Ok()
+                .content_type(header::ContentType::html())
+                .body(LoginTemplate {
+                    title: "Login",
+                    user : None,
+                    error: None,
+                }.render()?)
+        );
+    }
+
     Ok(
         HttpResponse::TemporaryRedirect()
             .append_header(("Location", "/user"))
@@ -81,17 +157,18 @@ pub async fn me_redirect(_auth : Authenticated) -> Result<HttpResponse> {
 }
 
 #[get("/user")]
-pub async fn get_user(auth : Authenticated, settings : web::Data<Settings>) -> actix_web::Result<HttpResponse> {
+pub async fn get_user(auth : Authenticated, settings : web::Data<Settings>) -> Result<HttpResponse> {
     let keys = settings.db.get_api_keys(auth.id).await?;
 
     Ok(
         HttpResponse::Ok()
+            .content_type(header::ContentType::html())
             .body(UserTemplate {
                 title : &auth.name,
                 user : None,
                 name: &auth.name,
                 api_keys: keys,
-            }.to_string())
+            }.render()?)
     )
 }
 
@@ -101,10 +178,19 @@ pub struct NewKey {
 }
 
 #[post("/user/api-key")]
-pub async fn gen_api_key(auth : Authenticated, new_key : web::Json<NewKey>, settings : web::Data<Settings>) -> actix_web::Result<HttpResponse> {
+pub async fn gen_api_key(req : HttpRequest, auth : Authenticated, new_key : web::Json<NewKey>, settings : web::Data<Settings>) -> std::result::Result<HttpResponse, DatabaseError> {
 
     let res = settings.db.generate_api_key(new_key.name.clone(), &auth).await?;
 
+    audit::record(settings.db.as_ref(), NewAuditEntry {
+        uid: Some(auth.id),
+        action: AuditAction::ApiKeyCreate,
+        crate_name: None,
+        vers: None,
+        detail: Some(new_key.name.clone()),
+        ip: audit::source_ip(&req),
+    }).await;
+
     Ok(
         HttpResponse::Ok()
         .json(json!({
@@ -119,9 +205,18 @@ pub struct OldKey {
 }
 
 #[delete("/user/api-key")]
-pub async fn revoke_api_key(auth : Authenticated, new_key : web::Json<OldKey>, settings : web::Data<Settings>) -> actix_web::Result<HttpResponse> {
+pub async fn revoke_api_key(req : HttpRequest, auth : Authenticated, new_key : web::Json<OldKey>, settings : web::Data<Settings>) -> std::result::Result<HttpResponse, DatabaseError> {
     settings.db.revoke_api_key(new_key.id, auth.id).await?;
 
+    audit::record(settings.db.as_ref(), NewAuditEntry {
+        uid: Some(auth.id),
+        action: AuditAction::ApiKeyRevoke,
+        crate_name: None,
+        vers: None,
+        detail: Some(format!("key id {}", new_key.id)),
+        ip: audit::source_ip(&req),
+    }).await;
+
     Ok(
         HttpResponse::Ok()
             .finish()
@@ -132,7 +227,8 @@ pub async fn revoke_api_key(auth : Authenticated, new_key : web::Json<OldKey>, s
 pub async fn login(_req: HttpRequest) -> Result<LoginTemplate<'static>> {
     Ok(LoginTemplate {
         title: "Login",
-        user : None
+        user : None,
+        error: None,
     })
 }
 
@@ -143,25 +239,37 @@ pub struct LoginData {
 }
 
 #[post("/login")]
-pub async fn login_req(data: web::Form<LoginData>, settings: web::Data<Settings>, session : Session) -> actix_web::Result<HttpResponse> {
-
-    if let Some(user) = settings.db.get_user(data.username.clone()).await? {
-        settings.db.verify_password(&user, data.password.clone()).await?;
-
-        session.insert("uid", user.id)?;
-
-        return Ok(
-            HttpResponse::SeeOther()
-                .append_header(("Location", "/user"))
-                .finish()
-        )
+pub async fn login_req(data: web::Form<LoginData>, settings: web::Data<Settings>, session : Session) -> Result<HttpResponse> {
+
+    // Unknown users still get a password check, against a dummy, so they
+    // take as long to turn away as a wrong password does.
+    let user = settings.db.get_user(data.username.clone()).await?;
+    let exists = user.is_some();
+    let user = user.unwrap_or_else(User::dummy);
+    match settings.db.verify_password(&user, data.password.clone()).await {
+        Ok(()) if exists => {
+            session.insert("uid", user.id)?;
+
+            return Ok(
+                HttpResponse::SeeOther()
+                    .append_header(("Location", "/user"))
+                    .finish()
+            )
+        }
+        Ok(()) => {}
+        Err(e) if e.status_code() == StatusCode::UNAUTHORIZED => {}
+        Err(e) => return Err(e.into()),
     }
 
-    Ok(HttpResponse::Ok()
+    // Unknown users and wrong passwords look the same, so names can't be
+    // fished for.
+    Ok(HttpResponse::Unauthorized()
+        .content_type(header::ContentType::html())
         .body(LoginTemplate {
             title: "Login",
-            user : None
-        }.to_string()))
+            user : None,
+            error: Some("Invalid username or password"),
+        }.render()?))
 }
 
 #[derive(Template)]
@@ -179,17 +287,12 @@ pub struct CrateVersionListPath {
 
 pub async fn version_list(
     path: web::Path<CrateVersionListPath>,
-    index: web::Data<Mutex<PackageIndex>>,
+    index: web::Data<IndexHandle>,
 ) -> Result<CrateVersionListTemplate> {
-    let index = index.lock().unwrap();
     let releases = index
         .get_package_versions(&path.crate_name)
         .map_err(|e| match e {
-            PackageIndexError::IO(e @ std::io::Error { .. })
-                if e.kind() == std::io::ErrorKind::NotFound =>
-            {
-                EstuaryError::NotFound
-            }
+            PackageIndexError::CrateNotFound(_) => EstuaryError::NotFound,
             _ => e.into(),
         })?;
 
@@ -200,6 +303,142 @@ pub async fn version_list(
     })
 }
 
+#[derive(Deserialize, Debug)]
+pub struct CrateSourcePath {
+    crate_name: String,
+    version: semver::Version,
+    /// Relative to the root of the crate.
+    #[serde(default)]
+    path: String,
+}
+
+/// A file from a crate, ready for display.
+pub struct SourceFile {
+    /// `None` for binary files and files too big to show.
+    text: Option<String>,
+    size: u64,
+    /// For syntax highlighting, in the style of highlight.js/prism class names.
+    language: &'static str,
+}
+
+#[derive(Template)]
+#[template(path = "crate_source.html")]
+pub struct CrateSourceTemplate {
+    title: String,
+    user: Option<User>,
+    crate_name: String,
+    vers: String,
+    /// Each directory leading to the path, with the path to link to.
+    breadcrumbs: Vec<(String, String)>,
+    entries: Option<Vec<SourceEntry>>,
+    file: Option<SourceFile>,
+}
+
+/// Guess the language of a file from its name, for syntax highlighting.
+fn source_language(path: &str) -> &'static str {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    match name.rsplit('.').next() {
+        _ if name == "Cargo.lock" => "toml",
+        Some("rs") => "rust",
+        Some("toml") | Some("orig") => "toml",
+        Some("md") => "markdown",
+        Some("json") => "json",
+        Some("yml") | Some("yaml") => "yaml",
+        Some("sh") => "bash",
+        Some("c") | Some("h") => "c",
+        Some("html") => "html",
+        Some("css") => "css",
+        Some("js") => "javascript",
+        _ => "plaintext",
+    }
+}
+
+/// Browse the files inside a published crate.
+pub async fn crate_source(
+    path: web::Path<CrateSourcePath>,
+    settings: web::Data<Settings>,
+) -> Result<CrateSourceTemplate> {
+    let path = path.into_inner();
+    let crate_file = crate::storage::get_crate_file_path(&settings.crate_dir, &path.crate_name, &path.version);
+    if !crate_file.exists() {
+        return Err(EstuaryError::NotFound);
+    }
+
+    let source = {
+        let (name, vers, path) = (path.crate_name.clone(), path.version.clone(), path.path.clone());
+        web::block(move || crate_source::browse(crate_file, &name, &vers, &path)).await??
+    }
+    .ok_or(EstuaryError::NotFound)?;
+
+    let source_path = path.path.trim_matches('/');
+    let mut breadcrumbs = vec![];
+    let mut href = String::new();
+    for part in source_path.split('/').filter(|p| !p.is_empty()) {
+        href = if href.is_empty() { part.to_string() } else { format!("{}/{}", href, part) };
+        breadcrumbs.push((part.to_string(), href.clone()));
+    }
+
+    let (entries, file) = match source {
+        Source::Dir(entries) => (Some(entries), None),
+        Source::File { size, contents } => (None, Some(SourceFile {
+            text: contents.and_then(|c| String::from_utf8(c).ok()),
+            size,
+            language: source_language(source_path),
+        })),
+    };
+
+    Ok(CrateSourceTemplate {
+        title: format!("{} v{} :: Source", path.crate_name, path.version),
+        user: None,
+        crate_name: path.crate_name,
+        vers: path.version.to_string(),
+        breadcrumbs,
+        entries,
+        file,
+    })
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DocsPath {
+    crate_name: String,
+    version: semver::Version,
+    /// Relative to the root of the docs.
+    #[serde(default)]
+    path: String,
+}
+
+/// Serve uploaded rustdoc output.
+///
+/// The root of a version's docs sends the browser on to the crate's own page.
+///
+/// Docs are whatever the uploader put in them and share an origin with the
+/// rest of the registry, so they're sandboxed to keep any scripts in them
+/// away from visitors' sessions.
+pub async fn docs(
+    req: HttpRequest,
+    path: web::Path<DocsPath>,
+    settings: web::Data<Settings>,
+) -> Result<HttpResponse> {
+    let docs_dir = crate::storage::get_docs_dir(&settings.crate_dir, &path.crate_name, &path.version);
+    if !docs_dir.is_dir() {
+        return Err(EstuaryError::NotFound);
+    }
+
+    if path.path.is_empty() {
+        let entry_point = docs::entry_point(&docs_dir, &path.crate_name).ok_or(EstuaryError::NotFound)?;
+        return Ok(HttpResponse::Found()
+            .append_header((header::LOCATION, format!("/docs/{}/{}/{}", path.crate_name, path.version, entry_point)))
+            .finish());
+    }
+
+    let file = docs::resolve(&docs_dir, &path.path).ok_or(EstuaryError::NotFound)?;
+    let mut resp = actix_files::NamedFile::open(file)?.into_response(&req);
+    let headers = resp.headers_mut();
+    headers.insert(header::CONTENT_SECURITY_POLICY, header::HeaderValue::from_static("sandbox"));
+    headers.insert(header::X_CONTENT_TYPE_OPTIONS, header::HeaderValue::from_static("nosniff"));
+    Ok(resp)
+}
+
 #[derive(Deserialize, Debug)]
 pub struct CrateDetailPath {
     crate_name: String,
@@ -209,23 +448,18 @@ pub struct CrateDetailPath {
 
 pub async fn crate_detail(
     path: web::Path<CrateDetailPath>,
-    index: web::Data<Mutex<PackageIndex>>,
+    index: web::Data<IndexHandle>,
+    settings: web::Data<Settings>,
 ) -> Result<CrateDetailTemplate> {
     // 404 if:
     // - the crate isn't in the index
     // - the crate version doesn't exist
     // - the requested version isn't a valid version string
 
-    let index = index.lock().unwrap();
-
     let all_releases = index
         .get_package_versions(&path.crate_name)
         .map_err(|e| match e {
-            PackageIndexError::IO(e @ std::io::Error { .. })
-                if e.kind() == std::io::ErrorKind::NotFound =>
-            {
-                EstuaryError::NotFound
-            }
+            PackageIndexError::CrateNotFound(_) => EstuaryError::NotFound,
             _ => e.into(),
         })?;
 
@@ -244,8 +478,10 @@ pub async fn crate_detail(
                 .partition(|dep| dep.kind == DependencyKind::Dev);
 
             Ok(CrateDetailTemplate {
+                has_docs: crate::storage::get_docs_dir(&settings.crate_dir, &pkg.name, &pkg.vers).is_dir(),
                 user : None,
                 title: format!("{} v{}", pkg.name, pkg.vers),
+                dependents: index.get_latest_dependents(&pkg.name),
                 pkg,
                 dev_deps,
                 non_dev_deps,
@@ -261,34 +497,121 @@ pub async fn crate_detail(
 mod tests {
     use crate::test_helpers;
     use crate::test_helpers::MY_CRATE_0_1_0;
-    use actix_web::http::StatusCode;
+    use actix_web::http::{header, StatusCode};
     use actix_web::{test, App};
 
     #[actix_rt::test]
     async fn test_landing_ok_empty() {
         let data_root = test_helpers::get_data_root();
-        let settings = test_helpers::get_test_settings(&data_root.path());
+        let settings = test_helpers::get_test_settings(data_root.path()).await;
         let package_index = test_helpers::get_test_package_index(&settings.index_dir);
-        let mut app = test::init_service(
+        let app = test::init_service(
             App::new()
+                .wrap(crate::auth::AuthFactory::new(settings.db.clone()))
                 .app_data(package_index.clone())
                 .app_data(settings.clone())
                 .configure(crate::handlers::configure_routes),
         )
         .await;
         let req = test::TestRequest::get().uri("/").to_request();
-        let resp = test::call_service(&mut app, req).await;
+        let resp = test::call_service(&app, req).await;
         assert_eq!(StatusCode::OK, resp.status());
     }
 
+    #[actix_rt::test]
+    async fn test_landing_and_feed_list_releases() {
+        let data_root = test_helpers::get_data_root();
+        let settings = test_helpers::get_test_settings(data_root.path()).await;
+        let package_index = test_helpers::get_test_package_index(&settings.index_dir);
+        let api_key = test_helpers::get_test_api_key(&settings).await;
+        let app = test::init_service(
+            App::new()
+                .wrap(crate::auth::AuthFactory::new(settings.db.clone()))
+                .app_data(package_index.clone())
+                .app_data(settings.clone())
+                .configure(crate::handlers::configure_routes),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/releases.atom").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::OK, resp.status());
+        assert_eq!("application/atom+xml", resp.headers().get(header::CONTENT_TYPE).unwrap());
+        let body = test::read_body(resp).await;
+        assert!(!String::from_utf8_lossy(&body).contains("<entry>"));
+
+        let req = test::TestRequest::put()
+            .uri("/api/v1/crates/new")
+            .append_header((header::AUTHORIZATION, api_key.as_str()))
+            .set_payload(MY_CRATE_0_1_0)
+            .to_request();
+        let _: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+
+        let req = test::TestRequest::get().uri("/").to_request();
+        let body = String::from_utf8(test::call_and_read_body(&app, req).await.to_vec()).unwrap();
+        assert!(body.contains("New Crates"));
+        assert!(body.contains(r#"href="/crates/my-crate/0.1.0""#));
+
+        let req = test::TestRequest::get().uri("/releases.atom").to_request();
+        let body = String::from_utf8(test::call_and_read_body(&app, req).await.to_vec()).unwrap();
+        assert_eq!(1, body.matches("<entry>").count());
+        assert!(body.contains("<title>my-crate v0.1.0</title>"));
+        assert!(body.contains("<id>http://localhost/crates/my-crate/0.1.0</id>"));
+    }
+
+    #[actix_rt::test]
+    async fn test_crate_source() {
+        let data_root = test_helpers::get_data_root();
+        let settings = test_helpers::get_test_settings(data_root.path()).await;
+        let package_index = test_helpers::get_test_package_index(&settings.index_dir);
+        let api_key = test_helpers::get_test_api_key(&settings).await;
+        let app = test::init_service(
+            App::new()
+                .wrap(crate::auth::AuthFactory::new(settings.db.clone()))
+                .app_data(package_index.clone())
+                .app_data(settings.clone())
+                .configure(crate::handlers::configure_routes),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/crates/my-crate/0.1.0/source").to_request();
+        assert_eq!(StatusCode::NOT_FOUND, test::call_service(&app, req).await.status());
+
+        let req = test::TestRequest::put()
+            .uri("/api/v1/crates/new")
+            .append_header((header::AUTHORIZATION, api_key.as_str()))
+            .set_payload(MY_CRATE_0_1_0)
+            .to_request();
+        let _: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+
+        for uri in &["/crates/my-crate/0.1.0/source", "/crates/my-crate/0.1.0/source/"] {
+            let req = test::TestRequest::get().uri(uri).to_request();
+            let body = String::from_utf8(test::call_and_read_body(&app, req).await.to_vec()).unwrap();
+            assert!(body.contains(r#"href="/crates/my-crate/0.1.0/source/src/""#));
+            assert!(body.contains(r#"href="/crates/my-crate/0.1.0/source/Cargo.toml""#));
+        }
+
+        let req = test::TestRequest::get().uri("/crates/my-crate/0.1.0/source/src/").to_request();
+        let body = String::from_utf8(test::call_and_read_body(&app, req).await.to_vec()).unwrap();
+        assert!(body.contains(r#"href="/crates/my-crate/0.1.0/source/src/lib.rs""#));
+
+        let req = test::TestRequest::get().uri("/crates/my-crate/0.1.0/source/src/lib.rs").to_request();
+        let body = String::from_utf8(test::call_and_read_body(&app, req).await.to_vec()).unwrap();
+        assert!(body.contains(r#"<code class="language-rust">"#));
+
+        let req = test::TestRequest::get().uri("/crates/my-crate/0.1.0/source/src/main.rs").to_request();
+        assert_eq!(StatusCode::NOT_FOUND, test::call_service(&app, req).await.status());
+    }
+
     #[actix_rt::test]
     async fn test_login() {
         let data_root = test_helpers::get_data_root();
-        let settings = test_helpers::get_test_settings(&data_root.path());
+        let settings = test_helpers::get_test_settings(data_root.path()).await;
         let package_index = test_helpers::get_test_package_index(&settings.index_dir);
 
-        let mut app = test::init_service(
+        let app = test::init_service(
             App::new()
+                .wrap(crate::auth::AuthFactory::new(settings.db.clone()))
                 .app_data(package_index.clone())
                 .app_data(settings.clone())
                 .configure(crate::handlers::configure_routes),
@@ -296,18 +619,20 @@ mod tests {
         .await;
 
         let req = test::TestRequest::get().uri("/me").to_request();
-        let resp = test::call_service(&mut app, req).await;
+        let resp = test::call_service(&app, req).await;
         assert_eq!(StatusCode::OK, resp.status());
     }
 
     #[actix_rt::test]
     async fn test_detail_existing_crate_no_version_is_ok() {
         let data_root = test_helpers::get_data_root();
-        let settings = test_helpers::get_test_settings(&data_root.path());
+        let settings = test_helpers::get_test_settings(data_root.path()).await;
+        let api_key = test_helpers::get_test_api_key(&settings).await;
         let package_index = test_helpers::get_test_package_index(&settings.index_dir);
 
-        let mut app = test::init_service(
+        let app = test::init_service(
             App::new()
+                .wrap(crate::auth::AuthFactory::new(settings.db.clone()))
                 .app_data(settings.clone())
                 .app_data(package_index.clone())
                 .configure(crate::handlers::configure_routes),
@@ -316,27 +641,29 @@ mod tests {
 
         let req = test::TestRequest::put()
             .uri("/api/v1/crates/new")
+            .append_header((header::AUTHORIZATION, api_key.as_str()))
             .set_payload(MY_CRATE_0_1_0)
             .to_request();
 
-        let _: serde_json::Value = test::call_and_read_body_json(&mut app, req).await;
+        let _: serde_json::Value = test::call_and_read_body_json(&app, req).await;
 
         let req = test::TestRequest::get()
             .uri("/crates/my-crate")
             .to_request();
 
-        let resp = test::call_service(&mut app, req).await;
+        let resp = test::call_service(&app, req).await;
         assert_eq!(StatusCode::OK, resp.status());
     }
 
     #[actix_rt::test]
     async fn test_detail_nonexistent_crate_is_not_found() {
         let data_root = test_helpers::get_data_root();
-        let settings = test_helpers::get_test_settings(&data_root.path());
+        let settings = test_helpers::get_test_settings(data_root.path()).await;
         let package_index = test_helpers::get_test_package_index(&settings.index_dir);
 
-        let mut app = test::init_service(
+        let app = test::init_service(
             App::new()
+                .wrap(crate::auth::AuthFactory::new(settings.db.clone()))
                 .app_data(settings.clone())
                 .app_data(package_index.clone())
                 .configure(crate::handlers::configure_routes),
@@ -347,18 +674,19 @@ mod tests {
             .uri("/crates/non-existent/0.1.0")
             .to_request();
 
-        let resp = test::call_service(&mut app, req).await;
+        let resp = test::call_service(&app, req).await;
         assert_eq!(StatusCode::NOT_FOUND, resp.status());
     }
 
     #[actix_rt::test]
     async fn test_detail_nonexistent_crate_no_version_is_not_found() {
         let data_root = test_helpers::get_data_root();
-        let settings = test_helpers::get_test_settings(&data_root.path());
+        let settings = test_helpers::get_test_settings(data_root.path()).await;
         let package_index = test_helpers::get_test_package_index(&settings.index_dir);
 
-        let mut app = test::init_service(
+        let app = test::init_service(
             App::new()
+                .wrap(crate::auth::AuthFactory::new(settings.db.clone()))
                 .app_data(settings.clone())
                 .app_data(package_index.clone())
                 .configure(crate::handlers::configure_routes),
@@ -369,18 +697,20 @@ mod tests {
             .uri("/crates/non-existent")
             .to_request();
 
-        let resp = test::call_service(&mut app, req).await;
+        let resp = test::call_service(&app, req).await;
         assert_eq!(StatusCode::NOT_FOUND, resp.status());
     }
 
     #[actix_rt::test]
     async fn test_version_list_existing_crate_is_ok() {
         let data_root = test_helpers::get_data_root();
-        let settings = test_helpers::get_test_settings(&data_root.path());
+        let settings = test_helpers::get_test_settings(data_root.path()).await;
+        let api_key = test_helpers::get_test_api_key(&settings).await;
         let package_index = test_helpers::get_test_package_index(&settings.index_dir);
 
-        let mut app = test::init_service(
+        let app = test::init_service(
             App::new()
+                .wrap(crate::auth::AuthFactory::new(settings.db.clone()))
                 .app_data(settings.clone())
                 .app_data(package_index.clone())
                 .configure(crate::handlers::configure_routes),
@@ -389,27 +719,29 @@ mod tests {
 
         let req = test::TestRequest::put()
             .uri("/api/v1/crates/new")
+            .append_header((header::AUTHORIZATION, api_key.as_str()))
             .set_payload(MY_CRATE_0_1_0)
             .to_request();
 
-        let _: serde_json::Value = test::call_and_read_body_json(&mut app, req).await;
+        let _: serde_json::Value = test::call_and_read_body_json(&app, req).await;
 
         let req = test::TestRequest::get()
             .uri("/crates/my-crate/versions")
             .to_request();
 
-        let resp = test::call_service(&mut app, req).await;
+        let resp = test::call_service(&app, req).await;
         assert_eq!(StatusCode::OK, resp.status());
     }
 
     #[actix_rt::test]
     async fn test_version_list_nonexistent_crate_is_not_found() {
         let data_root = test_helpers::get_data_root();
-        let settings = test_helpers::get_test_settings(&data_root.path());
+        let settings = test_helpers::get_test_settings(data_root.path()).await;
         let package_index = test_helpers::get_test_package_index(&settings.index_dir);
 
-        let mut app = test::init_service(
+        let app = test::init_service(
             App::new()
+                .wrap(crate::auth::AuthFactory::new(settings.db.clone()))
                 .app_data(settings.clone())
                 .app_data(package_index.clone())
                 .configure(crate::handlers::configure_routes),
@@ -420,7 +752,66 @@ mod tests {
             .uri("/crates/non-existent/versions")
             .to_request();
 
-        let resp = test::call_service(&mut app, req).await;
+        let resp = test::call_service(&app, req).await;
         assert_eq!(StatusCode::NOT_FOUND, resp.status());
     }
+
+    #[actix_rt::test]
+    async fn test_error_responses() {
+        let data_root = test_helpers::get_data_root();
+        let settings = test_helpers::get_test_settings(data_root.path()).await;
+        let package_index = test_helpers::get_test_package_index(&settings.index_dir);
+        settings
+            .db
+            .create_user(String::from("bob"), String::from("hunter2"), crate::database::UserRole::Publisher)
+            .await
+            .unwrap();
+        let app = test::init_service(
+            App::new()
+                .wrap(crate::auth::AuthFactory::new(settings.db.clone()))
+                .app_data(package_index.clone())
+                .app_data(settings.clone())
+                .configure(crate::handlers::configure_routes),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/crates/nope").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::NOT_FOUND, resp.status());
+        assert_eq!("text/html; charset=utf-8", resp.headers().get(header::CONTENT_TYPE).unwrap());
+        let body = test::read_body(resp).await;
+        assert!(std::str::from_utf8(&body).unwrap().contains("Not Found"));
+
+        // A wrong password and an unknown user get the same answer.
+        for (username, password) in &[("bob", "wrong"), ("alice", "hunter2")] {
+            let req = test::TestRequest::post()
+                .uri("/login")
+                .set_form([("username", username), ("password", password)])
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(StatusCode::UNAUTHORIZED, resp.status());
+            let body = test::read_body(resp).await;
+            assert!(std::str::from_utf8(&body).unwrap().contains("Invalid username or password"));
+        }
+        // Unknown users are checked against a real hash, so they take as long.
+        let dummy = crate::database::User::dummy();
+        assert!(dummy.password_hash.starts_with("$argon2"));
+        assert!(matches!(
+            settings.db.verify_password(&dummy, String::new()).await,
+            Err(crate::database::DatabaseError::PWHashError(argon2::password_hash::Error::Password))
+        ));
+
+        let req = test::TestRequest::post()
+            .uri("/login")
+            .set_form([("username", "bob"), ("password", "hunter2")])
+            .to_request();
+        assert_eq!(StatusCode::SEE_OTHER, test::call_service(&app, req).await.status());
+
+        // The cargo API wants credentials, not a login page.
+        let req = test::TestRequest::put()
+            .uri("/api/v1/crates/new")
+            .set_payload(MY_CRATE_0_1_0)
+            .to_request();
+        assert_eq!(StatusCode::UNAUTHORIZED, test::call_service(&app, req).await.status());
+    }
 }
@@ -5,27 +5,20 @@
 //!
 //! The endpoints here aim to support whatever is necessary for "git fetch" to
 //! work so cargo can do what it needs.
+//!
+//! Clients asking for protocol v2 with the `Git-Protocol` header get it,
+//! everyone else gets v0.
 
-use crate::errors::EstuaryError;
+use crate::errors::{EstuaryError, GitProtocolError};
+use crate::git_server::{self, pkt_line, ProtocolVersion};
+use crate::metrics;
 use crate::Settings;
-use actix_web::{get, post, web, HttpResponse};
+use actix_web::{get, post, web, HttpRequest, HttpResponse};
+use git2::Repository;
 use serde::Deserialize;
-use std::io::Write;
-use std::process::{Command, Stdio};
 
 type Result<T> = std::result::Result<T, EstuaryError>;
 
-/// Prefixes the string with 4 bytes representing the hex length of the string.
-///
-/// The lines git's response bodies use a packet protocol where the first 4
-/// bytes are the hex value of the length of the line (including the hex prefix).
-///
-/// Lines that include newline characters should have a literal `\n` in the
-/// string so it can be included in the length computed here.
-fn pkt_line(s: &str) -> String {
-    format!("{:04x}{}", s.len() + 4, s)
-}
-
 /// Git "services" offered by our transport.
 #[derive(Deserialize)]
 pub enum Service {
@@ -46,38 +39,40 @@ pub struct Query {
     service: Service,
 }
 
+/// The protocol version the client asked for.
+fn protocol_version(req: &HttpRequest) -> ProtocolVersion {
+    ProtocolVersion::from_header(
+        req.headers()
+            .get("Git-Protocol")
+            .and_then(|value| value.to_str().ok()),
+    )
+}
+
 #[get("/info/refs")]
 pub async fn get_info_refs(
+    req: HttpRequest,
     settings: web::Data<Settings>,
     query: web::Query<Query>,
 ) -> Result<HttpResponse> {
     let service_name = query.service.as_service_name().to_string();
-    let svc = service_name.clone();
-    let output = web::block(move || {
-        let service_name = svc;
-        Command::new(&settings.git_binary)
-            .args(&[
-                &service_name,
-                "--stateless-rpc",
-                "--advertise-refs",
-                &settings.index_dir.display().to_string(),
-            ])
-            .output()
-    })
-    .await??;
-
-    log::trace!("git says: {:?}", &output);
 
-    let mut body = vec![];
+    let body = match protocol_version(&req) {
+        // v2 responses skip the service header, same as `git http-backend`.
+        ProtocolVersion::V2 => git_server::advertise_capabilities_v2(),
+        ProtocolVersion::V0 => {
+            let refs = web::block(move || -> Result<Vec<u8>> {
+                let repo =
+                    Repository::open(&settings.index_dir).map_err(GitProtocolError::from)?;
+                Ok(git_server::advertise_refs(&repo)?)
+            })
+            .await??;
 
-    write!(
-        body,
-        "{}",
-        pkt_line(&format!("# service=git-{}\n", &service_name))
-    )?;
-
-    write!(body, "0000")?;
-    body.extend(output.stdout);
+            let mut body = pkt_line(&format!("# service=git-{}\n", &service_name)).into_bytes();
+            body.extend(git_server::FLUSH_PKT);
+            body.extend(refs);
+            body
+        }
+    };
 
     Ok(HttpResponse::Ok()
         .content_type(format!("application/x-git-{}-advertisement", &service_name))
@@ -86,66 +81,47 @@ pub async fn get_info_refs(
 
 #[post("/git-upload-pack")]
 pub async fn upload_pack(
+    req: HttpRequest,
     settings: web::Data<Settings>,
     payload: web::Bytes,
 ) -> Result<HttpResponse> {
     let service_name = Service::UploadPack.as_service_name();
+    let version = protocol_version(&req);
 
-    let output = web::block(move || {
-        let mut cmd = Command::new(&settings.git_binary)
-            .args(&[
-                service_name,
-                "--stateless-rpc",
-                &settings.index_dir.display().to_string(),
-            ])
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .spawn()?;
-
-        cmd.stdin.as_mut().unwrap().write_all(&payload)?;
-        cmd.wait_with_output()
+    let timer = metrics::UPLOAD_PACK_DURATION.start_timer();
+    let body = web::block(move || -> Result<Vec<u8>> {
+        let repo = Repository::open(&settings.index_dir).map_err(GitProtocolError::from)?;
+        Ok(match version {
+            ProtocolVersion::V0 => git_server::upload_pack(&repo, &payload)?,
+            ProtocolVersion::V2 => git_server::upload_pack_v2(&repo, &payload)?,
+        })
     })
     .await??;
+    timer.observe_duration();
 
-    if output.status.success() {
-        Ok(HttpResponse::Ok()
-            .content_type(format!("application/x-git-{}-result", service_name))
-            .body(output.stdout))
-    } else {
-        log::error!("git upload-pack failed with: `{:?}`", &output);
-        Ok(HttpResponse::InternalServerError().finish())
-    }
+    Ok(HttpResponse::Ok()
+        .content_type(format!("application/x-git-{}-result", service_name))
+        .body(body))
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::handlers::git::pkt_line;
+    use crate::git_server::pkt_line;
+    use crate::git_server::tests::{index_pack, split_response};
     use crate::test_helpers;
     use actix_web::http::header::QualityItem;
     use actix_web::http::{StatusCode, header};
-    use actix_web::{test, App};
-
-    #[test]
-    async fn test_pkt_line_from_example() {
-        let input = "d049f6c27a2244e12041955e262a404c7faba355 refs/heads/master\n";
-        let expected = "003fd049f6c27a2244e12041955e262a404c7faba355 refs/heads/master\n";
-        assert_eq!(expected, pkt_line(input));
-    }
-
-    #[test]
-    async fn test_pkt_line_empty() {
-        let input = "";
-        let expected = "0004";
-        assert_eq!(expected, pkt_line(input));
-    }
+    use actix_web::dev::ServerHandle;
+    use actix_web::{test, web, App, HttpServer};
 
     #[actix_rt::test]
     async fn test_get_info_refs_no_service_query() {
         let data_root = test_helpers::get_data_root();
-        let settings = test_helpers::get_test_settings(&data_root.path());
+        let settings = test_helpers::get_test_settings(data_root.path()).await;
         let package_index = test_helpers::get_test_package_index(&settings.index_dir);
-        let mut app = test::init_service(
+        let app = test::init_service(
             App::new()
+                .wrap(crate::auth::AuthFactory::new(settings.db.clone()))
                 .app_data(package_index.clone())
                 .app_data(settings.clone())
                 .configure(crate::handlers::configure_routes),
@@ -154,17 +130,18 @@ mod tests {
         let req = test::TestRequest::get()
             .uri("/git/index/info/refs")
             .to_request();
-        let resp = test::call_service(&mut app, req).await;
+        let resp = test::call_service(&app, req).await;
         assert_eq!(StatusCode::BAD_REQUEST, resp.status());
     }
 
     #[actix_rt::test]
     async fn test_get_info_refs_invalid_service_query() {
         let data_root = test_helpers::get_data_root();
-        let settings = test_helpers::get_test_settings(&data_root.path());
+        let settings = test_helpers::get_test_settings(data_root.path()).await;
         let package_index = test_helpers::get_test_package_index(&settings.index_dir);
-        let mut app = test::init_service(
+        let app = test::init_service(
             App::new()
+                .wrap(crate::auth::AuthFactory::new(settings.db.clone()))
                 .app_data(package_index.clone())
                 .app_data(settings.clone())
                 .configure(crate::handlers::configure_routes),
@@ -173,17 +150,18 @@ mod tests {
         let req = test::TestRequest::get()
             .uri("/git/index/info/refs?service=something%20invalid")
             .to_request();
-        let resp = test::call_service(&mut app, req).await;
+        let resp = test::call_service(&app, req).await;
         assert_eq!(StatusCode::BAD_REQUEST, resp.status());
     }
 
     #[actix_rt::test]
     async fn test_get_info_refs_valid_service_query() {
         let data_root = test_helpers::get_data_root();
-        let settings = test_helpers::get_test_settings(&data_root.path());
+        let settings = test_helpers::get_test_settings(data_root.path()).await;
         let package_index = test_helpers::get_test_package_index(&settings.index_dir);
-        let mut app = test::init_service(
+        let app = test::init_service(
             App::new()
+                .wrap(crate::auth::AuthFactory::new(settings.db.clone()))
                 .app_data(package_index.clone())
                 .app_data(settings.clone())
                 .configure(crate::handlers::configure_routes),
@@ -192,18 +170,19 @@ mod tests {
         let req = test::TestRequest::get()
             .uri("/git/index/info/refs?service=git-upload-pack")
             .to_request();
-        let resp = test::call_service(&mut app, req).await;
+        let resp = test::call_service(&app, req).await;
         assert_eq!(StatusCode::OK, resp.status());
     }
 
     #[actix_rt::test]
     async fn test_upload_pack_no_body() {
         let data_root = test_helpers::get_data_root();
-        let settings = test_helpers::get_test_settings(&data_root.path());
+        let settings = test_helpers::get_test_settings(data_root.path()).await;
         let package_index = test_helpers::get_test_package_index(&settings.index_dir);
 
-        let mut app = test::init_service(
+        let app = test::init_service(
             App::new()
+                .wrap(crate::auth::AuthFactory::new(settings.db.clone()))
                 .app_data(package_index.clone())
                 .app_data(settings.clone())
                 .configure(crate::handlers::configure_routes),
@@ -216,19 +195,18 @@ mod tests {
                 QualityItem::max("application/x-git-upload-pack-result".parse().unwrap())
             ]))
             .to_request();
-        let resp = test::call_service(&mut app, req).await;
-        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, resp.status());
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::BAD_REQUEST, resp.status());
     }
 
-    
-
     #[actix_rt::test]
     async fn test_upload_pack_initial_fetch() {
         let data_root = test_helpers::get_data_root();
-        let settings = test_helpers::get_test_settings(&data_root.path());
+        let settings = test_helpers::get_test_settings(data_root.path()).await;
         let package_index = test_helpers::get_test_package_index(&settings.index_dir);
-        let mut app = test::init_service(
+        let app = test::init_service(
             App::new()
+                .wrap(crate::auth::AuthFactory::new(settings.db.clone()))
                 .app_data(package_index.clone())
                 .app_data(settings.clone())
                 .configure(crate::handlers::configure_routes),
@@ -242,7 +220,200 @@ mod tests {
             ]))
             .set_payload("0000") // empty fetch, "don't care what you have"
             .to_request();
-        let resp = test::call_service(&mut app, req).await;
+        let resp = test::call_service(&app, req).await;
         assert_eq!(StatusCode::OK, resp.status());
     }
+
+    /// Serve the git routes on a random port, returning the url for the index.
+    fn start_server(settings: web::Data<crate::Settings>) -> (String, ServerHandle) {
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(settings.clone())
+                .configure(crate::handlers::configure_routes)
+        })
+        .workers(1)
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let url = format!("http://{}/git/index", server.addrs()[0]);
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+        (url, handle)
+    }
+
+    /// Cargo fetches the index with libgit2, so make sure it can clone from a
+    /// real server.
+    #[actix_rt::test]
+    async fn test_clone_with_libgit2() {
+        let data_root = test_helpers::get_data_root();
+        let settings = test_helpers::get_test_settings(data_root.path()).await;
+        let package_index = test_helpers::get_test_package_index(&settings.index_dir);
+        package_index.publish(test_helpers::pkg("foo", "0.1.0"), None).await.unwrap();
+
+        let (url, handle) = start_server(settings.clone());
+
+        let dest = data_root.path().join("clone");
+        let clone = dest.clone();
+        let head = web::block(move || {
+            let repo = git2::Repository::clone(&url, &clone).unwrap();
+            repo.refname_to_id("HEAD").unwrap()
+        })
+        .await
+        .unwrap();
+        handle.stop(true).await;
+
+        let index = git2::Repository::open(&settings.index_dir).unwrap();
+        assert_eq!(index.head().unwrap().target().unwrap(), head);
+        assert!(dest.join("3").join("f").join("foo").exists());
+    }
+
+    /// The git command line client, speaking protocol v2, can clone too.
+    #[actix_rt::test]
+    async fn test_clone_with_git_v2() {
+        if std::process::Command::new("git").arg("--version").output().is_err() {
+            eprintln!("skipping, `git` isn't installed");
+            return;
+        }
+        let data_root = test_helpers::get_data_root();
+        let settings = test_helpers::get_test_settings(data_root.path()).await;
+        let package_index = test_helpers::get_test_package_index(&settings.index_dir);
+        package_index.publish(test_helpers::pkg("foo", "0.1.0"), None).await.unwrap();
+
+        let (url, handle) = start_server(settings.clone());
+
+        let dest = data_root.path().join("clone");
+        let clone = dest.clone();
+        let output = web::block(move || {
+            std::process::Command::new("git")
+                .args(["-c", "protocol.version=2", "clone", "--quiet", &url])
+                .arg(&clone)
+                .env("GIT_TRACE_PACKET", "1")
+                .env("GIT_TERMINAL_PROMPT", "0")
+                .output()
+                .unwrap()
+        })
+        .await
+        .unwrap();
+        handle.stop(true).await;
+
+        let trace = String::from_utf8_lossy(&output.stderr);
+        assert!(output.status.success(), "{}", trace);
+        assert!(trace.contains("git< version 2"), "{}", trace);
+        assert!(trace.contains("> command=fetch"), "{}", trace);
+
+        let index = git2::Repository::open(&settings.index_dir).unwrap();
+        let cloned = git2::Repository::open(&dest).unwrap();
+        assert_eq!(
+            index.head().unwrap().target().unwrap(),
+            cloned.head().unwrap().target().unwrap()
+        );
+        assert!(dest.join("3").join("f").join("foo").exists());
+    }
+
+    /// Walk through a v2 clone: capabilities, `ls-refs`, then `fetch`.
+    #[actix_rt::test]
+    async fn test_clone_v2() {
+        let data_root = test_helpers::get_data_root();
+        let settings = test_helpers::get_test_settings(data_root.path()).await;
+        let package_index = test_helpers::get_test_package_index(&settings.index_dir);
+        package_index.publish(test_helpers::pkg("foo", "0.1.0"), None).await.unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(settings.clone())
+                .configure(crate::handlers::configure_routes),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/git/index/info/refs?service=git-upload-pack")
+            .append_header(("Git-Protocol", "version=2"))
+            .to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        assert!(body.starts_with(b"000eversion 2\n"));
+
+        let mut ls_refs = pkt_line("command=ls-refs\n").into_bytes();
+        ls_refs.extend(b"0001");
+        ls_refs.extend(pkt_line("symrefs\n").as_bytes());
+        ls_refs.extend(pkt_line("ref-prefix HEAD\n").as_bytes());
+        ls_refs.extend(b"0000");
+        let req = test::TestRequest::post()
+            .uri("/git/index/git-upload-pack")
+            .append_header(("Git-Protocol", "version=2"))
+            .set_payload(ls_refs)
+            .to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        let line = std::str::from_utf8(&body).unwrap();
+        assert!(line.contains(" HEAD symref-target:refs/heads/"));
+        let head = git2::Oid::from_str(&line[4..44]).unwrap();
+
+        let mut fetch = pkt_line("command=fetch\n").into_bytes();
+        fetch.extend(b"0001");
+        fetch.extend(pkt_line(&format!("want {}\n", head)).as_bytes());
+        fetch.extend(pkt_line("done\n").as_bytes());
+        fetch.extend(b"0000");
+        let req = test::TestRequest::post()
+            .uri("/git/index/git-upload-pack")
+            .append_header(("Git-Protocol", "version=2"))
+            .set_payload(fetch)
+            .to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        let packfile = pkt_line("packfile\n");
+        assert!(body.starts_with(packfile.as_bytes()));
+        let (_, pack) = split_response(&body[packfile.len()..]);
+
+        let clone = git2::Repository::init(data_root.path().join("clone")).unwrap();
+        index_pack(&clone, &pack);
+        let tree = clone.find_commit(head).unwrap().tree().unwrap();
+        assert!(tree.get_path(std::path::Path::new("3/f/foo")).is_ok());
+        assert!(tree.get_name("config.json").is_some());
+    }
+
+    /// Clones made before a squash have to take the new history with a
+    /// forced fetch, the same way cargo updates the index.
+    #[actix_rt::test]
+    async fn test_fetch_after_squash() {
+        let data_root = test_helpers::get_data_root();
+        let settings = test_helpers::get_test_settings(data_root.path()).await;
+        let package_index = test_helpers::get_test_package_index(&settings.index_dir);
+        package_index.publish(test_helpers::pkg("foo", "0.1.0"), None).await.unwrap();
+        let (url, handle) = start_server(settings.clone());
+
+        let dest = data_root.path().join("clone");
+        let clone_url = url.clone();
+        let clone_dest = dest.clone();
+        web::block(move || git2::Repository::clone(&clone_url, &clone_dest).map(|_| ()))
+            .await
+            .unwrap()
+            .unwrap();
+
+        package_index.publish(test_helpers::pkg("foo", "0.2.0"), None).await.unwrap();
+        package_index.squash().await.unwrap().unwrap();
+
+        let fetched = web::block(move || {
+            let repo = git2::Repository::open(&dest).unwrap();
+            repo.find_remote("origin")
+                .unwrap()
+                .fetch(&["+refs/heads/*:refs/remotes/origin/*"], None, None)
+                .unwrap();
+            let head = repo.find_reference("refs/remotes/origin/master").unwrap();
+            let commit = head.peel_to_commit().unwrap();
+            let blob = commit
+                .tree()
+                .unwrap()
+                .get_path(std::path::Path::new("3/f/foo"))
+                .unwrap()
+                .to_object(&repo)
+                .unwrap()
+                .peel_to_blob()
+                .unwrap();
+            (commit.id(), blob.content().to_vec())
+        })
+        .await
+        .unwrap();
+        handle.stop(true).await;
+
+        let index = git2::Repository::open(&settings.index_dir).unwrap();
+        assert_eq!(index.head().unwrap().target().unwrap(), fetched.0);
+        assert_eq!(2, String::from_utf8(fetched.1).unwrap().lines().count());
+    }
 }
@@ -0,0 +1,240 @@
+//! Endpoints for keeping an eye on estuary itself.
+//!
+//! - Metrics `GET /metrics`, in the Prometheus text format.
+//! - Liveness `GET /healthz`, which is ok as long as the server is answering.
+//! - Readiness `GET /readyz`, which checks that the database and redis can be
+//!   reached, the index repo can be opened and the crate dir can be written
+//!   to. Responds with a 503 when any of them fail, with the result of each
+//!   check in the json body.
+
+use crate::errors::EstuaryError;
+use crate::metrics;
+use crate::Settings;
+use actix_web::{get, web, HttpResponse};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::time::Duration;
+
+/// How long to give redis to answer.
+const REDIS_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[get("/metrics")]
+pub async fn get_metrics(settings: web::Data<Settings>) -> Result<HttpResponse, EstuaryError> {
+    // Measuring storage walks the crate dir, so keep it off the event loop.
+    let body =
+        web::block(move || metrics::render(settings.db.as_ref(), &settings.crate_dir)).await?;
+    Ok(HttpResponse::Ok()
+        .content_type(prometheus::TEXT_FORMAT)
+        .body(body))
+}
+
+#[get("/healthz")]
+pub async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(Readiness {
+        status: "ok",
+        checks: BTreeMap::new(),
+    })
+}
+
+#[derive(Serialize)]
+struct Readiness {
+    status: &'static str,
+    checks: BTreeMap<&'static str, String>,
+}
+
+#[get("/readyz")]
+pub async fn readyz(settings: web::Data<Settings>) -> HttpResponse {
+    let mut checks = BTreeMap::new();
+    checks.insert("database", outcome("database", settings.db.ping().await));
+
+    let blocking = settings.clone();
+    let res = web::block(move || {
+        let redis = blocking.redis_uri.as_deref().map(ping_redis);
+        let index = git2::Repository::open(&blocking.index_dir).map(|_| ());
+        let crate_dir = check_writable(&blocking.crate_dir);
+        (redis, index, crate_dir)
+    })
+    .await;
+    match res {
+        Ok((redis, index, crate_dir)) => {
+            checks.insert(
+                "redis",
+                match redis {
+                    Some(res) => outcome("redis", res),
+                    None => String::from("not configured"),
+                },
+            );
+            checks.insert("index", outcome("index", index));
+            checks.insert("crate_dir", outcome("crate_dir", crate_dir));
+        }
+        Err(e) => {
+            log::error!("Readiness checks failed to run: {}", e);
+            checks.insert("checks", String::from("failed to run"));
+        }
+    }
+
+    let ready = checks
+        .values()
+        .all(|outcome| outcome == "ok" || outcome == "not configured");
+    let body = Readiness {
+        status: if ready { "ok" } else { "unavailable" },
+        checks,
+    };
+    if ready {
+        HttpResponse::Ok().json(body)
+    } else {
+        HttpResponse::ServiceUnavailable().json(body)
+    }
+}
+
+/// Summarize a check. Failures are logged in full, but only reported as
+/// failed since anyone can ask.
+fn outcome<E: std::fmt::Display>(check: &str, res: Result<(), E>) -> String {
+    match res {
+        Ok(()) => String::from("ok"),
+        Err(e) => {
+            log::warn!("Readiness check `{}` failed: {}", check, e);
+            String::from("failed")
+        }
+    }
+}
+
+/// Send redis a `PING`. Any reply at all means it's up, even one asking for
+/// a password.
+fn ping_redis(addr: &str) -> io::Result<()> {
+    let addr = addr.trim_start_matches("redis://");
+    let addr = addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address for redis"))?;
+    let mut stream = TcpStream::connect_timeout(&addr, REDIS_TIMEOUT)?;
+    stream.set_read_timeout(Some(REDIS_TIMEOUT))?;
+    stream.write_all(b"PING\r\n")?;
+    let mut reply = [0; 1];
+    stream.read_exact(&mut reply)?;
+    match reply[0] {
+        b'+' | b'-' => Ok(()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unexpected reply from redis",
+        )),
+    }
+}
+
+/// Write and remove a file, to be sure publishes won't fail on storage.
+fn check_writable(dir: &Path) -> io::Result<()> {
+    let probe = dir.join(".readyz");
+    std::fs::write(&probe, b"")?;
+    std::fs::remove_file(&probe)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_helpers;
+    use crate::test_helpers::MY_CRATE_0_1_0;
+    use actix_web::http::{header, StatusCode};
+    use actix_web::{test, App};
+
+    /// The value of a series, which has to be there.
+    fn value(metrics: &str, series: &str) -> f64 {
+        metrics
+            .lines()
+            .find_map(|line| line.strip_prefix(series)?.trim().parse().ok())
+            .unwrap_or_else(|| panic!("`{}` missing from metrics", series))
+    }
+
+    #[actix_rt::test]
+    async fn test_metrics() {
+        let data_root = test_helpers::get_data_root();
+        let settings = test_helpers::get_test_settings(data_root.path()).await;
+        let api_key = test_helpers::get_test_api_key(&settings).await;
+        let package_index = test_helpers::get_test_package_index(&settings.index_dir);
+        let app = test::init_service(
+            App::new()
+                .wrap(crate::metrics::RequestMetrics)
+                .wrap(crate::auth::AuthFactory::new(settings.db.clone()))
+                .app_data(settings.clone())
+                .app_data(package_index.clone())
+                .configure(crate::handlers::configure_routes),
+        )
+        .await;
+
+        let req = test::TestRequest::put()
+            .uri("/api/v1/crates/new")
+            .append_header((header::AUTHORIZATION, api_key.as_str()))
+            .set_payload(MY_CRATE_0_1_0)
+            .to_request();
+        let _: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+
+        let req = test::TestRequest::get()
+            .uri("/api/v1/crates/my-crate/0.1.0/download")
+            .to_request();
+        assert_eq!(StatusCode::OK, test::call_service(&app, req).await.status());
+
+        let req = test::TestRequest::get().uri("/metrics").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::OK, resp.status());
+        let body = test::read_body(resp).await;
+        let metrics = std::str::from_utf8(&body).unwrap();
+
+        // Other tests share the counters, so they may be higher.
+        assert!(value(metrics, "estuary_publishes_total") >= 1.0);
+        assert!(value(metrics, "estuary_downloads_total") >= 1.0);
+        assert!(value(metrics, "estuary_index_wait_seconds_count") >= 1.0);
+        assert!(
+            value(
+                metrics,
+                r#"estuary_http_requests_total{method="GET",route="/api/v1/crates/{crate_name}/{version}/download",status="200"}"#
+            ) >= 1.0
+        );
+        assert!(value(metrics, "estuary_storage_bytes") > 0.0);
+        assert!(value(metrics, "estuary_db_pool_connections") >= 1.0);
+    }
+
+    #[actix_rt::test]
+    async fn test_health_and_readiness() {
+        let data_root = test_helpers::get_data_root();
+        let settings = test_helpers::get_test_settings(data_root.path()).await;
+        let package_index = test_helpers::get_test_package_index(&settings.index_dir);
+        std::fs::create_dir_all(&settings.crate_dir).unwrap();
+
+        let mut broken = settings.get_ref().clone();
+        // A file where a dir should be.
+        std::fs::write(data_root.path().join("file"), b"").unwrap();
+        broken.crate_dir = data_root.path().join("file");
+        broken.redis_uri = Some(String::from("127.0.0.1:1"));
+
+        for (settings, ready) in [(settings, true), (actix_web::web::Data::new(broken), false)] {
+            let app = test::init_service(
+                App::new()
+                    .wrap(crate::auth::AuthFactory::new(settings.db.clone()))
+                    .app_data(settings.clone())
+                    .app_data(package_index.clone())
+                    .configure(crate::handlers::configure_routes),
+            )
+            .await;
+
+            let req = test::TestRequest::get().uri("/healthz").to_request();
+            assert_eq!(StatusCode::OK, test::call_service(&app, req).await.status());
+
+            let req = test::TestRequest::get().uri("/readyz").to_request();
+            let resp = test::call_service(&app, req).await;
+            let status = resp.status();
+            let body: serde_json::Value = test::read_body_json(resp).await;
+            assert_eq!("ok", body["checks"]["database"]);
+            assert_eq!("ok", body["checks"]["index"]);
+            if ready {
+                assert_eq!(StatusCode::OK, status);
+                assert_eq!("not configured", body["checks"]["redis"]);
+                assert_eq!("ok", body["checks"]["crate_dir"]);
+            } else {
+                assert_eq!(StatusCode::SERVICE_UNAVAILABLE, status);
+                assert_eq!("failed", body["checks"]["redis"]);
+                assert_eq!("failed", body["checks"]["crate_dir"]);
+            }
+        }
+    }
+}
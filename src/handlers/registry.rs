@@ -12,25 +12,48 @@
 //! - [x] Download `GET /api/v1/crates/{crate_name}/{version}/download`.
 //! - [x] Yank `DELETE /api/v1/crates/{crate_name}/{version}/yank`.
 //! - [x] Unyank `PUT /api/v1/crates/{crate_name}/{version}/unyank`.
+//! - [x] Delete `DELETE /api/v1/crates/{crate_name}/{version}` (not part of the
+//!   cargo api, administrators only). Query params: `block` (refuse future
+//!   publishes of the same version - default false).
+//! - [x] Docs `PUT /api/v1/crates/{crate_name}/{version}/docs` (not part of the
+//!   cargo api). The body is a tarball of `target/doc`, optionally gzipped.
 //! - [ ] Owners List `GET /api/v1/crates/{crate_name}/owners`.
 //! - [ ] Owners Add `PUT /api/v1/crates/{crate_name}/owners`.
 //! - [ ] Owners Remove `DELETE /api/v1/crates/{crate_name}/owners`.
 //! - [ ] Search `GET /api/v1/crates` query params: `q` (search terms), `per_page`
 //!   (result limit - default 10, max 100).
 //! - [x] Login `/me` (this one lives in the frontend module).
+//!
+//! Cargo itself never reads crate details over the web api (it uses the index)
+//! but plenty of other tools do, so a subset of the crates.io read api is
+//! offered as well, built from the index plus the metadata recorded in the
+//! database during publish:
+//!
+//! - [x] Crate `GET /api/v1/crates/{crate_name}`.
+//! - [x] Versions `GET /api/v1/crates/{crate_name}/versions`.
+//! - [x] Version `GET /api/v1/crates/{crate_name}/{version}`.
+//! - [x] Dependencies `GET /api/v1/crates/{crate_name}/{version}/dependencies`.
+//! - [x] Reverse Dependencies `GET /api/v1/crates/{crate_name}/reverse_dependencies`.
 
+use crate::audit;
 use crate::auth::Authenticated;
-use crate::errors::ApiError;
-use crate::package_index::{Dependency, PackageIndex, PackageVersion};
+use crate::deletion::{self, Deletion};
+use crate::docs;
+use crate::metrics;
+use crate::database::{AuditAction, CrateVersion, DatabaseError, NewAuditEntry, NewCrateVersion};
+use crate::errors::{ApiError, PackageIndexError};
+use crate::package_index::{Dependency, DependencyKind, IndexHandle, IndexReader, PackageVersion};
 use crate::Settings;
+use crate::webhooks::{self, WebhookEvent};
 use actix_files as fs;
-use actix_web::{delete, get, put, web, HttpResponse};
+use actix_web::{delete, get, put, web, HttpRequest, HttpResponse};
 use byteorder::{LittleEndian, ReadBytesExt};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use futures::StreamExt;
 use sha2::{Digest, Sha256};
+use std::io::Write;
 use std::collections::HashMap;
-use std::sync::Mutex;
 
 pub type ApiResponse = Result<HttpResponse, ApiError>;
 
@@ -40,10 +63,16 @@ pub struct Crate {
     version: semver::Version,
 }
 
+#[derive(Deserialize)]
+pub struct CrateName {
+    crate_name: String,
+}
+
 /// Data supplied by `cargo` during the publishing of a crate.
 ///
-/// The actual json payload has extra fields (which we're currently dropping)
-/// but in the future we might want to record the data instead.
+/// The actual json payload has extra fields (readme, badges, etc) which we're
+/// currently dropping.
+/// The fields that aren't part of the index are recorded in the database.
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct PartialPackageVersion {
     name: String,
@@ -51,19 +80,29 @@ pub struct PartialPackageVersion {
     deps: Vec<Dependency>,
     features: HashMap<String, Vec<String>>,
     links: Option<String>,
+    #[serde(default)]
+    authors: Vec<String>,
+    description: Option<String>,
+    documentation: Option<String>,
+    homepage: Option<String>,
+    #[serde(default)]
+    keywords: Vec<String>,
+    #[serde(default)]
+    categories: Vec<String>,
+    license: Option<String>,
+    repository: Option<String>,
 }
 
 #[put("/new")]
 pub async fn publish(
+    req: HttpRequest,
     mut payload: web::Bytes,
-    package_index: web::Data<Mutex<PackageIndex>>,
+    package_index: web::Data<IndexHandle>,
     settings: web::Data<Settings>,
-    _auth : Authenticated
+    auth : Authenticated
 ) -> ApiResponse {
     log::trace!("total len: {}", payload.len());
 
-    log::trace!("data: {:?}", payload.as_ref());
-
     let metadata_len = { payload.split_to(4).as_ref().read_u32::<LittleEndian>()? } as usize;
     log::trace!("metadata len: {}", metadata_len);
 
@@ -76,6 +115,31 @@ pub async fn publish(
     let crate_file_bytes = payload.split_to(crate_file_len);
     let cksum = format!("{:x}", Sha256::digest(crate_file_bytes.as_ref()));
 
+    let new_version = NewCrateVersion {
+        vers: metadata.vers.to_string(),
+        description: metadata.description,
+        documentation: metadata.documentation,
+        homepage: metadata.homepage,
+        repository: metadata.repository,
+        license: metadata.license,
+        keywords: metadata.keywords,
+        categories: metadata.categories,
+        authors: metadata.authors,
+        crate_size: crate_file_bytes.len() as i64,
+    };
+
+    if settings
+        .db
+        .is_version_blocked(metadata.name.clone(), metadata.vers.to_string())
+        .await?
+    {
+        return Err(PackageIndexError::Publish(format!(
+            "Failed to publish `{} v{}`. This version was deleted and can't be published again.",
+            metadata.name, metadata.vers
+        ))
+        .into());
+    }
+
     let pkg_version = PackageVersion {
         name: metadata.name,
         vers: metadata.vers,
@@ -84,10 +148,12 @@ pub async fn publish(
         features: metadata.features,
         yanked: false,
         links: metadata.links,
+        extra: Default::default(),
     };
 
-    let package_index = package_index.lock().unwrap();
-    package_index.publish(&pkg_version)?;
+    package_index
+        .publish(pkg_version.clone(), Some(auth.name.clone()))
+        .await?;
 
     crate::storage::store_crate_file(
         &settings.crate_dir,
@@ -95,6 +161,44 @@ pub async fn publish(
         &pkg_version.vers,
         crate_file_bytes.as_ref(),
     )?;
+
+    let entry = NewAuditEntry {
+        uid: Some(auth.id),
+        action: AuditAction::Publish,
+        crate_name: Some(pkg_version.name.clone()),
+        vers: Some(pkg_version.vers.to_string()),
+        detail: None,
+        ip: audit::source_ip(&req),
+    };
+
+    // The version is in the index and storage by now, so cargo can already
+    // use it. Failing the publish here would only make a retry fail as a
+    // duplicate, so the client is told about it instead.
+    let mut other_warnings = vec![];
+    if let Err(e) = record_version(&settings, &auth, &pkg_version.name, &entry, new_version).await {
+        log::error!(
+            "Published `{} v{}` but failed to record it in the database: {}",
+            pkg_version.name, pkg_version.vers, e
+        );
+        other_warnings.push(format!(
+            "`{} v{}` was published, but its details couldn't be saved so it may be \
+            missing from the web interface. Please let the registry administrator know.",
+            pkg_version.name, pkg_version.vers
+        ));
+    }
+    metrics::PUBLISHES.inc();
+    audit::record(settings.db.as_ref(), entry).await;
+    webhooks::notify(
+        settings.db.clone(),
+        webhooks::Payload {
+            event: WebhookEvent::Publish,
+            crate_name: pkg_version.name,
+            version: pkg_version.vers.to_string(),
+            user: auth.name.clone(),
+            timestamp: chrono::Utc::now().to_rfc3339(),
+        },
+    );
+
     Ok(HttpResponse::Ok().json(json!({
         // Optional object of warnings to display to the user.
         "warnings": {
@@ -103,30 +207,230 @@ pub async fn publish(
             // Array of strings of badge names that are invalid and ignored.
             "invalid_badges": [],
             // Array of strings of arbitrary warnings to display to the user.
-            "other": []
+            "other": other_warnings
         }
     })))
 }
 
+/// Record a newly published version in the database, creating the crate (owned
+/// by the publisher) if it's new.
+async fn record_version(
+    settings: &Settings,
+    auth: &Authenticated,
+    name: &str,
+    entry: &NewAuditEntry,
+    new_version: NewCrateVersion,
+) -> Result<(), DatabaseError> {
+    let krate = match settings.db.get_crate(name.to_string()).await? {
+        Some(krate) => krate,
+        None => {
+            let krate = settings.db.create_crate(name.to_string()).await?;
+            settings.db.add_crate_owner(krate.id, auth.id).await?;
+            let owner_entry = NewAuditEntry {
+                action: AuditAction::OwnerAdd,
+                vers: None,
+                detail: Some(auth.name.clone()),
+                ..entry.clone()
+            };
+            audit::record(settings.db.as_ref(), owner_entry).await;
+            krate
+        }
+    };
+    settings.db.add_crate_version(krate.id, auth.id, new_version).await
+}
+
 #[delete("/{crate_name}/{version}/yank")]
 pub async fn yank(
+    req: HttpRequest,
     path: web::Path<Crate>,
-    package_index: web::Data<Mutex<PackageIndex>>,
-    _auth : Authenticated
+    package_index: web::Data<IndexHandle>,
+    settings: web::Data<Settings>,
+    auth : Authenticated
 ) -> ApiResponse {
-    let package_index = package_index.lock().unwrap();
-    package_index.set_yanked(&path.crate_name, &path.version, true)?;
-    Ok(HttpResponse::Ok().json(json!({ "ok": true })))
+    set_yanked(req, path, package_index, settings, auth, true).await
 }
 
 #[put("/{crate_name}/{version}/unyank")]
 pub async fn unyank(
+    req: HttpRequest,
+    path: web::Path<Crate>,
+    package_index: web::Data<IndexHandle>,
+    settings: web::Data<Settings>,
+    auth : Authenticated
+) -> ApiResponse {
+    set_yanked(req, path, package_index, settings, auth, false).await
+}
+
+#[put("/{crate_name}/{version}/docs")]
+pub async fn upload_docs(
+    req: HttpRequest,
+    path: web::Path<Crate>,
+    mut payload: web::Payload,
+    package_index: web::Data<IndexHandle>,
+    settings: web::Data<Settings>,
+    auth : Authenticated
+) -> ApiResponse {
+    let versions = get_package_versions(&package_index, &path.crate_name)?;
+    if !versions.iter().any(|v| v.vers == path.version) {
+        return Err(ApiError::NotFound);
+    }
+    // Docs are served from the registry's own origin, so only the people
+    // who could publish the crate get to upload them.
+    if !auth.is_admin() {
+        let owners = settings.db.get_crate_owners(path.crate_name.clone()).await?;
+        if !owners.iter().any(|owner| owner.id == auth.id) {
+            return Err(ApiError::Forbidden);
+        }
+    }
+
+    let dest = crate::storage::get_docs_dir(&settings.crate_dir, &path.crate_name, &path.version);
+    std::fs::create_dir_all(dest.parent().unwrap())?;
+    let archive = docs::scratch_path(&dest, "upload.tar");
+    {
+        let mut fh = std::fs::File::create(&archive)?;
+        let mut size = 0;
+        while let Some(chunk) = payload.next().await {
+            let chunk = chunk.map_err(|e| ApiError::BadRequest(e.to_string()))?;
+            size += chunk.len();
+            if size > docs::MAX_UPLOAD_SIZE {
+                drop(fh);
+                std::fs::remove_file(&archive)?;
+                return Err(ApiError::BadRequest(format!(
+                    "Docs can be at most {} MiB.",
+                    docs::MAX_UPLOAD_SIZE / 1024 / 1024
+                )));
+            }
+            fh.write_all(&chunk)?;
+        }
+    }
+
+    let res = {
+        let archive = archive.clone();
+        web::block(move || docs::unpack(&archive, &dest)).await
+    };
+    std::fs::remove_file(&archive)?;
+    match res? {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+            return Err(ApiError::BadRequest(e.to_string()))
+        }
+        Err(e) => return Err(e.into()),
+    }
+
+    audit::record(
+        settings.db.as_ref(),
+        NewAuditEntry {
+            uid: Some(auth.id),
+            action: AuditAction::DocsUpload,
+            crate_name: Some(path.crate_name.clone()),
+            vers: Some(path.version.to_string()),
+            detail: None,
+            ip: audit::source_ip(&req),
+        },
+    )
+    .await;
+
+    Ok(HttpResponse::Ok().json(json!({ "ok": true })))
+}
+
+#[derive(Deserialize)]
+pub struct DeleteQuery {
+    #[serde(default)]
+    block: bool,
+}
+
+#[delete("/{crate_name}/{version}")]
+pub async fn delete_version(
+    req: HttpRequest,
+    path: web::Path<Crate>,
+    query: web::Query<DeleteQuery>,
+    package_index: web::Data<IndexHandle>,
+    settings: web::Data<Settings>,
+    auth : Authenticated
+) -> ApiResponse {
+    if !auth.is_admin() {
+        return Err(ApiError::Forbidden);
+    }
+
+    let deletion = Deletion {
+        crate_name: path.crate_name.clone(),
+        version: path.version.clone(),
+        block: query.block,
+        uid: Some(auth.id),
+        author: Some(auth.name.clone()),
+        ip: audit::source_ip(&req),
+    };
+    deletion::delete_version(&package_index, &settings, deletion)
+        .await
+        .map_err(|e| match e {
+            ApiError::PackageIndex(PackageIndexError::CrateNotFound(_))
+            | ApiError::PackageIndex(PackageIndexError::VersionNotFound(_, _)) => ApiError::NotFound,
+            e => e,
+        })?;
+
+    Ok(HttpResponse::Ok().json(json!({ "ok": true })))
+}
+
+/// Shared by yank and unyank, which only differ by the flag they set.
+async fn set_yanked(
+    req: HttpRequest,
     path: web::Path<Crate>,
-    package_index: web::Data<Mutex<PackageIndex>>,
-    _auth : Authenticated
+    package_index: web::Data<IndexHandle>,
+    settings: web::Data<Settings>,
+    auth: Authenticated,
+    yanked: bool,
 ) -> ApiResponse {
-    let index = package_index.lock().unwrap();
-    index.set_yanked(&path.crate_name, &path.version, false)?;
+    let changed = package_index
+        .set_yanked(
+            path.crate_name.clone(),
+            path.version.clone(),
+            yanked,
+            Some(auth.name.clone()),
+        )
+        .await
+        .map_err(|e| match e {
+            PackageIndexError::CrateNotFound(_)
+            | PackageIndexError::VersionNotFound(_, _) => ApiError::NotFound,
+            e => e.into(),
+        })?;
+    if !changed {
+        // Already the way it was asked for, so nothing to tell anyone about.
+        return Ok(HttpResponse::Ok().json(json!({ "ok": true })));
+    }
+    settings
+        .db
+        .set_yanked(path.crate_name.clone(), path.version.to_string(), yanked)
+        .await?;
+
+    let (action, event) = if yanked {
+        metrics::YANKS.inc();
+        (AuditAction::Yank, WebhookEvent::Yank)
+    } else {
+        metrics::UNYANKS.inc();
+        (AuditAction::Unyank, WebhookEvent::Unyank)
+    };
+    audit::record(
+        settings.db.as_ref(),
+        NewAuditEntry {
+            uid: Some(auth.id),
+            action,
+            crate_name: Some(path.crate_name.clone()),
+            vers: Some(path.version.to_string()),
+            detail: None,
+            ip: audit::source_ip(&req),
+        },
+    )
+    .await;
+    webhooks::notify(
+        settings.db.clone(),
+        webhooks::Payload {
+            event,
+            crate_name: path.crate_name.clone(),
+            version: path.version.to_string(),
+            user: auth.name.clone(),
+            timestamp: chrono::Utc::now().to_rfc3339(),
+        },
+    );
     Ok(HttpResponse::Ok().json(json!({ "ok": true })))
 }
 
@@ -138,7 +442,9 @@ pub async fn download(
     let crate_file =
         crate::storage::get_crate_file_path(&settings.crate_dir, &path.crate_name, &path.version);
     log::debug!("serving `{}`", crate_file.display());
-    Ok(fs::NamedFile::open(crate_file)?)
+    let file = fs::NamedFile::open(crate_file)?;
+    metrics::DOWNLOADS.inc();
+    Ok(file)
 }
 
 /// Query string params for the search endpoint.
@@ -169,10 +475,9 @@ pub struct SearchResult {
 #[get("")]
 pub async fn search(
     query: web::Query<SearchQuery>,
-    index: web::Data<Mutex<PackageIndex>>,
+    index: web::Data<IndexHandle>,
 ) -> ApiResponse {
-    let index = index.lock().unwrap();
-    let names = index.list_crates()?;
+    let names = index.list_crates();
     let terms: Vec<&str> = query.q.split(&['-', '_', ' ', '\t'][..]).collect();
     let mut matches: Vec<(&str, usize)> = names
         .iter()
@@ -226,21 +531,350 @@ pub async fn search(
     })))
 }
 
+/// Timestamps in the crates.io api are RFC 3339 strings.
+pub(crate) fn format_timestamp(secs: i64) -> Option<String> {
+    use chrono::TimeZone;
+    chrono::Utc
+        .timestamp_opt(secs, 0)
+        .single()
+        .map(|dt| dt.to_rfc3339())
+}
+
+/// Read the versions for a crate from the index, treating a missing package
+/// file as a 404.
+fn get_package_versions(index: &IndexReader, name: &str) -> Result<Vec<PackageVersion>, ApiError> {
+    index.get_package_versions(name).map_err(|e| match e {
+        PackageIndexError::CrateNotFound(_) => ApiError::NotFound,
+        _ => e.into(),
+    })
+}
+
+#[derive(Serialize, Debug)]
+pub struct ApiUser {
+    id: i32,
+    login: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ApiVersionLinks {
+    dependencies: String,
+}
+
+/// A version, as described by the crates.io api.
+///
+/// Versions published before metadata was being recorded (or otherwise missing
+/// from the database) will have `null` for the fields only the database knows.
+#[derive(Serialize, Debug)]
+pub struct ApiVersion {
+    id: Option<i32>,
+    #[serde(rename = "crate")]
+    krate: String,
+    num: semver::Version,
+    dl_path: String,
+    checksum: String,
+    yanked: bool,
+    features: HashMap<String, Vec<String>>,
+    links: ApiVersionLinks,
+    license: Option<String>,
+    crate_size: Option<i64>,
+    published_by: Option<ApiUser>,
+    created_at: Option<String>,
+    updated_at: Option<String>,
+    downloads: u64,
+}
+
+impl ApiDependency {
+    fn new(id: usize, version_id: Option<i32>, dep: Dependency) -> Self {
+        ApiDependency {
+            id,
+            version_id,
+            // When a dependency is renamed, `name` is the alias and `package`
+            // holds the name of the crate it actually refers to.
+            crate_id: dep.package.unwrap_or(dep.name),
+            req: dep.req,
+            optional: dep.optional,
+            default_features: dep.default_features,
+            features: dep.features,
+            target: dep.target,
+            kind: dep.kind,
+            downloads: 0,
+        }
+    }
+}
+
+impl ApiVersion {
+    fn new(pkg: &PackageVersion, meta: Option<&CrateVersion>) -> Self {
+        let created_at = meta.and_then(|m| format_timestamp(m.created_at));
+        ApiVersion {
+            id: meta.map(|m| m.id),
+            krate: pkg.name.clone(),
+            num: pkg.vers.clone(),
+            dl_path: format!("/api/v1/crates/{}/{}/download", pkg.name, pkg.vers),
+            checksum: pkg.cksum.clone(),
+            yanked: pkg.yanked,
+            features: pkg.features.clone(),
+            links: ApiVersionLinks {
+                dependencies: format!("/api/v1/crates/{}/{}/dependencies", pkg.name, pkg.vers),
+            },
+            license: meta.and_then(|m| m.license.clone()),
+            crate_size: meta.map(|m| m.crate_size),
+            published_by: meta.and_then(|m| {
+                m.published_by.clone().map(|login| ApiUser { id: m.uid, login })
+            }),
+            updated_at: created_at.clone(),
+            created_at,
+            downloads: 0,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct ApiCrateLinks {
+    versions: String,
+    reverse_dependencies: String,
+}
+
+/// A crate, as described by the crates.io api.
+#[derive(Serialize, Debug)]
+pub struct ApiCrate {
+    id: String,
+    name: String,
+    description: Option<String>,
+    homepage: Option<String>,
+    documentation: Option<String>,
+    repository: Option<String>,
+    keywords: Vec<String>,
+    categories: Vec<String>,
+    max_version: semver::Version,
+    max_stable_version: Option<semver::Version>,
+    newest_version: semver::Version,
+    versions: Vec<Option<i32>>,
+    links: ApiCrateLinks,
+    created_at: Option<String>,
+    updated_at: Option<String>,
+    downloads: u64,
+    recent_downloads: Option<u64>,
+    exact_match: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ApiDependency {
+    id: usize,
+    version_id: Option<i32>,
+    crate_id: String,
+    req: String,
+    optional: bool,
+    default_features: bool,
+    features: Vec<String>,
+    target: Option<String>,
+    kind: DependencyKind,
+    downloads: u64,
+}
+
+/// Pair each version from the index with the metadata recorded for it.
+fn join_versions(pkgs: &[PackageVersion], metas: &[CrateVersion]) -> Vec<ApiVersion> {
+    pkgs.iter()
+        .map(|pkg| {
+            let meta = metas.iter().find(|m| m.vers == pkg.vers.to_string());
+            ApiVersion::new(pkg, meta)
+        })
+        .collect()
+}
+
+#[get("/{crate_name}")]
+pub async fn crate_info(
+    path: web::Path<CrateName>,
+    index: web::Data<IndexHandle>,
+    settings: web::Data<Settings>,
+) -> ApiResponse {
+    let pkgs = get_package_versions(&index, &path.crate_name)?;
+    let metas = settings.db.get_crate_versions(path.crate_name.clone()).await?;
+
+    // The package file is only ever created by a publish, so there's always at
+    // least one version in it.
+    let newest = pkgs.last().ok_or(ApiError::NotFound)?;
+    let max_version = pkgs
+        .iter()
+        .filter(|pkg| !pkg.yanked)
+        .map(|pkg| &pkg.vers)
+        .max()
+        .cloned()
+        .unwrap_or_else(|| semver::Version::new(0, 0, 0));
+    let max_stable_version = pkgs
+        .iter()
+        .filter(|pkg| !pkg.yanked && !pkg.vers.is_prerelease())
+        .map(|pkg| &pkg.vers)
+        .max()
+        .cloned();
+
+    // Crate level details reflect whatever was sent with the latest publish.
+    let latest_meta = metas.last();
+    let mut versions = join_versions(&pkgs, &metas);
+    versions.sort_by(|a, b| b.num.cmp(&a.num));
+
+    let krate = ApiCrate {
+        id: newest.name.clone(),
+        name: newest.name.clone(),
+        description: latest_meta.and_then(|m| m.description.clone()),
+        homepage: latest_meta.and_then(|m| m.homepage.clone()),
+        documentation: latest_meta.and_then(|m| m.documentation.clone()),
+        repository: latest_meta.and_then(|m| m.repository.clone()),
+        keywords: latest_meta.map(|m| m.keyword_list()).unwrap_or_default(),
+        categories: latest_meta.map(|m| m.category_list()).unwrap_or_default(),
+        max_version,
+        max_stable_version,
+        newest_version: newest.vers.clone(),
+        versions: versions.iter().map(|v| v.id).collect(),
+        links: ApiCrateLinks {
+            versions: format!("/api/v1/crates/{}/versions", newest.name),
+            reverse_dependencies: format!(
+                "/api/v1/crates/{}/reverse_dependencies",
+                newest.name
+            ),
+        },
+        created_at: metas.first().and_then(|m| format_timestamp(m.created_at)),
+        updated_at: latest_meta.and_then(|m| format_timestamp(m.created_at)),
+        downloads: 0,
+        recent_downloads: None,
+        exact_match: false,
+    };
+
+    Ok(HttpResponse::Ok().json(json!({
+        "crate": krate,
+        "versions": versions,
+        "keywords": [],
+        "categories": [],
+    })))
+}
+
+#[get("/{crate_name}/versions")]
+pub async fn crate_versions(
+    path: web::Path<CrateName>,
+    index: web::Data<IndexHandle>,
+    settings: web::Data<Settings>,
+) -> ApiResponse {
+    let pkgs = get_package_versions(&index, &path.crate_name)?;
+    let metas = settings.db.get_crate_versions(path.crate_name.clone()).await?;
+    let mut versions = join_versions(&pkgs, &metas);
+    versions.sort_by(|a, b| b.num.cmp(&a.num));
+    Ok(HttpResponse::Ok().json(json!({ "versions": versions })))
+}
+
+#[get("/{crate_name}/{version}")]
+pub async fn crate_version(
+    path: web::Path<Crate>,
+    index: web::Data<IndexHandle>,
+    settings: web::Data<Settings>,
+) -> ApiResponse {
+    let pkg = get_package_versions(&index, &path.crate_name)?
+        .into_iter()
+        .find(|pkg| pkg.vers == path.version)
+        .ok_or(ApiError::NotFound)?;
+    let metas = settings.db.get_crate_versions(path.crate_name.clone()).await?;
+    let meta = metas.iter().find(|m| m.vers == pkg.vers.to_string());
+    Ok(HttpResponse::Ok().json(json!({ "version": ApiVersion::new(&pkg, meta) })))
+}
+
+#[get("/{crate_name}/{version}/dependencies")]
+pub async fn crate_version_dependencies(
+    path: web::Path<Crate>,
+    index: web::Data<IndexHandle>,
+    settings: web::Data<Settings>,
+) -> ApiResponse {
+    let pkg = get_package_versions(&index, &path.crate_name)?
+        .into_iter()
+        .find(|pkg| pkg.vers == path.version)
+        .ok_or(ApiError::NotFound)?;
+    let metas = settings.db.get_crate_versions(path.crate_name.clone()).await?;
+    let version_id = metas
+        .iter()
+        .find(|m| m.vers == pkg.vers.to_string())
+        .map(|m| m.id);
+
+    let dependencies: Vec<ApiDependency> = pkg
+        .deps
+        .into_iter()
+        .enumerate()
+        .map(|(id, dep)| ApiDependency::new(id, version_id, dep))
+        .collect();
+
+    Ok(HttpResponse::Ok().json(json!({ "dependencies": dependencies })))
+}
+
+/// Lists the crates depending on the given crate.
+///
+/// Like crates.io, only the highest version of each dependent crate is
+/// included.
+#[get("/{crate_name}/reverse_dependencies")]
+pub async fn reverse_dependencies(
+    path: web::Path<CrateName>,
+    index: web::Data<IndexHandle>,
+    settings: web::Data<Settings>,
+) -> ApiResponse {
+    // 404 for crates we've never heard of.
+    get_package_versions(&index, &path.crate_name)?;
+
+    // The highest version of each dependent which isn't yanked. Dependents
+    // are sorted by name then version, so the last one seen for each name wins.
+    let mut latest: Vec<(PackageVersion, Dependency)> = vec![];
+    for dependent in index.get_dependents(&path.crate_name) {
+        let pkg = get_package_versions(&index, &dependent.name)?
+            .into_iter()
+            .find(|pkg| pkg.vers == dependent.vers)
+            .ok_or(ApiError::NotFound)?;
+        if pkg.yanked {
+            continue;
+        }
+        match latest.last_mut() {
+            Some(last) if last.0.name == pkg.name => *last = (pkg, dependent.dep),
+            _ => latest.push((pkg, dependent.dep)),
+        }
+    }
+
+    let metas = settings
+        .db
+        .get_crate_versions_for(latest.iter().map(|(pkg, _)| pkg.name.clone()).collect())
+        .await?;
+    let mut dependencies = vec![];
+    let mut versions = vec![];
+    for (id, (pkg, dep)) in latest.into_iter().enumerate() {
+        let version = ApiVersion::new(
+            &pkg,
+            metas
+                .get(&pkg.name)
+                .and_then(|metas| metas.iter().find(|m| m.vers == pkg.vers.to_string())),
+        );
+        dependencies.push(ApiDependency::new(id, version.id, dep));
+        versions.push(version);
+    }
+
+    Ok(HttpResponse::Ok().json(json!({
+        "dependencies": dependencies,
+        "versions": versions,
+        "meta": {
+            "total": dependencies.len()
+        }
+    })))
+}
+
 #[cfg(test)]
 mod tests {
     use crate::test_helpers;
+    use serde_json::json;
     use crate::test_helpers::MY_CRATE_0_1_0;
-    use actix_web::http::StatusCode;
+    use actix_web::http::{header, StatusCode};
     use actix_web::{test, App};
 
     #[actix_rt::test]
     async fn test_publish() {
         let data_root = test_helpers::get_data_root();
-        let settings = test_helpers::get_test_settings(&data_root.path());
+        let settings = test_helpers::get_test_settings(data_root.path()).await;
+        let api_key = test_helpers::get_test_api_key(&settings).await;
         let package_index = test_helpers::get_test_package_index(&settings.index_dir);
 
-        let mut app = test::init_service(
+        let app = test::init_service(
             App::new()
+                .wrap(crate::auth::AuthFactory::new(settings.db.clone()))
                 .app_data(settings.clone())
                 .app_data(package_index.clone())
                 .configure(crate::handlers::configure_routes),
@@ -249,21 +883,24 @@ mod tests {
 
         let req = test::TestRequest::put()
             .uri("/api/v1/crates/new")
+            .append_header((header::AUTHORIZATION, api_key.as_str()))
             .set_payload(MY_CRATE_0_1_0)
             .to_request();
 
-        let resp: serde_json::Value = test::call_and_read_body_json(&mut app, req).await;
+        let resp: serde_json::Value = test::call_and_read_body_json(&app, req).await;
         assert!(!resp.as_object().unwrap().contains_key("errors"));
     }
 
     #[actix_rt::test]
     async fn test_publish_twice_is_error() {
         let data_root = test_helpers::get_data_root();
-        let settings = test_helpers::get_test_settings(&data_root.path());
+        let settings = test_helpers::get_test_settings(data_root.path()).await;
+        let api_key = test_helpers::get_test_api_key(&settings).await;
         let package_index = test_helpers::get_test_package_index(&settings.index_dir);
 
-        let mut app = test::init_service(
+        let app = test::init_service(
             App::new()
+                .wrap(crate::auth::AuthFactory::new(settings.db.clone()))
                 .app_data(settings.clone())
                 .app_data(package_index.clone())
                 .configure(crate::handlers::configure_routes),
@@ -273,20 +910,22 @@ mod tests {
         // First publish
         let req = test::TestRequest::put()
             .uri("/api/v1/crates/new")
+            .append_header((header::AUTHORIZATION, api_key.as_str()))
             .set_payload(MY_CRATE_0_1_0)
             .to_request();
 
-        let resp: serde_json::Value = test::call_and_read_body_json(&mut app, req).await;
+        let resp: serde_json::Value = test::call_and_read_body_json(&app, req).await;
         // No errors the first time
         assert!(!resp.as_object().unwrap().contains_key("errors"));
 
         // Second publish
         let req = test::TestRequest::put()
             .uri("/api/v1/crates/new")
+            .append_header((header::AUTHORIZATION, api_key.as_str()))
             .set_payload(MY_CRATE_0_1_0)
             .to_request();
 
-        let resp: serde_json::Value = test::call_and_read_body_json(&mut app, req).await;
+        let resp: serde_json::Value = test::call_and_read_body_json(&app, req).await;
         // There should be errors in this case...
         assert!(resp.as_object().unwrap().contains_key("errors"));
     }
@@ -294,11 +933,13 @@ mod tests {
     #[actix_rt::test]
     async fn test_yank() {
         let data_root = test_helpers::get_data_root();
-        let settings = test_helpers::get_test_settings(&data_root.path());
+        let settings = test_helpers::get_test_settings(data_root.path()).await;
+        let api_key = test_helpers::get_test_api_key(&settings).await;
         let package_index = test_helpers::get_test_package_index(&settings.index_dir);
 
-        let mut app = test::init_service(
+        let app = test::init_service(
             App::new()
+                .wrap(crate::auth::AuthFactory::new(settings.db.clone()))
                 .app_data(settings.clone())
                 .app_data(package_index.clone())
                 .configure(crate::handlers::configure_routes),
@@ -308,27 +949,62 @@ mod tests {
         // Publish (so we can yank)
         let req = test::TestRequest::put()
             .uri("/api/v1/crates/new")
+            .append_header((header::AUTHORIZATION, api_key.as_str()))
             .set_payload(MY_CRATE_0_1_0)
             .to_request();
 
-        let _: serde_json::Value = test::call_and_read_body_json(&mut app, req).await;
+        let _: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+
+        let req = test::TestRequest::delete()
+            .uri("/api/v1/crates/my-crate/0.1.0/yank")
+            .append_header((header::AUTHORIZATION, api_key.as_str()))
+            .to_request();
+
+        let resp: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert!(resp["ok"].as_bool().unwrap());
+        assert!(settings
+            .db
+            .get_yanked_versions()
+            .await
+            .unwrap()
+            .contains(&(String::from("my-crate"), String::from("0.1.0"))));
 
+        // Yanking again is fine, but there's nothing new to record.
         let req = test::TestRequest::delete()
             .uri("/api/v1/crates/my-crate/0.1.0/yank")
+            .append_header((header::AUTHORIZATION, api_key.as_str()))
             .to_request();
 
-        let resp: serde_json::Value = test::call_and_read_body_json(&mut app, req).await;
+        let resp: serde_json::Value = test::call_and_read_body_json(&app, req).await;
         assert!(resp["ok"].as_bool().unwrap());
+        let entries = settings
+            .db
+            .get_audit_entries(crate::database::AuditQuery {
+                action: Some(crate::database::AuditAction::Yank),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(1, entries.len());
+
+        // A version that was never published can't be yanked.
+        let req = test::TestRequest::delete()
+            .uri("/api/v1/crates/my-crate/0.2.0/yank")
+            .append_header((header::AUTHORIZATION, api_key.as_str()))
+            .to_request();
+        assert_eq!(StatusCode::NOT_FOUND, test::call_service(&app, req).await.status());
     }
 
     #[actix_rt::test]
     async fn test_unyank() {
         let data_root = test_helpers::get_data_root();
-        let settings = test_helpers::get_test_settings(&data_root.path());
+        let settings = test_helpers::get_test_settings(data_root.path()).await;
+        let api_key = test_helpers::get_test_api_key(&settings).await;
         let package_index = test_helpers::get_test_package_index(&settings.index_dir);
 
-        let mut app = test::init_service(
+        let app = test::init_service(
             App::new()
+                .wrap(crate::auth::AuthFactory::new(settings.db.clone()))
                 .app_data(settings.clone())
                 .app_data(package_index.clone())
                 .configure(crate::handlers::configure_routes),
@@ -338,33 +1014,220 @@ mod tests {
         // Publish (so we can yank)
         let req = test::TestRequest::put()
             .uri("/api/v1/crates/new")
+            .append_header((header::AUTHORIZATION, api_key.as_str()))
             .set_payload(MY_CRATE_0_1_0)
             .to_request();
 
-        let _: serde_json::Value = test::call_and_read_body_json(&mut app, req).await;
+        let _: serde_json::Value = test::call_and_read_body_json(&app, req).await;
 
         let req = test::TestRequest::delete()
             .uri("/api/v1/crates/my-crate/0.1.0/yank")
+            .append_header((header::AUTHORIZATION, api_key.as_str()))
             .to_request();
 
-        let _: serde_json::Value = test::call_and_read_body_json(&mut app, req).await;
+        let _: serde_json::Value = test::call_and_read_body_json(&app, req).await;
 
         let req = test::TestRequest::put()
             .uri("/api/v1/crates/my-crate/0.1.0/unyank")
+            .append_header((header::AUTHORIZATION, api_key.as_str()))
+            .to_request();
+
+        let resp: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert!(resp["ok"].as_bool().unwrap());
+        assert!(settings.db.get_yanked_versions().await.unwrap().is_empty());
+
+        let req = test::TestRequest::put()
+            .uri("/api/v1/crates/no-such-crate/0.1.0/unyank")
+            .append_header((header::AUTHORIZATION, api_key.as_str()))
+            .to_request();
+        assert_eq!(StatusCode::NOT_FOUND, test::call_service(&app, req).await.status());
+    }
+
+    #[actix_rt::test]
+    async fn test_delete_version() {
+        let data_root = test_helpers::get_data_root();
+        let settings = test_helpers::get_test_settings(data_root.path()).await;
+        let api_key = test_helpers::get_test_api_key(&settings).await;
+        let package_index = test_helpers::get_test_package_index(&settings.index_dir);
+        let user = settings
+            .db
+            .create_user(String::from("bob"), String::from("bob"), crate::database::UserRole::Publisher)
+            .await
+            .unwrap();
+        let bob_key = settings
+            .db
+            .generate_api_key(String::from("bob's key"), &user)
+            .await
+            .unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .wrap(crate::auth::AuthFactory::new(settings.db.clone()))
+                .app_data(settings.clone())
+                .app_data(package_index.clone())
+                .configure(crate::handlers::configure_routes),
+        )
+        .await;
+
+        let req = test::TestRequest::put()
+            .uri("/api/v1/crates/new")
+            .append_header((header::AUTHORIZATION, api_key.as_str()))
+            .set_payload(MY_CRATE_0_1_0)
+            .to_request();
+        let _: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let crate_file = crate::storage::get_crate_file_path(
+            &settings.crate_dir,
+            "my-crate",
+            &"0.1.0".parse().unwrap(),
+        );
+        assert!(crate_file.exists());
+
+        // Only administrators may delete.
+        let req = test::TestRequest::delete()
+            .uri("/api/v1/crates/my-crate/0.1.0")
+            .append_header((header::AUTHORIZATION, bob_key.as_str()))
+            .to_request();
+        assert_eq!(StatusCode::FORBIDDEN, test::call_service(&app, req).await.status());
+
+        let req = test::TestRequest::delete()
+            .uri("/api/v1/crates/my-crate/0.1.0?block=true")
+            .append_header((header::AUTHORIZATION, api_key.as_str()))
+            .to_request();
+        let resp: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert!(resp["ok"].as_bool().unwrap());
+        assert!(!crate_file.exists());
+        assert!(package_index.get_package_versions("my-crate").is_err());
+        assert!(settings.db.get_crate_versions(String::from("my-crate")).await.unwrap().is_empty());
+
+        let entries = settings
+            .db
+            .get_audit_entries(crate::database::AuditQuery {
+                action: Some(crate::database::AuditAction::Delete),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(1, entries.len());
+        assert_eq!(Some("republishing blocked"), entries[0].detail.as_deref());
+
+        // It's gone, so deleting again is a 404.
+        let req = test::TestRequest::delete()
+            .uri("/api/v1/crates/my-crate/0.1.0")
+            .append_header((header::AUTHORIZATION, api_key.as_str()))
+            .to_request();
+        assert_eq!(StatusCode::NOT_FOUND, test::call_service(&app, req).await.status());
+
+        // And it can't come back.
+        let req = test::TestRequest::put()
+            .uri("/api/v1/crates/new")
+            .append_header((header::AUTHORIZATION, api_key.as_str()))
+            .set_payload(MY_CRATE_0_1_0)
+            .to_request();
+        let resp: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert!(resp.as_object().unwrap().contains_key("errors"));
+        assert!(package_index.get_package_versions("my-crate").is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_upload_docs() {
+        let data_root = test_helpers::get_data_root();
+        let settings = test_helpers::get_test_settings(data_root.path()).await;
+        let api_key = test_helpers::get_test_api_key(&settings).await;
+        let package_index = test_helpers::get_test_package_index(&settings.index_dir);
+
+        let app = test::init_service(
+            App::new()
+                .wrap(crate::auth::AuthFactory::new(settings.db.clone()))
+                .app_data(settings.clone())
+                .app_data(package_index.clone())
+                .configure(crate::handlers::configure_routes),
+        )
+        .await;
+
+        let req = test::TestRequest::put()
+            .uri("/api/v1/crates/new")
+            .append_header((header::AUTHORIZATION, api_key.as_str()))
+            .set_payload(MY_CRATE_0_1_0)
             .to_request();
+        let _: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+
+        // No docs yet.
+        let req = test::TestRequest::get().uri("/docs/my-crate/0.1.0/").to_request();
+        assert_eq!(StatusCode::NOT_FOUND, test::call_service(&app, req).await.status());
 
-        let resp: serde_json::Value = test::call_and_read_body_json(&mut app, req).await;
+        let req = test::TestRequest::put()
+            .uri("/api/v1/crates/my-crate/0.1.0/docs")
+            .append_header((header::AUTHORIZATION, api_key.as_str()))
+            .set_payload(crate::docs::tests::make_docs_tarball("doc/"))
+            .to_request();
+        let resp: serde_json::Value = test::call_and_read_body_json(&app, req).await;
         assert!(resp["ok"].as_bool().unwrap());
+
+        let req = test::TestRequest::get().uri("/docs/my-crate/0.1.0/").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::FOUND, resp.status());
+        assert_eq!(
+            "/docs/my-crate/0.1.0/my_crate/index.html",
+            resp.headers().get(header::LOCATION).unwrap()
+        );
+
+        let req = test::TestRequest::get()
+            .uri("/docs/my-crate/0.1.0/my_crate/fn.foo.html")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!("sandbox", resp.headers().get(header::CONTENT_SECURITY_POLICY).unwrap());
+        assert_eq!("nosniff", resp.headers().get(header::X_CONTENT_TYPE_OPTIONS).unwrap());
+        let body = test::read_body(resp).await;
+        assert_eq!(&b"<h1>foo</h1>"[..], &body[..]);
+
+        let req = test::TestRequest::get()
+            .uri("/docs/my-crate/0.1.0/../0.1.0.crate")
+            .to_request();
+        assert_eq!(StatusCode::NOT_FOUND, test::call_service(&app, req).await.status());
+
+        let req = test::TestRequest::get().uri("/crates/my-crate/0.1.0").to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        assert!(std::str::from_utf8(&body).unwrap().contains("Documentation"));
+
+        let req = test::TestRequest::put()
+            .uri("/api/v1/crates/my-crate/0.1.0/docs")
+            .append_header((header::AUTHORIZATION, api_key.as_str()))
+            .set_payload("not a tarball")
+            .to_request();
+        assert_eq!(StatusCode::BAD_REQUEST, test::call_service(&app, req).await.status());
+
+        let req = test::TestRequest::put()
+            .uri("/api/v1/crates/my-crate/0.2.0/docs")
+            .append_header((header::AUTHORIZATION, api_key.as_str()))
+            .set_payload(crate::docs::tests::make_docs_tarball(""))
+            .to_request();
+        assert_eq!(StatusCode::NOT_FOUND, test::call_service(&app, req).await.status());
+
+        // Only owners can upload docs.
+        let other = settings
+            .db
+            .create_user(String::from("other"), String::from("password"), crate::database::UserRole::Publisher)
+            .await
+            .unwrap();
+        let other_key = settings.db.generate_api_key(String::from("other"), &other).await.unwrap();
+        let req = test::TestRequest::put()
+            .uri("/api/v1/crates/my-crate/0.1.0/docs")
+            .append_header((header::AUTHORIZATION, other_key.as_str()))
+            .set_payload(crate::docs::tests::make_docs_tarball(""))
+            .to_request();
+        assert_eq!(StatusCode::FORBIDDEN, test::call_service(&app, req).await.status());
     }
 
     #[actix_rt::test]
     async fn test_download_existing_crate_is_ok() {
         let data_root = test_helpers::get_data_root();
-        let settings = test_helpers::get_test_settings(&data_root.path());
+        let settings = test_helpers::get_test_settings(data_root.path()).await;
+        let api_key = test_helpers::get_test_api_key(&settings).await;
         let package_index = test_helpers::get_test_package_index(&settings.index_dir);
 
-        let mut app = test::init_service(
+        let app = test::init_service(
             App::new()
+                .wrap(crate::auth::AuthFactory::new(settings.db.clone()))
                 .app_data(settings.clone())
                 .app_data(package_index.clone())
                 .configure(crate::handlers::configure_routes),
@@ -374,27 +1237,29 @@ mod tests {
         // Publish (so we can download)
         let req = test::TestRequest::put()
             .uri("/api/v1/crates/new")
+            .append_header((header::AUTHORIZATION, api_key.as_str()))
             .set_payload(MY_CRATE_0_1_0)
             .to_request();
 
-        let _: serde_json::Value = test::call_and_read_body_json(&mut app, req).await;
+        let _: serde_json::Value = test::call_and_read_body_json(&app, req).await;
 
         let req = test::TestRequest::get()
             .uri("/api/v1/crates/my-crate/0.1.0/download")
             .to_request();
 
-        let resp = test::call_service(&mut app, req).await;
+        let resp = test::call_service(&app, req).await;
         assert_eq!(StatusCode::OK, resp.status());
     }
 
     #[actix_rt::test]
     async fn test_download_nonexistent_crate_is_not_found() {
         let data_root = test_helpers::get_data_root();
-        let settings = test_helpers::get_test_settings(&data_root.path());
+        let settings = test_helpers::get_test_settings(data_root.path()).await;
         let package_index = test_helpers::get_test_package_index(&settings.index_dir);
 
-        let mut app = test::init_service(
+        let app = test::init_service(
             App::new()
+                .wrap(crate::auth::AuthFactory::new(settings.db.clone()))
                 .app_data(settings.clone())
                 .app_data(package_index.clone())
                 .configure(crate::handlers::configure_routes),
@@ -406,7 +1271,237 @@ mod tests {
             .uri("/api/v1/crates/my-crate/0.1.0/download")
             .to_request();
 
-        let resp = test::call_service(&mut app, req).await;
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::NOT_FOUND, resp.status());
+    }
+
+    #[actix_rt::test]
+    async fn test_crate_info_existing_crate() {
+        let data_root = test_helpers::get_data_root();
+        let settings = test_helpers::get_test_settings(data_root.path()).await;
+        let api_key = test_helpers::get_test_api_key(&settings).await;
+        let package_index = test_helpers::get_test_package_index(&settings.index_dir);
+
+        let app = test::init_service(
+            App::new()
+                .wrap(crate::auth::AuthFactory::new(settings.db.clone()))
+                .app_data(settings.clone())
+                .app_data(package_index.clone())
+                .configure(crate::handlers::configure_routes),
+        )
+        .await;
+
+        let req = test::TestRequest::put()
+            .uri("/api/v1/crates/new")
+            .append_header((header::AUTHORIZATION, api_key.as_str()))
+            .set_payload(MY_CRATE_0_1_0)
+            .to_request();
+
+        let _: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+
+        let req = test::TestRequest::get()
+            .uri("/api/v1/crates/my-crate")
+            .to_request();
+
+        let resp: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!("my-crate", resp["crate"]["name"]);
+        assert_eq!("0.1.0", resp["crate"]["max_version"]);
+        assert_eq!("0.1.0", resp["crate"]["newest_version"]);
+        assert_eq!(1, resp["versions"].as_array().unwrap().len());
+        assert_eq!("0.1.0", resp["versions"][0]["num"]);
+        assert_eq!("admin", resp["versions"][0]["published_by"]["login"]);
+        assert!(resp["versions"][0]["created_at"].is_string());
+    }
+
+    #[actix_rt::test]
+    async fn test_crate_info_nonexistent_crate_is_not_found() {
+        let data_root = test_helpers::get_data_root();
+        let settings = test_helpers::get_test_settings(data_root.path()).await;
+        let package_index = test_helpers::get_test_package_index(&settings.index_dir);
+
+        let app = test::init_service(
+            App::new()
+                .wrap(crate::auth::AuthFactory::new(settings.db.clone()))
+                .app_data(settings.clone())
+                .app_data(package_index.clone())
+                .configure(crate::handlers::configure_routes),
+        )
+        .await;
+
+        for uri in &[
+            "/api/v1/crates/my-crate",
+            "/api/v1/crates/my-crate/versions",
+            "/api/v1/crates/my-crate/0.1.0",
+            "/api/v1/crates/my-crate/0.1.0/dependencies",
+        ] {
+            let req = test::TestRequest::get().uri(uri).to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(StatusCode::NOT_FOUND, resp.status(), "{}", uri);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_crate_versions_and_version() {
+        let data_root = test_helpers::get_data_root();
+        let settings = test_helpers::get_test_settings(data_root.path()).await;
+        let api_key = test_helpers::get_test_api_key(&settings).await;
+        let package_index = test_helpers::get_test_package_index(&settings.index_dir);
+
+        let app = test::init_service(
+            App::new()
+                .wrap(crate::auth::AuthFactory::new(settings.db.clone()))
+                .app_data(settings.clone())
+                .app_data(package_index.clone())
+                .configure(crate::handlers::configure_routes),
+        )
+        .await;
+
+        let req = test::TestRequest::put()
+            .uri("/api/v1/crates/new")
+            .append_header((header::AUTHORIZATION, api_key.as_str()))
+            .set_payload(MY_CRATE_0_1_0)
+            .to_request();
+
+        let _: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+
+        let req = test::TestRequest::get()
+            .uri("/api/v1/crates/my-crate/versions")
+            .to_request();
+
+        let resp: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(1, resp["versions"].as_array().unwrap().len());
+        assert_eq!(
+            "/api/v1/crates/my-crate/0.1.0/download",
+            resp["versions"][0]["dl_path"]
+        );
+
+        let req = test::TestRequest::get()
+            .uri("/api/v1/crates/my-crate/0.1.0")
+            .to_request();
+
+        let resp: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!("my-crate", resp["version"]["crate"]);
+        assert_eq!("0.1.0", resp["version"]["num"]);
+        assert_eq!(false, resp["version"]["yanked"]);
+
+        let req = test::TestRequest::get()
+            .uri("/api/v1/crates/my-crate/0.2.0")
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
         assert_eq!(StatusCode::NOT_FOUND, resp.status());
     }
+
+    #[actix_rt::test]
+    async fn test_crate_version_dependencies() {
+        let data_root = test_helpers::get_data_root();
+        let settings = test_helpers::get_test_settings(data_root.path()).await;
+        let api_key = test_helpers::get_test_api_key(&settings).await;
+        let package_index = test_helpers::get_test_package_index(&settings.index_dir);
+
+        let app = test::init_service(
+            App::new()
+                .wrap(crate::auth::AuthFactory::new(settings.db.clone()))
+                .app_data(settings.clone())
+                .app_data(package_index.clone())
+                .configure(crate::handlers::configure_routes),
+        )
+        .await;
+
+        let req = test::TestRequest::put()
+            .uri("/api/v1/crates/new")
+            .append_header((header::AUTHORIZATION, api_key.as_str()))
+            .set_payload(MY_CRATE_0_1_0)
+            .to_request();
+
+        let _: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+
+        let req = test::TestRequest::get()
+            .uri("/api/v1/crates/my-crate/0.1.0/dependencies")
+            .to_request();
+
+        let resp: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let deps = resp["dependencies"].as_array().unwrap();
+        assert_eq!(
+            vec!["chrono", "serde", "uuid"],
+            deps.iter()
+                .map(|dep| dep["crate_id"].as_str().unwrap())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!("^0.4", deps[0]["req"]);
+        assert_eq!("build", deps[2]["kind"]);
+    }
+
+    #[actix_rt::test]
+    async fn test_reverse_dependencies() {
+        let data_root = test_helpers::get_data_root();
+        let settings = test_helpers::get_test_settings(data_root.path()).await;
+        let api_key = test_helpers::get_test_api_key(&settings).await;
+        let package_index = test_helpers::get_test_package_index(&settings.index_dir);
+
+        let app = test::init_service(
+            App::new()
+                .wrap(crate::auth::AuthFactory::new(settings.db.clone()))
+                .app_data(settings.clone())
+                .app_data(package_index.clone())
+                .configure(crate::handlers::configure_routes),
+        )
+        .await;
+
+        let on_foo = json!([{
+            "name": "foo",
+            "version_req": "^0.1",
+            "features": [],
+            "optional": false,
+            "default_features": true,
+            "target": null,
+            "kind": "normal",
+            "registry": null,
+        }]);
+        let foo = test_helpers::make_publish_metadata("foo", "0.1.0", json!([]));
+        let bar = test_helpers::make_publish_metadata("bar", "0.1.0", on_foo.clone());
+        let bar_2 = test_helpers::make_publish_metadata("bar", "0.2.0", on_foo.clone());
+        let baz = test_helpers::make_publish_metadata("baz", "0.1.0", on_foo);
+
+        for metadata in &[foo, bar, bar_2, baz] {
+            let req = test::TestRequest::put()
+                .uri("/api/v1/crates/new")
+                .append_header((header::AUTHORIZATION, api_key.as_str()))
+                .set_payload(test_helpers::make_publish_body(metadata, b"crate"))
+                .to_request();
+
+            let resp: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+            assert!(!resp.as_object().unwrap().contains_key("errors"));
+        }
+
+        // Yanked dependents don't count, an older version still can.
+        for uri in &[
+            "/api/v1/crates/bar/0.2.0/yank",
+            "/api/v1/crates/baz/0.1.0/yank",
+        ] {
+            let req = test::TestRequest::delete()
+                .uri(uri)
+                .append_header((header::AUTHORIZATION, api_key.as_str()))
+                .to_request();
+            assert!(test::call_service(&app, req).await.status().is_success());
+        }
+
+        let req = test::TestRequest::get()
+            .uri("/api/v1/crates/foo/reverse_dependencies")
+            .to_request();
+
+        let resp: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(1, resp["meta"]["total"]);
+        assert_eq!("foo", resp["dependencies"][0]["crate_id"]);
+        assert_eq!("bar", resp["versions"][0]["crate"]);
+        assert_eq!("0.1.0", resp["versions"][0]["num"]);
+        assert_eq!("admin", resp["versions"][0]["published_by"]["login"]);
+
+        let req = test::TestRequest::get()
+            .uri("/api/v1/crates/bar/reverse_dependencies")
+            .to_request();
+
+        let resp: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(0, resp["meta"]["total"]);
+    }
 }
@@ -0,0 +1,883 @@
+//! Importing crates from another registry, or from a directory of `.crate`
+//! files.
+//!
+//! With another registry's index, every version in it is imported along with
+//! its yanked flag, in the order its history says they were published. The
+//! `.crate` files are downloaded from the registry (anything cargo accepts as
+//! `dl` works, and local paths too) or picked out of a directory.
+//!
+//! Without an index, each `.crate` file's index entry is worked out from its
+//! manifest, as [`reindex`](crate::reindex) does, and versions are imported in
+//! version order.
+//!
+//! Versions already in the registry are left alone, and one which an earlier
+//! import only got as far as the index with is finished off, so an import
+//! which was cut short can be run again.
+
+use crate::crate_source;
+use crate::database::{AuditAction, Database, NewAuditEntry, NewCrateVersion, User};
+use crate::errors::{EstuaryError, PackageIndexError};
+use crate::package_index::{Config, PackageIndex, PackageVersion};
+use crate::reindex;
+use crate::storage::{self, invalid, StoredCrate};
+use git2::{Repository, Sort, TreeWalkMode, TreeWalkResult};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Where to import from.
+#[derive(Debug, Default)]
+pub struct Source {
+    /// The git repo of a cargo-format index, as a path or url.
+    pub index: Option<String>,
+    /// Where the index's `.crate` files are downloaded from, in the format of
+    /// the `dl` field of its `config.json`. Defaults to the index's own.
+    pub dl: Option<String>,
+    /// A directory of `.crate` files, used in place of downloading them.
+    pub crate_dir: Option<PathBuf>,
+}
+
+/// What an import did.
+#[derive(Debug, Default)]
+pub struct Summary {
+    /// Versions added to the registry.
+    pub imported: usize,
+    /// Of those, the ones marked as yanked.
+    pub yanked: usize,
+    /// Versions the registry already had.
+    pub existing: usize,
+    /// Versions which couldn't be imported, and why.
+    pub skipped: Vec<(String, String)>,
+}
+
+/// A version to import, and where its `.crate` file is.
+struct Candidate {
+    /// The index entry, if it came from an index. Otherwise it's worked out
+    /// from the `.crate` file.
+    pkg: Option<PackageVersion>,
+    name: String,
+    vers: semver::Version,
+    crate_file: CrateFile,
+}
+
+enum CrateFile {
+    Stored(StoredCrate),
+    Download(String),
+    /// Not in the directory of `.crate` files given.
+    Missing,
+}
+
+/// Import everything from `source` into the registry.
+///
+/// New crates are owned by `owner`, who is also recorded as having published
+/// every imported version.
+pub async fn import(
+    source: &Source,
+    index_dir: &Path,
+    crate_dir: &Path,
+    config: &Config,
+    db: &dyn Database,
+    owner: &str,
+) -> Result<Summary, EstuaryError> {
+    let owner = db
+        .get_user(owner.to_string())
+        .await?
+        .ok_or_else(|| invalid(format!("there's no user called `{}`", owner)))?;
+
+    let stored = match &source.crate_dir {
+        Some(dir) => {
+            let mut found = vec![];
+            find_crate_files(dir, &mut found)?;
+            found.sort_by(|a, b| a.path.cmp(&b.path));
+            found
+        }
+        None => vec![],
+    };
+    let candidates = match &source.index {
+        Some(url) => {
+            let (versions, dl) = read_source_index(url, index_dir)?;
+            let dl = source.dl.clone().or(dl);
+            let mut stored: HashMap<(String, String), StoredCrate> = stored
+                .into_iter()
+                .map(|krate| ((krate.name.clone(), krate.vers.clone()), krate))
+                .collect();
+            let mut candidates = vec![];
+            for pkg in versions {
+                let crate_file = match stored.remove(&(pkg.name.clone(), pkg.vers.to_string())) {
+                    Some(krate) => CrateFile::Stored(krate),
+                    None if source.crate_dir.is_some() => CrateFile::Missing,
+                    None => match &dl {
+                        Some(dl) => CrateFile::Download(download_url(dl, &pkg)),
+                        None => {
+                            return Err(invalid(String::from(
+                                "the index has no download location, give one with `--dl`",
+                            ))
+                            .into())
+                        }
+                    },
+                };
+                candidates.push(Candidate {
+                    name: pkg.name.clone(),
+                    vers: pkg.vers.clone(),
+                    pkg: Some(pkg),
+                    crate_file,
+                });
+            }
+            candidates
+        }
+        None => {
+            let mut candidates = vec![];
+            for krate in stored {
+                match krate.vers.parse() {
+                    Ok(vers) => candidates.push(Candidate {
+                        pkg: None,
+                        name: krate.name.clone(),
+                        vers,
+                        crate_file: CrateFile::Stored(krate),
+                    }),
+                    Err(e) => {
+                        log::warn!("Skipping `{}`: {}", krate.path.display(), e);
+                    }
+                }
+            }
+            candidates.sort_by(|a, b| (&a.name, &a.vers).cmp(&(&b.name, &b.vers)));
+            candidates
+        }
+    };
+
+    let index = PackageIndex::init(index_dir, config)?;
+    let in_db: HashSet<(String, String)> = db.list_crate_versions().await?.into_iter().collect();
+    let mut summary = Summary::default();
+    for candidate in candidates {
+        let label = format!("{} v{}", candidate.name, candidate.vers);
+        let indexed = index
+            .get_package_versions(&candidate.name)
+            .ok()
+            .and_then(|versions| versions.into_iter().find(|v| v.vers == candidate.vers));
+        match (
+            &indexed,
+            in_db.contains(&(candidate.name.clone(), candidate.vers.to_string())),
+        ) {
+            (Some(_), true) => {
+                summary.existing += 1;
+                continue;
+            }
+            (None, true) => {
+                summary.skipped.push((
+                    label,
+                    String::from("it's in the database but not the index, see `estuary fsck`"),
+                ));
+                continue;
+            }
+            // Either new, or an earlier import was cut short after adding it
+            // to the index, and it still needs the rest.
+            (_, false) => {}
+        }
+        if db
+            .is_version_blocked(candidate.name.clone(), candidate.vers.to_string())
+            .await?
+        {
+            summary
+                .skipped
+                .push((label, String::from("it was deleted and is blocked")));
+            continue;
+        }
+
+        match import_version(candidate, indexed, &index, crate_dir, db, &owner).await? {
+            Ok(yanked) => {
+                summary.imported += 1;
+                if yanked {
+                    summary.yanked += 1;
+                }
+            }
+            Err(reason) => summary.skipped.push((label, reason)),
+        }
+    }
+    Ok(summary)
+}
+
+/// Add one version to the index, crate storage and database, in that order,
+/// as a publish would.
+///
+/// `indexed` is the registry's own entry for a version an earlier import
+/// added to the index without getting any further. The entry is kept, and
+/// the `.crate` file is only fetched again if it didn't make it into storage
+/// intact.
+///
+/// Problems with the version itself are returned as the inner error, so the
+/// rest of the import can go ahead.
+async fn import_version(
+    candidate: Candidate,
+    indexed: Option<PackageVersion>,
+    index: &PackageIndex,
+    crate_dir: &Path,
+    db: &dyn Database,
+    owner: &User,
+) -> Result<Result<bool, String>, EstuaryError> {
+    let resumed = indexed.is_some();
+    let path = storage::get_crate_file_path(crate_dir, &candidate.name, &candidate.vers);
+    let already_stored = indexed.as_ref().and_then(|pkg| {
+        std::fs::read(&path)
+            .ok()
+            .filter(|bytes| format!("{:x}", Sha256::digest(bytes)) == pkg.cksum)
+    });
+    let needs_storing = already_stored.is_none();
+    let bytes = match already_stored {
+        Some(bytes) => bytes,
+        None => match &candidate.crate_file {
+            CrateFile::Stored(krate) => std::fs::read(&krate.path)?,
+            CrateFile::Download(url) => match download(url).await {
+                Ok(bytes) => bytes,
+                Err(e) => return Ok(Err(format!("couldn't get `{}`: {}", url, e))),
+            },
+            CrateFile::Missing => return Ok(Err(String::from("there's no .crate file for it"))),
+        },
+    };
+    let pkg = match indexed.or(candidate.pkg) {
+        Some(pkg) => {
+            let cksum = format!("{:x}", Sha256::digest(&bytes));
+            if cksum != pkg.cksum {
+                return Ok(Err(format!(
+                    "the .crate file has checksum {}, the index expects {}",
+                    cksum, pkg.cksum
+                )));
+            }
+            pkg
+        }
+        None => match &candidate.crate_file {
+            CrateFile::Stored(krate) => match reindex::index_entry(krate, &candidate.vers) {
+                Ok(pkg) => pkg,
+                Err(e) => return Ok(Err(e.to_string())),
+            },
+            _ => unreachable!("only index entries are downloaded"),
+        },
+    };
+
+    if !resumed {
+        index.publish(&pkg, Some(&owner.name))?;
+    }
+    if needs_storing {
+        storage::store_crate_file(crate_dir, &pkg.name, &pkg.vers, &bytes)?;
+    }
+
+    let manifest = match crate_source::read_manifest(&path, &pkg.name, &pkg.vers) {
+        Ok(manifest) => Some(manifest),
+        Err(e) => {
+            log::warn!(
+                "Couldn't read the manifest of `{} v{}`, it'll have no description: {}",
+                pkg.name,
+                pkg.vers,
+                e
+            );
+            None
+        }
+    };
+    let entry = NewAuditEntry {
+        uid: Some(owner.id),
+        action: AuditAction::Publish,
+        crate_name: Some(pkg.name.clone()),
+        vers: Some(pkg.vers.to_string()),
+        detail: Some(String::from("imported")),
+        ip: None,
+    };
+    let krate = match db.get_crate(pkg.name.clone()).await? {
+        Some(krate) => krate,
+        None => {
+            let krate = db.create_crate(pkg.name.clone()).await?;
+            db.add_crate_owner(krate.id, owner.id).await?;
+            db.add_audit_entry(NewAuditEntry {
+                action: AuditAction::OwnerAdd,
+                vers: None,
+                detail: Some(owner.name.clone()),
+                ..entry.clone()
+            })
+            .await?;
+            krate
+        }
+    };
+    db.add_crate_version(
+        krate.id,
+        owner.id,
+        version_metadata(manifest.as_ref(), &pkg.vers, bytes.len()),
+    )
+    .await?;
+    db.add_audit_entry(entry.clone()).await?;
+    if pkg.yanked {
+        db.set_yanked(pkg.name.clone(), pkg.vers.to_string(), true)
+            .await?;
+        db.add_audit_entry(NewAuditEntry {
+            action: AuditAction::Yank,
+            ..entry
+        })
+        .await?;
+    }
+    Ok(Ok(pkg.yanked))
+}
+
+/// Find every `{name}-{version}.crate` file under `dir`.
+///
+/// Both names and versions can have `-` in them, so the name is taken to end
+/// at the first `-` which is followed by a valid version.
+fn find_crate_files(dir: &Path, acc: &mut Vec<StoredCrate>) -> io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            find_crate_files(&path, acc)?;
+            continue;
+        }
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        let stem = match file_name.strip_suffix(".crate") {
+            Some(stem) => stem,
+            None => continue,
+        };
+        let split = stem
+            .match_indices('-')
+            .map(|(i, _)| (&stem[..i], &stem[i + 1..]))
+            .find(|(_, vers)| vers.parse::<semver::Version>().is_ok());
+        match split {
+            Some((name, vers)) => acc.push(StoredCrate {
+                name: name.to_string(),
+                vers: vers.to_string(),
+                path,
+            }),
+            None => log::warn!(
+                "Skipping `{}`, it has no version in its name.",
+                path.display()
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// Clone the index at `url` next to `index_dir` and read every version out of
+/// it, in the order they were published, along with its `dl`.
+fn read_source_index(
+    url: &str,
+    index_dir: &Path,
+) -> Result<(Vec<PackageVersion>, Option<String>), EstuaryError> {
+    let clone_dir = storage::sibling(index_dir, "import");
+    match std::fs::remove_dir_all(&clone_dir) {
+        Ok(()) => log::warn!(
+            "Removed `{}` left by an earlier import.",
+            clone_dir.display()
+        ),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    let read = git2::build::RepoBuilder::new()
+        .bare(true)
+        .clone(url, &clone_dir)
+        .map_err(PackageIndexError::from)
+        .and_then(|repo| read_versions(&repo));
+    std::fs::remove_dir_all(&clone_dir)?;
+    Ok(read?)
+}
+
+fn read_versions(
+    repo: &Repository,
+) -> Result<(Vec<PackageVersion>, Option<String>), PackageIndexError> {
+    let tree = repo.head()?.peel_to_tree()?;
+    let mut files = vec![];
+    tree.walk(TreeWalkMode::PreOrder, |dir, entry| {
+        let name = entry.name().unwrap_or_default();
+        if name.starts_with('.') {
+            return TreeWalkResult::Skip;
+        }
+        if entry.kind() == Some(git2::ObjectType::Blob)
+            && !(dir.is_empty() && name == "config.json")
+        {
+            files.push((format!("{}{}", dir, name), entry.id()));
+        }
+        TreeWalkResult::Ok
+    })?;
+
+    let mut versions = vec![];
+    for (path, id) in files {
+        let blob = repo.find_blob(id)?;
+        let contents = String::from_utf8_lossy(blob.content());
+        for (i, line) in contents.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str(line) {
+                Ok(pkg) => versions.push(pkg),
+                Err(e) => log::warn!("Skipping line {} of `{}`: {}", i + 1, path, e),
+            }
+        }
+    }
+
+    let order = publish_order(repo)?;
+    // Anything the history doesn't account for goes last, as it is in the
+    // package files.
+    let mut versions: Vec<_> = versions.into_iter().enumerate().collect();
+    versions.sort_by_key(|(i, pkg): &(usize, PackageVersion)| {
+        let published = order.get(&(pkg.name.clone(), pkg.vers.to_string()));
+        (published.is_none(), published.copied(), *i)
+    });
+    let versions = versions.into_iter().map(|(_, pkg)| pkg).collect();
+
+    let dl = tree
+        .get_name("config.json")
+        .and_then(|entry| entry.to_object(repo).ok())
+        .and_then(|object| object.peel_to_blob().ok())
+        .and_then(|blob| serde_json::from_slice::<Config>(blob.content()).ok())
+        .map(|config| config.dl);
+    Ok((versions, dl))
+}
+
+/// The order in which versions were added to the index, going by the lines
+/// each commit added.
+fn publish_order(repo: &Repository) -> Result<HashMap<(String, String), usize>, PackageIndexError> {
+    #[derive(serde::Deserialize)]
+    struct Key {
+        name: String,
+        vers: String,
+    }
+
+    let mut walk = repo.revwalk()?;
+    walk.push_head()?;
+    walk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)?;
+
+    let mut order = HashMap::new();
+    for id in walk {
+        let commit = repo.find_commit(id?)?;
+        let parent = match commit.parents().next() {
+            Some(parent) => Some(parent.tree()?),
+            None => None,
+        };
+        let diff = repo.diff_tree_to_tree(parent.as_ref(), Some(&commit.tree()?), None)?;
+        diff.foreach(
+            &mut |_, _| true,
+            None,
+            None,
+            Some(&mut |_, _, line| {
+                if line.origin() == '+' {
+                    if let Ok(key) = serde_json::from_slice::<Key>(line.content()) {
+                        let next = order.len();
+                        order.entry((key.name, key.vers)).or_insert(next);
+                    }
+                }
+                true
+            }),
+        )?;
+    }
+    Ok(order)
+}
+
+/// Fill in a `dl` template for a version, the way cargo does.
+fn download_url(dl: &str, pkg: &PackageVersion) -> String {
+    const MARKERS: &[&str] = &[
+        "{crate}",
+        "{version}",
+        "{prefix}",
+        "{lowerprefix}",
+        "{sha256-checksum}",
+    ];
+    if !MARKERS.iter().any(|marker| dl.contains(marker)) {
+        return format!(
+            "{}/{}/{}/download",
+            dl.trim_end_matches('/'),
+            pkg.name,
+            pkg.vers
+        );
+    }
+    let prefix = match pkg.name.len() {
+        1 => String::from("1"),
+        2 => String::from("2"),
+        3 => format!("3/{}", &pkg.name[..1]),
+        _ => format!("{}/{}", &pkg.name[..2], &pkg.name[2..4]),
+    };
+    dl.replace("{crate}", &pkg.name)
+        .replace("{version}", &pkg.vers.to_string())
+        .replace("{lowerprefix}", &prefix.to_lowercase())
+        .replace("{prefix}", &prefix)
+        .replace("{sha256-checksum}", &pkg.cksum)
+}
+
+/// Fetch a `.crate` file over http, or read it from disk for `file://` urls
+/// and plain paths.
+async fn download(url: &str) -> Result<Vec<u8>, String> {
+    if url.starts_with("http://") || url.starts_with("https://") {
+        let resp = reqwest::get(url).await.map_err(|e| e.to_string())?;
+        let resp = resp.error_for_status().map_err(|e| e.to_string())?;
+        let bytes = resp.bytes().await.map_err(|e| e.to_string())?;
+        Ok(bytes.to_vec())
+    } else {
+        let path = url.strip_prefix("file://").unwrap_or(url);
+        std::fs::read(path).map_err(|e| e.to_string())
+    }
+}
+
+/// What the database records about a version, from its manifest.
+fn version_metadata(
+    manifest: Option<&toml::Value>,
+    vers: &semver::Version,
+    size: usize,
+) -> NewCrateVersion {
+    let package = manifest.and_then(|manifest| manifest.get("package"));
+    let str_field = |key: &str| {
+        package
+            .and_then(|package| package.get(key))
+            .and_then(|v| v.as_str())
+            .map(String::from)
+    };
+    let list_field = |key: &str| {
+        package
+            .and_then(|package| package.get(key))
+            .and_then(|v| v.as_array())
+            .map(|values| {
+                values
+                    .iter()
+                    .filter_map(|v| v.as_str().map(String::from))
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+    NewCrateVersion {
+        vers: vers.to_string(),
+        description: str_field("description"),
+        documentation: str_field("documentation"),
+        homepage: str_field("homepage"),
+        repository: str_field("repository"),
+        license: str_field("license"),
+        keywords: list_field("keywords"),
+        categories: list_field("categories"),
+        authors: list_field("authors"),
+        crate_size: size as i64,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers;
+
+    fn manifest(name: &str, vers: &str) -> String {
+        format!(
+            "[package]\nname = \"{}\"\nversion = \"{}\"\ndescription = \"The {} crate\"\nkeywords = [\"test\"]\n",
+            name, vers, name
+        )
+    }
+
+    fn make_crate(name: &str, vers: &str) -> Vec<u8> {
+        test_helpers::make_crate_file(
+            name,
+            vers,
+            &[("Cargo.toml", manifest(name, vers).as_bytes())],
+        )
+    }
+
+    fn pkg(name: &str, vers: &str, crate_file: &[u8]) -> PackageVersion {
+        PackageVersion {
+            cksum: format!("{:x}", Sha256::digest(crate_file)),
+            ..test_helpers::pkg(name, vers)
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_import_index() {
+        let data_root = test_helpers::get_data_root();
+        let settings = test_helpers::get_test_settings(data_root.path()).await;
+        let db = settings.db.as_ref();
+        let config = Config {
+            dl: String::new(),
+            api: String::new(),
+        };
+
+        // The other registry, serving crates from a directory.
+        let downloads = data_root.path().join("downloads");
+        std::fs::create_dir_all(&downloads).unwrap();
+        let source = PackageIndex::init(
+            data_root.path().join("source"),
+            &Config {
+                dl: format!("file://{}/{{crate}}-{{version}}.crate", downloads.display()),
+                api: String::new(),
+            },
+        )
+        .unwrap();
+        for (name, vers) in [
+            ("serde", "1.0.0"),
+            ("anyhow", "1.0.0"),
+            ("serde", "1.0.1"),
+            ("broken", "0.1.0"),
+        ] {
+            let crate_file = make_crate(name, vers);
+            std::fs::write(
+                downloads.join(format!("{}-{}.crate", name, vers)),
+                &crate_file,
+            )
+            .unwrap();
+            let mut pkg = pkg(name, vers, &crate_file);
+            if name == "anyhow" {
+                // Written by a newer cargo than estuary knows about.
+                pkg.extra = serde_json::json!({
+                    "features2": { "std": ["dep:backtrace", "serde?/std"] },
+                    "v": 2,
+                })
+                .as_object()
+                .unwrap()
+                .clone();
+            }
+            source.publish(&pkg, None).unwrap();
+        }
+        source
+            .set_yanked("serde", &"1.0.0".parse().unwrap(), true, None)
+            .unwrap();
+        std::fs::write(downloads.join("broken-0.1.0.crate"), b"changed").unwrap();
+
+        let from = Source {
+            index: Some(data_root.path().join("source").display().to_string()),
+            ..Default::default()
+        };
+        let summary = import(
+            &from,
+            &settings.index_dir,
+            &settings.crate_dir,
+            &config,
+            db,
+            "admin",
+        )
+        .await
+        .unwrap();
+        assert_eq!(3, summary.imported);
+        assert_eq!(1, summary.yanked);
+        assert_eq!(1, summary.skipped.len());
+        assert_eq!("broken v0.1.0", summary.skipped[0].0);
+        assert!(!settings.index_dir.with_file_name("index.import").exists());
+
+        // Publish order is kept, across crates as well.
+        assert_eq!(
+            vec![
+                (String::from("serde"), String::from("1.0.0")),
+                (String::from("anyhow"), String::from("1.0.0")),
+                (String::from("serde"), String::from("1.0.1")),
+            ],
+            db.list_crate_versions().await.unwrap()
+        );
+        let index = PackageIndex::init(&settings.index_dir, &config).unwrap();
+        let serde = index.get_package_versions("serde").unwrap();
+        assert!(serde[0].yanked);
+        assert!(!serde[1].yanked);
+        let anyhow = &index.get_package_versions("anyhow").unwrap()[0];
+        assert_eq!(2, anyhow.extra["v"]);
+        assert_eq!(
+            serde_json::json!(["dep:backtrace", "serde?/std"]),
+            anyhow.extra["features2"]["std"]
+        );
+        assert!(db
+            .get_yanked_versions()
+            .await
+            .unwrap()
+            .contains(&(String::from("serde"), String::from("1.0.0"))));
+        assert!(storage::get_crate_file_path(
+            &settings.crate_dir,
+            "anyhow",
+            &"1.0.0".parse().unwrap()
+        )
+        .exists());
+        let versions = db.get_crate_versions(String::from("anyhow")).await.unwrap();
+        assert_eq!(Some("The anyhow crate"), versions[0].description.as_deref());
+        assert_eq!(vec!["test"], versions[0].keyword_list());
+        assert_eq!(
+            vec!["admin"],
+            db.get_crate_owners(String::from("anyhow"))
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|user| user.name)
+                .collect::<Vec<_>>()
+        );
+        drop(index);
+
+        // Running it again only picks up what was missed.
+        std::fs::write(
+            downloads.join("broken-0.1.0.crate"),
+            make_crate("broken", "0.1.0"),
+        )
+        .unwrap();
+        let summary = import(
+            &from,
+            &settings.index_dir,
+            &settings.crate_dir,
+            &config,
+            db,
+            "admin",
+        )
+        .await
+        .unwrap();
+        assert_eq!(1, summary.imported);
+        assert_eq!(3, summary.existing);
+    }
+
+    #[actix_rt::test]
+    async fn test_resume_import() {
+        let data_root = test_helpers::get_data_root();
+        let settings = test_helpers::get_test_settings(data_root.path()).await;
+        let db = settings.db.as_ref();
+        let config = Config {
+            dl: String::new(),
+            api: String::new(),
+        };
+
+        let downloads = data_root.path().join("downloads");
+        std::fs::create_dir_all(&downloads).unwrap();
+        let source = PackageIndex::init(
+            data_root.path().join("source"),
+            &Config {
+                dl: format!("file://{}/{{crate}}-{{version}}.crate", downloads.display()),
+                api: String::new(),
+            },
+        )
+        .unwrap();
+        let index = PackageIndex::init(&settings.index_dir, &config).unwrap();
+        for (vers, yanked) in [("1.0.0", true), ("1.0.1", false)] {
+            let crate_file = make_crate("serde", vers);
+            std::fs::write(downloads.join(format!("serde-{}.crate", vers)), &crate_file).unwrap();
+            let pkg = PackageVersion {
+                yanked,
+                ..pkg("serde", vers, &crate_file)
+            };
+            source.publish(&pkg, None).unwrap();
+
+            // An earlier import got this far: 1.0.0 into the index, and 1.0.1
+            // into crate storage as well, but neither into the database.
+            index.publish(&pkg, Some("admin")).unwrap();
+            if vers == "1.0.1" {
+                storage::store_crate_file(&settings.crate_dir, "serde", &pkg.vers, &crate_file)
+                    .unwrap();
+            }
+        }
+        drop(index);
+
+        let from = Source {
+            index: Some(data_root.path().join("source").display().to_string()),
+            ..Default::default()
+        };
+        let summary = import(
+            &from,
+            &settings.index_dir,
+            &settings.crate_dir,
+            &config,
+            db,
+            "admin",
+        )
+        .await
+        .unwrap();
+        assert_eq!(2, summary.imported);
+        assert_eq!(1, summary.yanked);
+        assert_eq!(0, summary.existing);
+        assert!(summary.skipped.is_empty());
+
+        assert_eq!(
+            vec![
+                (String::from("serde"), String::from("1.0.0")),
+                (String::from("serde"), String::from("1.0.1")),
+            ],
+            db.list_crate_versions().await.unwrap()
+        );
+        assert!(db
+            .get_yanked_versions()
+            .await
+            .unwrap()
+            .contains(&(String::from("serde"), String::from("1.0.0"))));
+        assert!(storage::get_crate_file_path(
+            &settings.crate_dir,
+            "serde",
+            &"1.0.0".parse().unwrap()
+        )
+        .exists());
+        assert_eq!(
+            vec!["admin"],
+            db.get_crate_owners(String::from("serde"))
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|user| user.name)
+                .collect::<Vec<_>>()
+        );
+        let mut actions: Vec<_> = db
+            .get_audit_entries(crate::database::AuditQuery {
+                crate_name: Some(String::from("serde")),
+                ..Default::default()
+            })
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.action)
+            .collect();
+        actions.sort();
+        assert_eq!(vec!["owner_add", "publish", "publish", "yank"], actions);
+
+        // The index was left as it was, not published to twice.
+        let index = PackageIndex::init(&settings.index_dir, &config).unwrap();
+        assert_eq!(2, index.get_package_versions("serde").unwrap().len());
+    }
+
+    #[actix_rt::test]
+    async fn test_import_crate_dir() {
+        let data_root = test_helpers::get_data_root();
+        let settings = test_helpers::get_test_settings(data_root.path()).await;
+        let db = settings.db.as_ref();
+        let config = Config {
+            dl: String::new(),
+            api: String::new(),
+        };
+
+        let dir = data_root.path().join("crates-to-import");
+        std::fs::create_dir_all(&dir).unwrap();
+        for (name, vers) in [
+            ("foo", "0.2.0"),
+            ("foo", "0.10.0"),
+            ("foo-bar", "1.0.0-beta.1"),
+        ] {
+            std::fs::write(
+                dir.join(format!("{}-{}.crate", name, vers)),
+                make_crate(name, vers),
+            )
+            .unwrap();
+        }
+        std::fs::write(dir.join("baz-1.0.0.crate"), make_crate("qux", "1.0.0")).unwrap();
+
+        let from = Source {
+            crate_dir: Some(dir),
+            ..Default::default()
+        };
+        let summary = import(
+            &from,
+            &settings.index_dir,
+            &settings.crate_dir,
+            &config,
+            db,
+            "admin",
+        )
+        .await
+        .unwrap();
+        assert_eq!(3, summary.imported);
+        assert_eq!(1, summary.skipped.len());
+        assert_eq!("baz v1.0.0", summary.skipped[0].0);
+
+        let index = PackageIndex::init(&settings.index_dir, &config).unwrap();
+        let foo: Vec<_> = index
+            .get_package_versions("foo")
+            .unwrap()
+            .iter()
+            .map(|v| v.vers.to_string())
+            .collect();
+        assert_eq!(vec!["0.2.0", "0.10.0"], foo);
+        assert!(index.get_package_versions("foo-bar").is_ok());
+
+        // An unknown owner is refused before anything is imported.
+        assert!(import(
+            &from,
+            &settings.index_dir,
+            &settings.crate_dir,
+            &config,
+            db,
+            "nobody",
+        )
+        .await
+        .is_err());
+    }
+}
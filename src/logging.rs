@@ -0,0 +1,306 @@
+//! Log output, and tying log lines to the request they were written for.
+//!
+//! Every request gets an id, either the one the client (or a proxy) sent in
+//! the `X-Request-Id` header or a freshly generated one. The id is sent back
+//! in the same header, shown in error responses, and added to every line
+//! logged while handling the request.
+//!
+//! Logs are written as text by default, or as one json object per line with
+//! `--log-format json`.
+
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use actix_web::HttpMessage;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use futures::future::{ready, LocalBoxFuture, Ready};
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::future::Future;
+use std::io::Write;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Ids sent by clients longer than this are replaced.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Headers whose values never make it into the logs.
+const REDACTED_HEADERS: &[HeaderName] = &[header::AUTHORIZATION, header::COOKIE];
+
+thread_local! {
+    /// The id of the request being handled on this thread, if any.
+    static REQUEST_ID: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// The id of the request currently being handled.
+///
+/// Only known on the thread handling the request, so not in `web::block`
+/// closures or spawned tasks.
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.with(|id| id.borrow().clone())
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("`{}` isn't a log format, use `text` or `json`", s)),
+        }
+    }
+}
+
+/// Set up logging, filtered by `RUST_LOG` as usual.
+pub fn init(format: LogFormat) {
+    env_logger::Builder::from_default_env()
+        .format(move |buf, record| {
+            let request_id = current_request_id();
+            match format {
+                LogFormat::Json => {
+                    let line = serde_json::json!({
+                        "timestamp": chrono::Utc::now().to_rfc3339(),
+                        "level": record.level().as_str(),
+                        "target": record.target(),
+                        "message": record.args().to_string(),
+                        "request_id": request_id,
+                    });
+                    writeln!(buf, "{}", line)
+                }
+                LogFormat::Text => {
+                    write!(
+                        buf,
+                        "[{} {:<5} {}",
+                        buf.timestamp(),
+                        record.level(),
+                        record.target()
+                    )?;
+                    if let Some(id) = request_id {
+                        write!(buf, " request_id={}", id)?;
+                    }
+                    writeln!(buf, "] {}", record.args())
+                }
+            }
+        })
+        .init();
+}
+
+/// The json body of an error response, tagged with the request id so the
+/// error can be found in the logs.
+pub fn error_body(detail: &str) -> serde_json::Value {
+    serde_json::json!({
+        "errors": [{ "detail": detail }],
+        "request_id": current_request_id(),
+    })
+}
+
+/// Headers in a form fit for the logs, with credentials left out.
+pub fn redact_headers(headers: &HeaderMap) -> String {
+    let mut out = vec![];
+    for (name, value) in headers {
+        let value = if REDACTED_HEADERS.contains(name) {
+            "[redacted]"
+        } else {
+            value.to_str().unwrap_or("[binary]")
+        };
+        out.push(format!("{}: {}", name, value));
+    }
+    out.join(", ")
+}
+
+/// The id to use for a request, keeping the client's if it's sensible.
+fn request_id(req: &ServiceRequest) -> String {
+    req.headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= MAX_REQUEST_ID_LEN
+                && id.bytes().all(|b| b.is_ascii_graphic())
+        })
+        .map(String::from)
+        .unwrap_or_else(|| {
+            let mut id = [0; 16];
+            OsRng.fill_bytes(&mut id);
+            hex::encode(id)
+        })
+}
+
+/// The request's id, available to handlers as a request extension.
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+/// Middleware giving each request an id.
+///
+/// This should wrap everything else, so the id is known while any part of
+/// the request is being handled.
+pub struct RequestIdentity;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestIdentity
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = RequestIdentityMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestIdentityMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct RequestIdentityMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestIdentityMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let srv = self.service.clone();
+        let id = request_id(&req);
+        req.extensions_mut().insert(RequestId(id.clone()));
+
+        let handled = {
+            let id = id.clone();
+            async move {
+                log::debug!(
+                    "{} {} [{}]",
+                    req.method(),
+                    req.path(),
+                    redact_headers(req.headers())
+                );
+                let mut res = srv.call(req).await?;
+                // Only fails for ids we didn't check, which never get here.
+                if let Ok(value) = HeaderValue::from_str(&id) {
+                    res.headers_mut()
+                        .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+                }
+                Ok(res)
+            }
+        };
+        WithRequestId {
+            id,
+            inner: handled.boxed_local(),
+        }
+        .boxed_local()
+    }
+}
+
+/// Makes the request id current whenever the request's future is polled.
+struct WithRequestId<T> {
+    id: String,
+    inner: LocalBoxFuture<'static, T>,
+}
+
+impl<T> Future for WithRequestId<T> {
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let id = self.id.clone();
+        let previous = REQUEST_ID.with(|current| current.replace(Some(id)));
+        let res = self.inner.as_mut().poll(cx);
+        REQUEST_ID.with(|current| *current.borrow_mut() = previous);
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{
+        test::{call_service, init_service, read_body, read_body_json, TestRequest},
+        web, App, HttpResponse,
+    };
+
+    #[test]
+    fn test_redact_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("secret-key"),
+        );
+        headers.insert(header::USER_AGENT, HeaderValue::from_static("cargo"));
+
+        let redacted = redact_headers(&headers);
+        assert!(!redacted.contains("secret-key"));
+        assert!(redacted.contains("authorization: [redacted]"));
+        assert!(redacted.contains("user-agent: cargo"));
+    }
+
+    #[actix_rt::test]
+    async fn test_request_id() {
+        let app =
+            init_service(App::new().wrap(RequestIdentity).route(
+                "/",
+                web::get().to(|| async {
+                    HttpResponse::Ok().body(current_request_id().unwrap_or_default())
+                }),
+            ))
+            .await;
+
+        // A client's id is kept.
+        let req = TestRequest::get()
+            .uri("/")
+            .insert_header((REQUEST_ID_HEADER, "abc-123"))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!("abc-123", resp.headers().get(REQUEST_ID_HEADER).unwrap());
+        assert_eq!(&b"abc-123"[..], &read_body(resp).await[..]);
+
+        // Otherwise one is made up.
+        let req = TestRequest::get()
+            .uri("/")
+            .insert_header((REQUEST_ID_HEADER, "no spaces please"))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        let id = resp.headers().get(REQUEST_ID_HEADER).unwrap().clone();
+        assert_eq!(32, id.len());
+        assert_eq!(id.as_bytes(), &read_body(resp).await[..]);
+
+        // And it's forgotten once the request is done.
+        assert_eq!(None, current_request_id());
+    }
+
+    #[actix_rt::test]
+    async fn test_error_has_request_id() {
+        let app = init_service(App::new().wrap(RequestIdentity).route(
+            "/",
+            web::get().to(|| async { Err::<HttpResponse, _>(crate::errors::ApiError::NotFound) }),
+        ))
+        .await;
+
+        let req = TestRequest::get()
+            .uri("/")
+            .insert_header((REQUEST_ID_HEADER, "abc-123"))
+            .to_request();
+        let body: serde_json::Value = read_body_json(call_service(&app, req).await).await;
+        assert_eq!("abc-123", body["request_id"]);
+    }
+}
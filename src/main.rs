@@ -1,18 +1,32 @@
 use crate::errors::EstuaryError;
-use actix_web::{middleware, web, App, HttpServer, cookie::Key};
-use package_index::{Config, PackageIndex};
+use actix_web::{middleware, web, App, HttpMessage, HttpServer, cookie::Key};
+use package_index::{Config, IndexHandle, PackageIndex};
 use std::path::PathBuf;
-use std::sync::Mutex;
 use std::sync::Arc;
 use std::time::Duration;
 use database::{SqlDatabase, Database};
 use actix_session::{storage::RedisActorSessionStore, SessionMiddleware};
 
+mod audit;
+mod backup;
 mod cli;
+mod commands;
+mod config;
+mod crate_source;
+mod deletion;
+mod docs;
 mod errors;
+mod fsck;
+mod git_server;
 mod handlers;
+mod import;
+mod logging;
+mod metrics;
 mod package_index;
+mod reindex;
 mod storage;
+mod tls;
+mod webhooks;
 mod database;
 mod auth;
 
@@ -26,13 +40,15 @@ pub struct Settings {
     /// Note that this should be the path to the working tree, not the `.git`
     /// directory inside it.
     pub index_dir: PathBuf,
-    /// Optionally specify a path to `git`.
-    ///
-    /// Defaults to just "git", expecting it to be in your `PATH`.
-    pub git_binary: PathBuf,
-
     /// The key that must be presented in order to publish a crate.
     pub publish_key: Option<String>,
+    /// The public url of the registry, without a trailing slash.
+    pub base_url: String,
+    /// The redis server sessions are stored in, when serving the frontend.
+    pub redis_uri: Option<String>,
+    /// Reverse proxies whose forwarding headers are believed, see
+    /// [`audit::source_ip`].
+    pub trusted_proxies: Vec<std::net::IpAddr>,
 
     pub db : Arc<dyn Database>
 }
@@ -44,9 +60,19 @@ async fn main() -> Result<(), EstuaryError> {
     #[cfg(feature = "dotenv")]
     dotenv::dotenv().ok();
 
-    env_logger::init();
-
-    let args = cli::parse_args();
+    let mut opt = cli::parse_args();
+    let cmd = opt.cmd.take();
+    let args = match config::ServerConfig::load(&opt) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    logging::init(args.log_format);
+    if opt.git_bin.is_some() {
+        log::warn!("`--git-bin`/`ESTUARY_GIT_BIN` is deprecated and ignored, git is no longer needed to serve the index.");
+    }
 
     let bind_addr = format!("{}:{}", args.http_host, args.http_port);
     let config = Config {
@@ -54,20 +80,31 @@ async fn main() -> Result<(), EstuaryError> {
         api: args.base_url().to_string(),
     };
 
+    if let Some(cmd) = cmd {
+        return commands::run(cmd, &args, &config).await;
+    }
+
     let db = Arc::new(SqlDatabase::new(
         args.db_uri.clone(),
         args.db_max_connections,
         Duration::from_secs(args.db_timeout_s.into())
-    ).await);
+    ).await?);
         
 
     log::warn!("{:?}", db.migrate().await);
 
+    // Already checked when the configuration was loaded.
+    let tls = args.tls()?;
+    let secure_cookies = args.secure_cookies();
+    let redis_uri = args.redis_uri.clone();
+
     let settings = Settings {
+        base_url: args.base_url().to_string(),
         crate_dir: args.crate_dir,
         index_dir: args.index_dir,
-        git_binary: args.git_bin,
         publish_key: args.publish_key,
+        redis_uri: Some(redis_uri.clone()),
+        trusted_proxies: args.trusted_proxies.clone(),
         db : db.clone()
     };
 
@@ -78,36 +115,66 @@ async fn main() -> Result<(), EstuaryError> {
     log::info!("\tIndex Dir: `{}`", settings.index_dir.display());
     log::info!("\tCrate Dir: `{}`", settings.crate_dir.display());
     log::info!("\tPackage Index Config: `{:?}`", config);
-    log::info!("\tDatabase URI: `{:?}`", args.db_uri);
-    log::info!("\tRedis URI: `{:?}`", args.redis_uri);
-
-    let package_index = web::Data::new(Mutex::new(PackageIndex::init(
+    log::info!("\tDatabase URI: `{}`", config::mask_uri(&args.db_uri));
+    log::info!("\tRedis URI: `{}`", config::mask_uri(&redis_uri));
+    log::info!("\tTLS: `{}`", if tls.is_some() { "on" } else { "off" });
+
+    // Nothing else writes to the index while the server is up, so anything
+    // left half done is from a write that was cut short.
+    for repair in PackageIndex::recover(&settings.index_dir)? {
+        log::warn!("Recovered from an interrupted index write: {}", repair);
+    }
+    let package_index = web::Data::new(IndexHandle::spawn(PackageIndex::init(
         &settings.index_dir,
         &config,
     )?));
 
-    let secret_key = Key::generate();
+    if let Some(hours) = args.squash_interval_hours {
+        log::info!("Squashing the index every {} hours.", hours);
+        package_index.schedule_squash(Duration::from_secs(hours * 60 * 60));
+    }
 
-    //let redis_uri = args.redis_uri.clone();
+    let secret_key = Key::generate();
+    let index_writer = package_index.clone();
 
-    Ok(HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         App::new()
-            .wrap(middleware::Logger::default())
+            .wrap(
+                middleware::Logger::new(r#"%a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T %{request_id}xi"#)
+                    .custom_request_replace("request_id", |req| {
+                        req.extensions()
+                            .get::<logging::RequestId>()
+                            .map(|id| format!("request_id={}", id.0))
+                            .unwrap_or_default()
+                    })
+            )
+            .wrap(metrics::RequestMetrics)
             .wrap(auth::AuthFactory::new(db.clone()))
             .wrap(SessionMiddleware::builder(
-                    RedisActorSessionStore::new("localhost:6379"),
+                    RedisActorSessionStore::new(redis_uri.as_str()),
                     secret_key.clone()
                 )
-                .cookie_secure(false)
+                .cookie_secure(secure_cookies)
                 .build()
             )
+            .wrap(logging::RequestIdentity)
             .app_data(package_index.clone())
             .app_data(web::Data::new(settings.clone()))
             .configure(handlers::configure_routes)
-    })
-    .bind(bind_addr)?
-    .run()
-    .await?)
+    });
+    let server = match tls {
+        Some(tls) => server.bind_rustls_021(bind_addr, tls)?,
+        None => server.bind(bind_addr)?,
+    };
+
+    // Stops on SIGTERM or SIGINT once in-flight requests are done (or given
+    // up on).
+    let res = server.run().await;
+    log::info!("Server stopped, waiting for index writes to finish.");
+    if let Err(e) = index_writer.drain().await {
+        log::error!("Failed to finish index writes: {}", e);
+    }
+    Ok(res?)
 }
 
 #[cfg(test)]
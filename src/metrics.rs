@@ -0,0 +1,181 @@
+//! Prometheus metrics, served in the text format at `/metrics`.
+//!
+//! Counters and histograms are updated as things happen. Gauges describing
+//! the state of the registry (database pool, storage) are only worked out
+//! when the metrics are scraped.
+
+use crate::database::Database;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use futures::future::{ready, LocalBoxFuture, Ready};
+use futures::FutureExt;
+use once_cell::sync::Lazy;
+use prometheus::{
+    register_histogram, register_histogram_vec, register_int_counter, register_int_counter_vec,
+    register_int_gauge, Encoder, Histogram, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    TextEncoder,
+};
+use std::path::Path;
+use std::rc::Rc;
+use std::time::Instant;
+
+static HTTP_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "estuary_http_requests_total",
+        "HTTP requests handled, by route and response status.",
+        &["method", "route", "status"]
+    )
+    .unwrap()
+});
+
+static HTTP_REQUEST_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "estuary_http_request_duration_seconds",
+        "Time taken to handle HTTP requests, by route.",
+        &["method", "route"]
+    )
+    .unwrap()
+});
+
+pub static PUBLISHES: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!("estuary_publishes_total", "Crate versions published.").unwrap()
+});
+
+pub static YANKS: Lazy<IntCounter> =
+    Lazy::new(|| register_int_counter!("estuary_yanks_total", "Crate versions yanked.").unwrap());
+
+pub static UNYANKS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!("estuary_unyanks_total", "Crate versions unyanked.").unwrap()
+});
+
+pub static DOWNLOADS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!("estuary_downloads_total", "Crate files downloaded.").unwrap()
+});
+
+pub static UPLOAD_PACK_DURATION: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "estuary_git_upload_pack_duration_seconds",
+        "Time taken to build packs for git fetches of the index."
+    )
+    .unwrap()
+});
+
+pub static INDEX_WAIT: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "estuary_index_wait_seconds",
+        "Time changes to the index spend waiting for earlier changes to finish."
+    )
+    .unwrap()
+});
+
+static DB_POOL_CONNECTIONS: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "estuary_db_pool_connections",
+        "Connections currently open in the database pool."
+    )
+    .unwrap()
+});
+
+static DB_POOL_IDLE: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "estuary_db_pool_idle_connections",
+        "Open connections in the database pool which aren't in use."
+    )
+    .unwrap()
+});
+
+static STORAGE_BYTES: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "estuary_storage_bytes",
+        "Size of the crate dir, including hosted docs."
+    )
+    .unwrap()
+});
+
+/// Gather all metrics in the Prometheus text format.
+pub fn render(db: &dyn Database, crate_dir: &Path) -> String {
+    // Make sure everything is registered, even if nothing has happened yet.
+    for counter in [&PUBLISHES, &YANKS, &UNYANKS, &DOWNLOADS] {
+        Lazy::force(counter);
+    }
+    Lazy::force(&UPLOAD_PACK_DURATION);
+    Lazy::force(&INDEX_WAIT);
+    Lazy::force(&HTTP_REQUESTS);
+    Lazy::force(&HTTP_REQUEST_DURATION);
+
+    let pool = db.pool_status();
+    DB_POOL_CONNECTIONS.set(pool.size.into());
+    DB_POOL_IDLE.set(pool.idle as i64);
+    match crate::storage::dir_size(crate_dir) {
+        Ok(size) => STORAGE_BYTES.set(size as i64),
+        Err(e) => log::warn!("Failed to measure `{}`: {}", crate_dir.display(), e),
+    }
+
+    let mut buf = vec![];
+    // Only fails for malformed metrics, which would be a bug here.
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buf)
+        .unwrap();
+    String::from_utf8(buf).unwrap()
+}
+
+/// Middleware counting and timing every request.
+///
+/// Requests are labelled with the route pattern they matched rather than
+/// their path, so there's one series per route rather than one per crate.
+pub struct RequestMetrics;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestMetrics
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = RequestMetricsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestMetricsMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct RequestMetricsMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestMetricsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let srv = self.service.clone();
+        let started = Instant::now();
+        let method = req.method().to_string();
+
+        async move {
+            let res = srv.call(req).await?;
+            let route = res
+                .request()
+                .match_pattern()
+                .unwrap_or_else(|| String::from("unmatched"));
+            let status = res.status().as_u16().to_string();
+
+            HTTP_REQUESTS
+                .with_label_values(&[&method, &route, &status])
+                .inc();
+            HTTP_REQUEST_DURATION
+                .with_label_values(&[&method, &route])
+                .observe(started.elapsed().as_secs_f64());
+            Ok(res)
+        }
+        .boxed_local()
+    }
+}
@@ -20,14 +20,19 @@
 //! Currently none of these restrictions are being performed. This may come in
 //! the future.
 use crate::errors::PackageIndexError;
+use git2::build::CheckoutBuilder;
 #[cfg(test)]
 use git2::Oid;
-use git2::{Repository, RepositoryInitOptions, Signature};
+use git2::{Repository, RepositoryInitOptions, ResetType, Signature, Status, StatusOptions};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs::OpenOptions;
 use std::io::{BufReader, Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+mod writer;
+pub use writer::IndexHandle;
 
 type Result<T> = std::result::Result<T, PackageIndexError>;
 
@@ -63,6 +68,10 @@ pub struct PackageVersion {
     /// The `links` string value from the package's manifest, or null if not
     /// specified. This field is optional and defaults to null.
     pub links: Option<String>,
+    /// Any other fields, like `features2` and `v` from newer versions of
+    /// cargo, kept as they are so rewriting the line doesn't lose them.
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
@@ -121,8 +130,194 @@ pub enum DependencyKind {
     Normal,
 }
 
+/// A published version which depends on some other crate in the index.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Dependent {
+    /// The name of the crate declaring the dependency.
+    pub name: String,
+    /// The version of the crate declaring the dependency.
+    pub vers: semver::Version,
+    /// The dependency itself, as it appears in the dependent's index entry.
+    pub dep: Dependency,
+}
+
+/// A version being published to the index, as recorded in the git reflog.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Publish {
+    pub name: String,
+    pub vers: semver::Version,
+    /// Unix timestamp (seconds).
+    pub timestamp: i64,
+}
+
+/// A line of a package file which isn't a valid version.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BadLine {
+    pub crate_name: String,
+    /// Counting from 1.
+    pub line: usize,
+    pub error: String,
+}
+
+/// An in-memory copy of the package files in the index.
+#[derive(Default)]
+struct IndexCache {
+    /// Every version of every crate, keyed by crate name, in the order they
+    /// appear in the package files.
+    crates: BTreeMap<String, Vec<PackageVersion>>,
+    /// Reverse dependency lookup, keyed by the (lowercased) name of the crate
+    /// being depended on.
+    dependents: HashMap<String, Vec<Dependent>>,
+    /// Every publish, oldest first.
+    publishes: Vec<Publish>,
+}
+
+impl IndexCache {
+    /// Add a version to the end of its crate's list, recording its dependencies
+    /// in the reverse dependency lookup.
+    ///
+    /// Only dependencies on crates from this registry are tracked.
+    fn insert(&mut self, pkg: PackageVersion) {
+        for dep in pkg.deps.iter().filter(|dep| dep.registry.is_none()) {
+            // A renamed dependency keeps the real crate name in `package`.
+            let target = dep.package.as_ref().unwrap_or(&dep.name).to_lowercase();
+            self.dependents.entry(target).or_default().push(Dependent {
+                name: pkg.name.clone(),
+                vers: pkg.vers.clone(),
+                dep: dep.clone(),
+            });
+        }
+        self.crates.entry(pkg.name.clone()).or_default().push(pkg);
+    }
+
+    /// Forget a version entirely, as if it had never been published.
+    fn remove(&mut self, name: &str, vers: &semver::Version) {
+        if let Some(versions) = self.crates.get_mut(name) {
+            versions.retain(|pkg| &pkg.vers != vers);
+            if versions.is_empty() {
+                self.crates.remove(name);
+            }
+        }
+        for dependents in self.dependents.values_mut() {
+            dependents.retain(|d| !(d.name == name && &d.vers == vers));
+        }
+        self.dependents
+            .retain(|_, dependents| !dependents.is_empty());
+        self.publishes
+            .retain(|p| !(p.name == name && &p.vers == vers));
+    }
+
+    fn contains(&self, name: &str, vers: &semver::Version) -> bool {
+        self.crates
+            .get(name)
+            .is_some_and(|versions| versions.iter().any(|pkg| &pkg.vers == vers))
+    }
+}
+
+/// Read-only access to the index.
+///
+/// Reads are served from memory and don't need the git repo, so readers are
+/// cheap to clone and can be shared by any number of request handlers while a
+/// single writer (see [`IndexHandle`]) makes changes.
+///
+/// The cache is loaded from the package files when the index is initialized
+/// and only updated by [`PackageIndex`] once a change has been committed.
+#[derive(Clone, Default)]
+pub struct IndexReader {
+    cache: Arc<RwLock<IndexCache>>,
+}
+
+impl IndexReader {
+    /// Get all the [`PackageVersion`]s for a given crate name, in the order they
+    /// were published.
+    pub fn get_package_versions(&self, name: &str) -> Result<Vec<PackageVersion>> {
+        read_lock(&self.cache)
+            .crates
+            .get(name)
+            .cloned()
+            .ok_or_else(|| PackageIndexError::CrateNotFound(name.to_string()))
+    }
+
+    /// Get a list of crates published to the index, sorted by name.
+    pub fn list_crates(&self) -> Vec<String> {
+        read_lock(&self.cache).crates.keys().cloned().collect()
+    }
+
+    /// Get every published version which depends on the named crate.
+    ///
+    /// Results are sorted by dependent name, then version.
+    pub fn get_dependents(&self, name: &str) -> Vec<Dependent> {
+        let mut dependents = read_lock(&self.cache)
+            .dependents
+            .get(&name.trim().to_lowercase())
+            .cloned()
+            .unwrap_or_default();
+        dependents.sort_by(|a, b| a.name.cmp(&b.name).then_with(|| a.vers.cmp(&b.vers)));
+        dependents
+    }
+
+    /// Get the most recent publishes, newest first.
+    pub fn get_publishes(&self, limit: usize) -> Vec<Publish> {
+        read_lock(&self.cache)
+            .publishes
+            .iter()
+            .rev()
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    /// Get the crates which were most recently published for the first time,
+    /// newest first.
+    ///
+    /// Each [`Publish`] is the first version of its crate.
+    pub fn get_new_crates(&self, limit: usize) -> Vec<Publish> {
+        let cache = read_lock(&self.cache);
+        let mut seen = HashSet::new();
+        let mut new: Vec<&Publish> = cache
+            .publishes
+            .iter()
+            .filter(|p| seen.insert(p.name.as_str()))
+            .collect();
+        new.reverse();
+        new.into_iter().take(limit).cloned().collect()
+    }
+
+    /// Get the crates which were most recently published to, newest first.
+    ///
+    /// Each [`Publish`] is the latest version of its crate to be published.
+    pub fn get_updated_crates(&self, limit: usize) -> Vec<Publish> {
+        let cache = read_lock(&self.cache);
+        let mut seen = HashSet::new();
+        cache
+            .publishes
+            .iter()
+            .rev()
+            .filter(|p| seen.insert(p.name.as_str()))
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    /// Like `get_dependents` but only the highest version of each dependent
+    /// crate is included.
+    pub fn get_latest_dependents(&self, name: &str) -> Vec<Dependent> {
+        let mut latest: Vec<Dependent> = vec![];
+        for dependent in self.get_dependents(name) {
+            // Dependents are sorted by name then version, so the last entry
+            // seen for each name is the highest version.
+            match latest.last_mut() {
+                Some(last) if last.name == dependent.name => *last = dependent,
+                _ => latest.push(dependent),
+            }
+        }
+        latest
+    }
+}
+
 pub struct PackageIndex {
     repo: Repository,
+    reader: IndexReader,
 }
 
 impl PackageIndex {
@@ -135,13 +330,18 @@ impl PackageIndex {
     /// is there's already a valid index at that path.
     /// An attempt to update the config (if necessary) using the supplied values
     /// will be made.
+    ///
+    /// Leftovers from an interrupted write are left alone, since they may
+    /// belong to a write still in progress elsewhere. See [`Self::recover`].
     pub fn init<P>(path: P, config: &Config) -> Result<Self>
     where
         P: AsRef<Path>,
     {
         let path = path.as_ref();
+        let repo = get_or_create_repo(path)?;
         let pkg_index = Self {
-            repo: get_or_create_repo(path)?,
+            repo,
+            reader: Default::default(),
         };
         let current_config: Option<Config> = pkg_index.read_config().ok();
 
@@ -149,33 +349,167 @@ impl PackageIndex {
             // XXX: might need to think about reverting if something fails part way
             // through the operation.
             pkg_index.write_config(config)?;
-            pkg_index.add_and_commit_file("config.json", "update registry config")?;
+            pkg_index.add_and_commit_file("config.json", "update registry config", None)?;
         }
+        pkg_index.load_cache()?;
         Ok(pkg_index)
     }
 
+    /// Open an existing index without changing anything in it, for looking
+    /// around.
+    pub fn open<P>(path: P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let pkg_index = Self {
+            repo: Repository::open(path.as_ref())?,
+            reader: Default::default(),
+        };
+        pkg_index.load_cache()?;
+        Ok(pkg_index)
+    }
+
+    /// Clean up after writes to the index at `path` which were interrupted
+    /// (by a crash, say), see [`recover_interrupted_writes`].
+    ///
+    /// This assumes any lock it finds is stale, so it must only be run when
+    /// nothing else can be writing to the index, which is when the server
+    /// starts. Returns a description of each repair.
+    pub fn recover<P>(path: P) -> Result<Vec<String>>
+    where
+        P: AsRef<Path>,
+    {
+        match Repository::open(path.as_ref()) {
+            Ok(repo) => recover_interrupted_writes(&repo),
+            Err(e) if e.code() == git2::ErrorCode::NotFound => Ok(vec![]),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Get a reader which can be used to look at the index from other threads.
+    pub fn reader(&self) -> IndexReader {
+        self.reader.clone()
+    }
+
+    /// Read every package file in the index into the cache.
+    ///
+    /// Crates with package files that can't be read are left out (with a
+    /// warning) rather than preventing the index from loading.
+    fn load_cache(&self) -> Result<()> {
+        let mut cache = IndexCache::default();
+        for name in self.scan_package_files()? {
+            match self.parse_package_file(&name) {
+                Ok(pkgs) => pkgs.into_iter().for_each(|pkg| cache.insert(pkg)),
+                Err(e) => log::warn!("Skipping `{}`: {}", name, e),
+            }
+        }
+        // Deleted versions stay in the reflog.
+        let publishes = self
+            .read_publishes()?
+            .into_iter()
+            .filter(|p| cache.contains(&p.name, &p.vers))
+            .collect();
+        cache.publishes = publishes;
+        *write_lock(&self.reader.cache) = cache;
+        Ok(())
+    }
+
+    /// Find the names of all the package files in the index.
+    fn scan_package_files(&self) -> Result<Vec<String>> {
+        let root = self.repo.workdir().unwrap();
+        let mut acc = vec![];
+
+        // TODO: maybe rewrite with a recursive fn and fs::read_dir().
+        //  Probably it'd be more efficient to do it without globs.
+        for path in glob::glob(&format!("{}/[1,2]/*", root.display()))?.flatten() {
+            acc.push(path.file_name().unwrap().to_str().unwrap().to_string());
+        }
+        for path in glob::glob(&format!("{}/3/?/*", root.display()))?.flatten() {
+            acc.push(path.file_name().unwrap().to_str().unwrap().to_string());
+        }
+        for path in glob::glob(&format!("{}/??/??/*", root.display()))?.flatten() {
+            acc.push(path.file_name().unwrap().to_str().unwrap().to_string());
+        }
+        Ok(acc)
+    }
+
     /// Add a file, then commit it to the git repo.
     ///
     /// Roughly equivalent to:
     ///
     /// ```text
-    /// git add <path> && git commit -m <msg>
+    /// git add --all <path> && git commit -m <msg>
     /// ```
-    fn add_and_commit_file<P>(&self, path: P, msg: &str) -> Result<()>
+    ///
+    /// A file which no longer exists is removed from the repo.
+    ///
+    /// The commit is authored by `author` when given, otherwise by the system.
+    fn add_and_commit_file<P>(&self, path: P, msg: &str, author: Option<&str>) -> Result<()>
     where
         P: AsRef<Path>,
     {
         let head = self.repo.head()?;
         let parent = head.peel_to_commit()?;
         let mut index = self.repo.index()?;
-        index.add_path(path.as_ref())?;
+        if self.repo.workdir().unwrap().join(path.as_ref()).exists() {
+            index.add_path(path.as_ref())?;
+        } else {
+            index.remove_path(path.as_ref())?;
+        }
         index.write()?;
         let tree_id = index.write_tree()?;
         let tree = self.repo.find_tree(tree_id)?;
         let sig = get_sig()?;
+        let author = match author {
+            Some(name) => get_user_sig(name)?,
+            None => sig.clone(),
+        };
         self.repo
-            .commit(Some("HEAD"), &sig, &sig, msg, &tree, &[&parent])?;
-        git_update_server_info(&self.repo)?;
+            .commit(Some("HEAD"), &author, &sig, msg, &tree, &[&parent])?;
+        // The commit stands either way, dumb clients just see it late.
+        if let Err(e) = git_update_server_info(&self.repo) {
+            log::warn!("Failed to update the index's server info: {}", e);
+        }
+        Ok(())
+    }
+
+    /// Change the package file at `path` with `write`, then commit it.
+    ///
+    /// Should anything fail along the way, the file is put back the way HEAD
+    /// has it, in both the work tree and git's index, so a half finished
+    /// change isn't swept up by the next commit.
+    fn commit_change<P, F>(&self, path: P, msg: &str, author: Option<&str>, write: F) -> Result<()>
+    where
+        P: AsRef<Path>,
+        F: FnOnce() -> Result<()>,
+    {
+        let res = write().and_then(|()| self.add_and_commit_file(path.as_ref(), msg, author));
+        if res.is_err() {
+            if let Err(e) = self.restore_file(path.as_ref()) {
+                log::error!(
+                    "Failed to restore `{}` after a failed write: {}",
+                    path.as_ref().display(),
+                    e
+                );
+            }
+        }
+        res
+    }
+
+    /// Put `path` back the way HEAD has it, removing it if HEAD doesn't.
+    fn restore_file(&self, path: &Path) -> Result<()> {
+        let head = self.repo.head()?.peel_to_commit()?;
+        self.repo.reset_default(Some(head.as_object()), [path])?;
+        if head.tree()?.get_path(path).is_ok() {
+            self.repo
+                .checkout_head(Some(CheckoutBuilder::new().force().path(path)))?;
+        } else {
+            match std::fs::remove_file(self.repo.workdir().unwrap().join(path)) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
         Ok(())
     }
 
@@ -206,55 +540,63 @@ impl PackageIndex {
     ///
     /// If the version already exists in the package file, this function will
     /// return an `Err`.
-    pub fn publish(&self, pkg: &PackageVersion) -> Result<()> {
+    ///
+    /// The commit is attributed to `author`, if given.
+    pub fn publish(&self, pkg: &PackageVersion, author: Option<&str>) -> Result<()> {
         let root = self.repo.workdir().unwrap();
         let dir = get_package_file_dir(&pkg.name)?;
-        std::fs::create_dir_all(root.join(&dir))?;
         let pkg_file = dir.join(&pkg.name);
 
-        // "touch" the file to make sure it's available for reading.
-        OpenOptions::new()
-            .create(true)
-            .write(true)
-            .open(root.join(&pkg_file))?;
+        std::fs::create_dir_all(root.join(&dir))?;
 
-        // Read the file to see if the version we're publishing is already present.
-        // Bail if it is.
-        {
-            let contents = self.read_package_file(&pkg.name)?;
-            for line in contents.lines() {
-                let PackageVersion { vers, .. } = serde_json::from_str(line)?;
-                if vers == pkg.vers {
-                    return Err(PackageIndexError::Publish(format!(
-                        "Failed to publish `{} v{}`. Crate already exists in index.",
-                        pkg.name, pkg.vers
-                    )));
+        self.commit_change(
+            &pkg_file,
+            &format!("publish crate: `{} v{}`", pkg.name, pkg.vers),
+            author,
+            || {
+                // "touch" the file to make sure it's available for reading.
+                OpenOptions::new()
+                    .create(true)
+                    .truncate(false)
+                    .write(true)
+                    .open(root.join(&pkg_file))?;
+
+                // Read the file to see if the version we're publishing is already
+                // present. Bail if it is.
+                let contents = self.read_package_file(&pkg.name)?;
+                for line in contents.lines() {
+                    let PackageVersion { vers, .. } = serde_json::from_str(line)?;
+                    if vers == pkg.vers {
+                        return Err(PackageIndexError::Publish(format!(
+                            "Failed to publish `{} v{}`. Crate already exists in index.",
+                            pkg.name, pkg.vers
+                        )));
+                    }
                 }
-            }
-        }
 
-        // Write the version to the file.
-        {
-            let mut fh = OpenOptions::new()
-                .create(false)
-                .append(true)
-                .open(root.join(&pkg_file))?;
-            writeln!(fh, "{}", serde_json::to_string(pkg)?)?;
-        }
-
-        self.add_and_commit_file(
-            pkg_file,
-            &format!("publish crate: `{} v{}`", pkg.name, pkg.vers),
+                // Write the version to the file.
+                let mut fh = OpenOptions::new()
+                    .create(false)
+                    .append(true)
+                    .open(root.join(&pkg_file))?;
+                writeln!(fh, "{}", serde_json::to_string(pkg)?)?;
+                Ok(())
+            },
         )?;
+        let mut cache = write_lock(&self.reader.cache);
+        cache.insert(pkg.clone());
+        cache.publishes.push(Publish {
+            name: pkg.name.clone(),
+            vers: pkg.vers.clone(),
+            timestamp: chrono::Utc::now().timestamp(),
+        });
         Ok(())
     }
 
     /// Get the contents of a package file.
     fn read_package_file(&self, name: &str) -> Result<String> {
         let root = self.repo.workdir().unwrap();
-        let dir = get_package_file_dir(name)?;
-        std::fs::create_dir_all(root.join(&dir))?;
-        let pkg_file = dir.join(name);
+        let pkg_file = get_package_file_dir(name)?.join(name);
         let mut fh = BufReader::new(
             OpenOptions::new()
                 .create(false)
@@ -267,6 +609,36 @@ impl PackageIndex {
         Ok(buf)
     }
 
+    /// Read every package file from disk, keeping whatever can be parsed.
+    ///
+    /// Unlike loading the index, which skips package files that are broken in
+    /// any way, this reports the individual lines which are.
+    pub fn scan(&self) -> Result<(Vec<PackageVersion>, Vec<BadLine>)> {
+        let mut versions = vec![];
+        let mut bad_lines = vec![];
+        for name in self.scan_package_files()? {
+            for (i, line) in self.read_package_file(&name)?.lines().enumerate() {
+                match serde_json::from_str(line) {
+                    Ok(pkg) => versions.push(pkg),
+                    Err(e) => bad_lines.push(BadLine {
+                        crate_name: name.clone(),
+                        line: i + 1,
+                        error: e.to_string(),
+                    }),
+                }
+            }
+        }
+        Ok((versions, bad_lines))
+    }
+
+    /// Parse every line of a package file.
+    fn parse_package_file(&self, name: &str) -> Result<Vec<PackageVersion>> {
+        self.read_package_file(name)?
+            .lines()
+            .map(|s| serde_json::from_str(s).map_err(PackageIndexError::from))
+            .collect()
+    }
+
     /// Truncate and rewrite a package file.
     fn rewrite_package_file(&self, name: &str, pkg_versions: &[PackageVersion]) -> Result<()> {
         let root = self.repo.workdir().unwrap();
@@ -288,7 +660,17 @@ impl PackageIndex {
     }
 
     /// Updates the `yanked` field of a given package version.
-    pub fn set_yanked(&self, name: &str, version: &semver::Version, yanked: bool) -> Result<()> {
+    ///
+    /// The commit is attributed to `author`, if given. Returns whether the
+    /// flag actually changed, nothing is committed when it didn't. Fails with
+    /// `CrateNotFound` or `VersionNotFound` if there's no such version.
+    pub fn set_yanked(
+        &self,
+        name: &str,
+        version: &semver::Version,
+        yanked: bool,
+        author: Option<&str>,
+    ) -> Result<bool> {
         // This is the most naive impl I can think of for this, but it should get
         // things rolling.
         // Read the whole package file, json parse all lines, modify the struct that
@@ -296,39 +678,142 @@ impl PackageIndex {
         // the file).
         // A better version of this would modify the specific line in the file, I
         // guess.
+        let mut pkg_versions = match self.parse_package_file(name) {
+            Ok(pkg_versions) => pkg_versions,
+            Err(PackageIndexError::IO(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(PackageIndexError::CrateNotFound(name.to_string()))
+            }
+            Err(e) => return Err(e),
+        };
 
-        let mut pkg_versions = self
-            .read_package_file(name)?
-            .lines()
-            .map(serde_json::from_str)
-            .map(|r| r.map_err(Into::into))
-            .collect::<Result<Vec<PackageVersion>>>()?;
-
-        for pkg in &mut pkg_versions {
-            if &pkg.vers == version {
-                if pkg.yanked == yanked {
-                    // Nothing to do if the values are the same.
-                    return Ok(());
-                }
-                pkg.yanked = yanked;
-                break;
+        let pkg = match pkg_versions.iter_mut().find(|pkg| &pkg.vers == version) {
+            Some(pkg) => pkg,
+            None => {
+                return Err(PackageIndexError::VersionNotFound(
+                    name.to_string(),
+                    version.clone(),
+                ))
             }
+        };
+        // Nothing to do if the values are the same.
+        if pkg.yanked == yanked {
+            return Ok(false);
         }
-
-        self.rewrite_package_file(name, &pkg_versions)?;
+        pkg.yanked = yanked;
 
         let dir = get_package_file_dir(name)?;
 
         let verb = if yanked { "yank" } else { "unyank" };
 
-        self.add_and_commit_file(
+        self.commit_change(
             dir.join(name),
             &format!("{} crate: `{} v{}`", verb, name, version),
+            author,
+            || self.rewrite_package_file(name, &pkg_versions),
         )?;
 
+        // The yanked flag has no bearing on the reverse dependency lookup so
+        // only the versions themselves need replacing.
+        write_lock(&self.reader.cache)
+            .crates
+            .insert(name.to_string(), pkg_versions);
+
+        Ok(true)
+    }
+
+    /// Remove a version from the index entirely.
+    ///
+    /// Unlike yanking, this leaves no trace of the version in the package file.
+    /// Once the last version of a crate is deleted, so is its package file.
+    ///
+    /// The commit is attributed to `author`, if given.
+    pub fn delete_version(
+        &self,
+        name: &str,
+        version: &semver::Version,
+        author: Option<&str>,
+    ) -> Result<()> {
+        let mut pkg_versions = match self.parse_package_file(name) {
+            Ok(pkg_versions) => pkg_versions,
+            Err(PackageIndexError::IO(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(PackageIndexError::CrateNotFound(name.to_string()))
+            }
+            Err(e) => return Err(e),
+        };
+        let before = pkg_versions.len();
+        pkg_versions.retain(|pkg| &pkg.vers != version);
+        if pkg_versions.len() == before {
+            return Err(PackageIndexError::VersionNotFound(
+                name.to_string(),
+                version.clone(),
+            ));
+        }
+
+        let pkg_file = get_package_file_dir(name)?.join(name);
+        self.commit_change(
+            &pkg_file,
+            &format!("delete crate: `{} v{}`", name, version),
+            author,
+            || {
+                if pkg_versions.is_empty() {
+                    std::fs::remove_file(self.repo.workdir().unwrap().join(&pkg_file))?;
+                    Ok(())
+                } else {
+                    self.rewrite_package_file(name, &pkg_versions)
+                }
+            },
+        )?;
+
+        write_lock(&self.reader.cache).remove(name, version);
         Ok(())
     }
 
+    /// Replace the history of the index with a single commit.
+    ///
+    /// The contents of the index stay the same, only the history goes away.
+    /// The old history is kept reachable by a ref under `refs/archive/`, named
+    /// after the time and the old head, which is never advertised to clients.
+    ///
+    /// Existing clones recover on their next fetch: cargo always force-fetches
+    /// the index, and the objects they already have are still common with us
+    /// so only the new commit needs to be sent.
+    ///
+    /// Returns the name of the archive ref, or `None` when there was no
+    /// history to squash.
+    pub fn squash(&self) -> Result<Option<String>> {
+        let head = self.repo.head()?;
+        let branch = head
+            .name()
+            .ok_or_else(|| PackageIndexError::Squash("HEAD is not a valid ref".to_string()))?
+            .to_string();
+        let old = head.peel_to_commit()?;
+        if old.parent_count() == 0 {
+            return Ok(None);
+        }
+
+        let archive = format!(
+            "refs/archive/{}-{}",
+            chrono::Utc::now().format("%Y-%m-%d-%H%M%S"),
+            old.id()
+        );
+        self.repo
+            .reference(&archive, old.id(), false, "archive index history")?;
+
+        let sig = get_sig()?;
+        let msg = format!("squash index history (archived to `{}`)", archive);
+        let new = self
+            .repo
+            .commit(None, &sig, &sig, &msg, &old.tree()?, &[])?;
+
+        // Only move the branch if nothing else has committed in the meantime.
+        self.repo
+            .reference_matching(&branch, new, true, old.id(), &msg)?;
+        if let Err(e) = git_update_server_info(&self.repo) {
+            log::warn!("Failed to update the index's server info: {}", e);
+        }
+        Ok(Some(archive))
+    }
+
     // XXX: we might want this irl for debug pages or whatever.
     #[cfg(test)]
     fn get_repo_log(&self) -> Result<Vec<(Oid, Option<String>)>> {
@@ -340,69 +825,76 @@ impl PackageIndex {
             .collect())
     }
 
-    #[allow(dead_code)] // not currently used but not ready to give up on it.
-    pub fn get_publishes(&self, limit: Option<usize>) -> Result<Vec<(String, String)>> {
-        let reflog = self.repo.reflog("HEAD")?;
-        let it = reflog.iter().filter_map(|entry| {
-            let msg = entry.message().unwrap_or("");
-            if msg.contains("publish crate") {
-                let middle = msg.splitn(3, '`').nth(1).unwrap();
+    /// Read every publish from the reflog, oldest first.
+    ///
+    /// The reflog is local to this repo, so publishes made before the index
+    /// was cloned or imported from elsewhere are missing. It does survive
+    /// squashing.
+    fn read_publishes(&self) -> Result<Vec<Publish>> {
+        let reflog = match self.repo.reflog("HEAD") {
+            Ok(reflog) => reflog,
+            Err(e) if e.code() == git2::ErrorCode::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
+        let mut publishes: Vec<Publish> = reflog
+            .iter()
+            .filter_map(|entry| {
+                let msg = entry.message().unwrap_or("");
+                if !msg.contains("publish crate") {
+                    return None;
+                }
+                let (_, rest) = msg.split_once('`')?;
+                let (middle, _) = rest.split_once('`')?;
                 let mut parts = middle.split_whitespace();
-                let (pkg, vers) = (
-                    parts.next().unwrap().to_string(),
-                    parts.next().unwrap().to_string(),
-                );
-                Some((pkg, vers))
-            } else {
-                None
-            }
-        });
-
-        if let Some(limit) = limit {
-            Ok(it.take(limit).collect())
-        } else {
-            Ok(it.collect())
-        }
+                let name = parts.next()?.to_string();
+                let vers = parts.next()?.trim_start_matches('v').parse().ok()?;
+                Some(Publish {
+                    name,
+                    vers,
+                    timestamp: entry.committer().when().seconds(),
+                })
+            })
+            .collect();
+        // The reflog is newest first.
+        publishes.reverse();
+        Ok(publishes)
     }
 
-    /// Get the [`PackageVersion`] given a crate name and (optional) version.
-    /// When `vers` is not specified, the latest available version will be
-    /// returned.
-    ///
-    /// Returns Ok(None) if the crate exists in the index, but the requested
-    /// version was not found.
+    /// See [`IndexReader::get_package_versions`].
     pub fn get_package_versions(&self, name: &str) -> Result<Vec<PackageVersion>> {
-        let contents = self.read_package_file(name)?;
-        Ok(contents
-            .lines()
-            .map(|s| serde_json::from_str(s).map_err(PackageIndexError::from))
-            .collect::<Result<Vec<PackageVersion>>>()?)
+        self.reader.get_package_versions(name)
     }
 
-    /// Get a list of crates published to the index.
-    pub fn list_crates(&self) -> Result<Vec<String>> {
-        let root = self.repo.workdir().unwrap();
-        let mut acc = vec![];
+    /// See [`IndexReader::list_crates`].
+    #[cfg(test)]
+    pub fn list_crates(&self) -> Vec<String> {
+        self.reader.list_crates()
+    }
 
-        // TODO: maybe rewrite with a recursive fn and fs::read_dir().
-        //  Probably it'd be more efficient to do it without globs.
-        for entry in glob::glob(&format!("{}/[1,2]/*", root.display()))? {
-            if let Ok(path) = entry {
-                acc.push(path.file_name().unwrap().to_str().unwrap().to_string());
-            }
-        }
-        for entry in glob::glob(&format!("{}/3/?/*", root.display()))? {
-            if let Ok(path) = entry {
-                acc.push(path.file_name().unwrap().to_str().unwrap().to_string());
-            }
-        }
-        for entry in glob::glob(&format!("{}/??/??/*", root.display()))? {
-            if let Ok(path) = entry {
-                acc.push(path.file_name().unwrap().to_str().unwrap().to_string());
-            }
-        }
-        Ok(acc)
+    /// See [`IndexReader::get_dependents`].
+    #[cfg(test)]
+    pub fn get_dependents(&self, name: &str) -> Vec<Dependent> {
+        self.reader.get_dependents(name)
     }
+
+    /// See [`IndexReader::get_latest_dependents`].
+    #[cfg(test)]
+    pub fn get_latest_dependents(&self, name: &str) -> Vec<Dependent> {
+        self.reader.get_latest_dependents(name)
+    }
+}
+
+/// The cache is only ever updated by replacing whole entries once a change has
+/// been committed, so a panic while the lock is held can't leave it half
+/// updated.
+/// Rather than take the whole index out of service, poisoning is ignored.
+fn read_lock<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// See [`read_lock`].
+fn write_lock<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    lock.write().unwrap_or_else(PoisonError::into_inner)
 }
 
 /// Generate the directory name for a package file in the index.
@@ -447,6 +939,13 @@ fn get_sig() -> Result<Signature<'static>> {
     Ok(Signature::now("estuary", "admin@localhost")?)
 }
 
+/// Get a git signature for a registry user.
+///
+/// Users don't have email addresses so a placeholder is used instead.
+fn get_user_sig(name: &str) -> Result<Signature<'static>> {
+    Ok(Signature::now(name, &format!("{}@localhost", name))?)
+}
+
 fn get_or_create_repo<P>(root: P) -> Result<Repository>
 where
     P: AsRef<Path>,
@@ -486,6 +985,82 @@ where
     }
 }
 
+/// Put the repo back the way the last commit left it.
+///
+/// Every change to the index ends with a commit, so anything else is the
+/// remains of a change that never finished: lock files git left behind, and
+/// uncommitted edits to the working tree. The edits are thrown away rather
+/// than committed, since the change they belong to was never reported as a
+/// success (a publish only stores the `.crate` file once the index commit is
+/// done).
+///
+/// Only one process should write to the index at a time, so any lock found
+/// here is assumed to be stale.
+///
+/// Returns a description of each repair, which is empty when the repo was
+/// clean.
+pub(crate) fn recover_interrupted_writes(repo: &Repository) -> Result<Vec<String>> {
+    let mut repairs = vec![];
+
+    let git_dir = repo.path();
+    let mut locks = vec![git_dir.join("index.lock"), git_dir.join("HEAD.lock")];
+    find_lock_files(&git_dir.join("refs"), &mut locks)?;
+    for lock in locks {
+        if lock.exists() {
+            std::fs::remove_file(&lock)?;
+            let name = lock.strip_prefix(git_dir).unwrap_or(&lock);
+            repairs.push(format!("removed stale lock `{}`", name.display()));
+        }
+    }
+
+    let mut opts = StatusOptions::new();
+    opts.include_untracked(true).recurse_untracked_dirs(true);
+    let dirty: Vec<(String, Status)> = repo
+        .statuses(Some(&mut opts))?
+        .iter()
+        .filter_map(|entry| entry.path().map(|path| (path.to_string(), entry.status())))
+        .collect();
+    if !dirty.is_empty() {
+        let head = repo.head()?.peel_to_commit()?;
+        repo.reset(
+            head.as_object(),
+            ResetType::Hard,
+            Some(CheckoutBuilder::new().force()),
+        )?;
+        let workdir = repo.workdir().unwrap();
+        for (path, status) in dirty {
+            // Resetting leaves files git has never seen alone.
+            if status.is_wt_new() {
+                std::fs::remove_file(workdir.join(&path))?;
+            }
+            repairs.push(format!("discarded uncommitted changes to `{}`", path));
+        }
+    }
+
+    // The last commit may have landed without the files for dumb clients
+    // being brought up to date.
+    git_update_server_info(repo)?;
+    Ok(repairs)
+}
+
+/// Collect the `.lock` files under `dir`, recursively.
+fn find_lock_files(dir: &Path, acc: &mut Vec<PathBuf>) -> Result<()> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    for entry in entries {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_lock_files(&path, acc)?;
+        } else if path.extension().is_some_and(|ext| ext == "lock") {
+            acc.push(path);
+        }
+    }
+    Ok(())
+}
+
 /// The post-update hook is normally used to generate `info/refs` for
 /// each branch when you push to a git server. This is achieved by
 /// running `git update-server-info`.
@@ -494,26 +1069,40 @@ where
 /// See: https://git-scm.com/book/en/v2/Git-on-the-Server-The-Protocols
 ///
 /// Our "git server" is closer to a plain working tree (like a clone) so
-/// we'd never push to this. In order to expose our git repo to `cargo` we can
-/// basically run this command after each commit.
+/// we'd never push to this. Instead, we write the same files
+/// `update-server-info` would after each commit.
 fn git_update_server_info(repo: &Repository) -> Result<()> {
-    // FIXME: see if we can do this without shelling out.
-    Ok(std::process::Command::new("git")
-        .current_dir(repo.workdir().unwrap())
-        .arg("update-server-info")
-        .output()
-        .map(|x| {
-            if x.status.success() {
-                log::trace!("git says: {:?}", x);
-            } else {
-                log::error!("git says: {:?}", x);
-            }
-        })?)
+    let git_dir = repo.path();
+
+    let mut refs = String::new();
+    for (name, oid) in crate::git_server::list_refs(repo)? {
+        refs.push_str(&format!("{}\t{}\n", oid, name));
+    }
+    std::fs::create_dir_all(git_dir.join("info"))?;
+    std::fs::write(git_dir.join("info").join("refs"), refs)?;
+
+    let mut packs = String::new();
+    if let Ok(entries) = std::fs::read_dir(git_dir.join("objects").join("pack")) {
+        let mut names: Vec<_> = entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .filter(|name| name.ends_with(".pack"))
+            .collect();
+        names.sort();
+        for name in names {
+            packs.push_str(&format!("P {}\n", name));
+        }
+    }
+    packs.push('\n');
+    std::fs::create_dir_all(git_dir.join("objects").join("info"))?;
+    std::fs::write(git_dir.join("objects").join("info").join("packs"), packs)?;
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_helpers::pkg;
     use serde_json::json;
     use tempdir::TempDir;
 
@@ -598,6 +1187,120 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_failed_commit_leaves_tree_clean() {
+        let root = TempDir::new("test_failed_commit_leaves_tree_clean").unwrap();
+        let config = Config {
+            dl: String::from("http://localhost/dl"),
+            api: String::from("http://localhost/api"),
+        };
+        let idx = PackageIndex::init(&root, &config).unwrap();
+        idx.publish(&pkg("foo", "0.1.0"), None).unwrap();
+
+        // Something else holds the branch, so every commit fails after the
+        // package file has been written and staged.
+        let branch = idx.repo.head().unwrap().name().unwrap().to_string();
+        let lock = idx.repo.path().join(format!("{}.lock", branch));
+        std::fs::write(&lock, "").unwrap();
+        assert!(idx.publish(&pkg("foo", "0.2.0"), None).is_err());
+        assert!(idx.publish(&pkg("bar", "0.1.0"), None).is_err());
+        assert!(idx
+            .set_yanked("foo", &"0.1.0".parse().unwrap(), true, None)
+            .is_err());
+        assert!(idx
+            .delete_version("foo", &"0.1.0".parse().unwrap(), None)
+            .is_err());
+
+        let mut opts = StatusOptions::new();
+        opts.include_untracked(true).recurse_untracked_dirs(true);
+        assert!(idx.repo.statuses(Some(&mut opts)).unwrap().is_empty());
+
+        // The next publish commits only its own file, and the failed one can
+        // be retried.
+        std::fs::remove_file(&lock).unwrap();
+        idx.publish(&pkg("bar", "0.1.0"), None).unwrap();
+        let head = idx.repo.head().unwrap().peel_to_commit().unwrap();
+        let diff = idx
+            .repo
+            .diff_tree_to_tree(
+                Some(&head.parent(0).unwrap().tree().unwrap()),
+                Some(&head.tree().unwrap()),
+                None,
+            )
+            .unwrap();
+        let changed: Vec<_> = diff
+            .deltas()
+            .map(|delta| delta.new_file().path().unwrap().to_path_buf())
+            .collect();
+        assert_eq!(vec![PathBuf::from("3/b/bar")], changed);
+        idx.publish(&pkg("foo", "0.2.0"), None).unwrap();
+        assert!(!idx.get_package_versions("foo").unwrap()[0].yanked);
+    }
+
+    #[test]
+    fn test_recover_interrupted_writes() {
+        let root = TempDir::new("test_recover_interrupted_writes").unwrap();
+        let config = Config {
+            dl: String::from("http://localhost/dl"),
+            api: String::from("http://localhost/api"),
+        };
+        let idx = PackageIndex::init(&root, &config).unwrap();
+        idx.publish(&pkg("foo", "0.1.0"), None).unwrap();
+        let commits = idx.get_repo_log().unwrap().len();
+        drop(idx);
+
+        // A publish of a new version and one of a new crate, both cut off
+        // before their commit, while git held its lock.
+        let foo = root.path().join("3/f/foo");
+        let mut contents = std::fs::read_to_string(&foo).unwrap();
+        contents.push_str("{\"name\":\"foo\",\"vers\":\"0.2");
+        std::fs::write(&foo, contents).unwrap();
+        std::fs::create_dir_all(root.path().join("3/b")).unwrap();
+        std::fs::write(root.path().join("3/b/bar"), "").unwrap();
+        std::fs::write(root.path().join(".git/index.lock"), "").unwrap();
+
+        // Opening the index leaves them be, they could be from a write still
+        // going on.
+        drop(PackageIndex::init(&root, &config).unwrap());
+        assert!(root.path().join(".git/index.lock").exists());
+        assert!(root.path().join("3/b/bar").exists());
+
+        assert_eq!(3, PackageIndex::recover(&root).unwrap().len());
+        let empty = TempDir::new("test_recover_empty").unwrap();
+        assert!(PackageIndex::recover(&empty).unwrap().is_empty());
+        let idx = PackageIndex::init(&root, &config).unwrap();
+
+        assert!(!root.path().join(".git/index.lock").exists());
+        assert!(!root.path().join("3/b/bar").exists());
+        assert!(idx.repo.statuses(None).unwrap().is_empty());
+        assert_eq!(commits, idx.get_repo_log().unwrap().len());
+        assert_eq!(1, idx.get_package_versions("foo").unwrap().len());
+        assert_eq!(vec!["foo"], idx.list_crates());
+    }
+
+    #[test]
+    fn test_server_info_written() {
+        let root = TempDir::new("test_server_info_written").unwrap();
+
+        let idx = PackageIndex::init(
+            &root,
+            &Config {
+                dl: String::from("http://localhost/dl"),
+                api: String::from("http://localhost/api"),
+            },
+        )
+        .unwrap();
+
+        let head = idx.repo.head().unwrap();
+        let expected = format!("{}\t{}\n", head.target().unwrap(), head.name().unwrap());
+        let git_dir = root.path().join(".git");
+        assert_eq!(
+            expected,
+            std::fs::read_to_string(git_dir.join("info").join("refs")).unwrap()
+        );
+        assert!(git_dir.join("objects").join("info").join("packs").exists());
+    }
+
     #[test]
     fn test_config_change_updates_repo() {
         let root = TempDir::new("test_config_change_updates").unwrap();
@@ -729,6 +1432,7 @@ mod tests {
             features: Default::default(),
             yanked: false,
             links: None,
+            extra: Default::default(),
         };
 
         let root = TempDir::new("test_publish_create_happy").unwrap();
@@ -740,7 +1444,7 @@ mod tests {
 
         let idx = PackageIndex::init(&root, &config).unwrap();
 
-        idx.publish(&pkg).unwrap();
+        idx.publish(&pkg, None).unwrap();
 
         let entries = idx.get_repo_log().unwrap();
 
@@ -757,6 +1461,27 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_publish_author_is_acting_user() {
+        let pkg = pkg("foo", "0.1.0");
+
+        let root = TempDir::new("test_publish_author_is_acting_user").unwrap();
+
+        let config = Config {
+            dl: String::from("http://localhost/dl"),
+            api: String::from("http://localhost/api"),
+        };
+
+        let idx = PackageIndex::init(&root, &config).unwrap();
+
+        idx.publish(&pkg, Some("alice")).unwrap();
+
+        let repo = Repository::open(&root).unwrap();
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(Some("alice"), head.author().name());
+        assert_eq!(Some("estuary"), head.committer().name());
+    }
+
     #[test]
     fn test_publish_same_vers_twice_is_err() {
         let pkg = PackageVersion {
@@ -767,6 +1492,7 @@ mod tests {
             features: Default::default(),
             yanked: false,
             links: None,
+            extra: Default::default(),
         };
 
         let root = TempDir::new("test_publish_same_vers_twice_is_err").unwrap();
@@ -778,8 +1504,8 @@ mod tests {
 
         let idx = PackageIndex::init(&root, &config).unwrap();
 
-        idx.publish(&pkg).unwrap();
-        assert!(idx.publish(&pkg).is_err());
+        idx.publish(&pkg, None).unwrap();
+        assert!(idx.publish(&pkg, None).is_err());
 
         let entries = idx.get_repo_log().unwrap();
 
@@ -805,6 +1531,7 @@ mod tests {
             features: Default::default(),
             yanked: false,
             links: None,
+            extra: Default::default(),
         };
 
         let root = TempDir::new("test_yank").unwrap();
@@ -816,8 +1543,8 @@ mod tests {
 
         let idx = PackageIndex::init(&root, &config).unwrap();
 
-        idx.publish(&pkg).unwrap();
-        idx.set_yanked(&pkg.name, &pkg.vers, true).unwrap();
+        idx.publish(&pkg, None).unwrap();
+        idx.set_yanked(&pkg.name, &pkg.vers, true, None).unwrap();
 
         let entries = idx.get_repo_log().unwrap();
 
@@ -844,6 +1571,7 @@ mod tests {
             features: Default::default(),
             yanked: false,
             links: None,
+            extra: Default::default(),
         };
 
         let root = TempDir::new("test_unyank").unwrap();
@@ -855,10 +1583,10 @@ mod tests {
 
         let idx = PackageIndex::init(&root, &config).unwrap();
 
-        idx.publish(&pkg).unwrap();
+        idx.publish(&pkg, None).unwrap();
 
-        idx.set_yanked(&pkg.name, &pkg.vers, true).unwrap();
-        idx.set_yanked(&pkg.name, &pkg.vers, false).unwrap();
+        idx.set_yanked(&pkg.name, &pkg.vers, true, None).unwrap();
+        idx.set_yanked(&pkg.name, &pkg.vers, false, None).unwrap();
 
         let entries = idx.get_repo_log().unwrap();
 
@@ -894,6 +1622,7 @@ mod tests {
             features: Default::default(),
             yanked: false,
             links: None,
+            extra: Default::default(),
         };
 
         let root = TempDir::new("test_double_yank").unwrap();
@@ -905,10 +1634,18 @@ mod tests {
 
         let idx = PackageIndex::init(&root, &config).unwrap();
 
-        idx.publish(&pkg).unwrap();
+        idx.publish(&pkg, None).unwrap();
 
-        idx.set_yanked(&pkg.name, &pkg.vers, true).unwrap();
-        idx.set_yanked(&pkg.name, &pkg.vers, true).unwrap();
+        assert!(idx.set_yanked(&pkg.name, &pkg.vers, true, None).unwrap());
+        assert!(!idx.set_yanked(&pkg.name, &pkg.vers, true, None).unwrap());
+        assert!(matches!(
+            idx.set_yanked(&pkg.name, &"0.2.0".parse().unwrap(), true, None),
+            Err(PackageIndexError::VersionNotFound(..))
+        ));
+        assert!(matches!(
+            idx.set_yanked("bar", &pkg.vers, true, None),
+            Err(PackageIndexError::CrateNotFound(_))
+        ));
 
         let entries = idx.get_repo_log().unwrap();
         assert_eq!(entries.len(), 4);
@@ -932,6 +1669,7 @@ mod tests {
             features: Default::default(),
             yanked: false,
             links: None,
+            extra: Default::default(),
         };
 
         let root = TempDir::new("test_double_yank").unwrap();
@@ -943,10 +1681,10 @@ mod tests {
 
         let idx = PackageIndex::init(&root, &config).unwrap();
 
-        idx.publish(&pkg).unwrap();
+        idx.publish(&pkg, None).unwrap();
 
-        idx.set_yanked(&pkg.name, &pkg.vers, false).unwrap();
-        idx.set_yanked(&pkg.name, &pkg.vers, false).unwrap();
+        idx.set_yanked(&pkg.name, &pkg.vers, false, None).unwrap();
+        idx.set_yanked(&pkg.name, &pkg.vers, false, None).unwrap();
 
         let entries = idx.get_repo_log().unwrap();
 
@@ -1011,7 +1749,7 @@ mod tests {
 
         let idx = PackageIndex::init(&root, &config).unwrap();
 
-        assert_eq!(Vec::<String>::new(), idx.list_crates().unwrap());
+        assert_eq!(Vec::<String>::new(), idx.list_crates());
     }
 
     #[test]
@@ -1024,6 +1762,7 @@ mod tests {
             features: Default::default(),
             yanked: false,
             links: None,
+            extra: Default::default(),
         };
 
         let root = TempDir::new("test_list_crates_one").unwrap();
@@ -1034,8 +1773,8 @@ mod tests {
         };
 
         let idx = PackageIndex::init(&root, &config).unwrap();
-        idx.publish(&pkg).unwrap();
-        assert_eq!(vec!["foo"], idx.list_crates().unwrap());
+        idx.publish(&pkg, None).unwrap();
+        assert_eq!(vec!["foo"], idx.list_crates());
     }
 
     #[test]
@@ -1048,6 +1787,7 @@ mod tests {
             features: Default::default(),
             yanked: false,
             links: None,
+            extra: Default::default(),
         };
 
         let pkg2 = PackageVersion {
@@ -1058,6 +1798,7 @@ mod tests {
             features: Default::default(),
             yanked: false,
             links: None,
+            extra: Default::default(),
         };
 
         let root = TempDir::new("test_list_crates_two").unwrap();
@@ -1069,9 +1810,9 @@ mod tests {
 
         let idx = PackageIndex::init(&root, &config).unwrap();
 
-        idx.publish(&pkg1).unwrap();
-        idx.publish(&pkg2).unwrap();
-        let mut crates = idx.list_crates().unwrap();
+        idx.publish(&pkg1, None).unwrap();
+        idx.publish(&pkg2, None).unwrap();
+        let mut crates = idx.list_crates();
         crates.sort();
         assert_eq!(vec!["bar", "foo"], crates);
     }
@@ -1090,20 +1831,377 @@ mod tests {
         let idx = PackageIndex::init(&root, &config).unwrap();
 
         for name in &names {
-            idx.publish(&PackageVersion {
-                name: name.to_string(),
-                vers: "0.1.0".parse().unwrap(),
-                deps: vec![],
-                cksum: "".to_string(),
-                features: Default::default(),
-                yanked: false,
-                links: None,
-            })
-            .unwrap();
+            idx.publish(&pkg(name, "0.1.0"), None).unwrap();
         }
 
-        let mut crates = idx.list_crates().unwrap();
+        let mut crates = idx.list_crates();
         crates.sort();
         assert_eq!(names.to_vec(), crates);
     }
+
+    fn dep_on(name: &str, registry: Option<&str>) -> Dependency {
+        Dependency {
+            name: name.to_string(),
+            req: "^0.1".to_string(),
+            features: vec![],
+            optional: false,
+            default_features: true,
+            target: None,
+            kind: DependencyKind::Normal,
+            registry: registry.map(String::from),
+            package: None,
+        }
+    }
+
+    #[test]
+    fn test_dependents_updated_on_publish() {
+        let root = TempDir::new("test_dependents_updated_on_publish").unwrap();
+
+        let config = Config {
+            dl: String::from("http://localhost/dl"),
+            api: String::from("http://localhost/api"),
+        };
+
+        let idx = PackageIndex::init(&root, &config).unwrap();
+
+        idx.publish(&pkg("foo", "0.1.0"), None).unwrap();
+
+        assert!(idx.get_dependents("foo").is_empty());
+
+        idx.publish(
+            &PackageVersion {
+                deps: vec![
+                    dep_on("foo", None),
+                    // Same name, but from some other registry.
+                    dep_on("baz", Some("https://github.com/rust-lang/crates.io-index")),
+                ],
+                ..pkg("bar", "0.2.0")
+            },
+            None,
+        )
+        .unwrap();
+
+        let dependents = idx.get_dependents("foo");
+        assert_eq!(1, dependents.len());
+        assert_eq!("bar", dependents[0].name);
+        assert_eq!("0.2.0", dependents[0].vers.to_string());
+        assert!(idx.get_dependents("baz").is_empty());
+    }
+
+    #[test]
+    fn test_dependents_loaded_on_init() {
+        let root = TempDir::new("test_dependents_loaded_on_init").unwrap();
+
+        let config = Config {
+            dl: String::from("http://localhost/dl"),
+            api: String::from("http://localhost/api"),
+        };
+
+        let mut renamed = dep_on("foo2", None);
+        renamed.package = Some("foo".to_string());
+
+        {
+            let idx = PackageIndex::init(&root, &config).unwrap();
+            idx.publish(
+                &PackageVersion {
+                    deps: vec![dep_on("foo", None)],
+                    ..pkg("bar", "0.1.0")
+                },
+                None,
+            )
+            .unwrap();
+            idx.publish(
+                &PackageVersion {
+                    deps: vec![renamed],
+                    ..pkg("abc", "1.0.0")
+                },
+                None,
+            )
+            .unwrap();
+        }
+
+        let idx = PackageIndex::init(&root, &config).unwrap();
+        let dependents = idx.get_dependents("foo");
+        assert_eq!(
+            vec!["abc", "bar"],
+            dependents
+                .iter()
+                .map(|d| d.name.as_str())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_latest_dependents() {
+        let root = TempDir::new("test_latest_dependents").unwrap();
+
+        let config = Config {
+            dl: String::from("http://localhost/dl"),
+            api: String::from("http://localhost/api"),
+        };
+
+        let idx = PackageIndex::init(&root, &config).unwrap();
+        for vers in &["0.1.0", "0.3.0", "0.2.0"] {
+            idx.publish(
+                &PackageVersion {
+                    deps: vec![dep_on("foo", None)],
+                    ..pkg("bar", vers)
+                },
+                None,
+            )
+            .unwrap();
+        }
+
+        assert_eq!(3, idx.get_dependents("foo").len());
+        let latest = idx.get_latest_dependents("foo");
+        assert_eq!(1, latest.len());
+        assert_eq!("0.3.0", latest[0].vers.to_string());
+    }
+
+    /// Compare what the cache holds against a fresh read of the package files.
+    fn assert_cache_matches_disk(root: &TempDir, config: &Config, idx: &PackageIndex) {
+        let fresh = PackageIndex::init(root, config).unwrap();
+        assert_eq!(fresh.list_crates(), idx.list_crates());
+        for name in fresh.list_crates() {
+            assert_eq!(
+                fresh.parse_package_file(&name).unwrap(),
+                idx.get_package_versions(&name).unwrap()
+            );
+            assert_eq!(fresh.get_dependents(&name), idx.get_dependents(&name));
+        }
+    }
+
+    #[test]
+    fn test_cache_consistency() {
+        let root = TempDir::new("test_cache_consistency").unwrap();
+
+        let config = Config {
+            dl: String::from("http://localhost/dl"),
+            api: String::from("http://localhost/api"),
+        };
+
+        let idx = PackageIndex::init(&root, &config).unwrap();
+        assert_cache_matches_disk(&root, &config, &idx);
+
+        let foo = pkg("foo", "0.1.0");
+        let bar = PackageVersion {
+            deps: vec![dep_on("foo", None)],
+            ..pkg("bar", "1.0.0")
+        };
+
+        idx.publish(&foo, None).unwrap();
+        assert_cache_matches_disk(&root, &config, &idx);
+
+        idx.publish(&bar, None).unwrap();
+        assert_cache_matches_disk(&root, &config, &idx);
+
+        idx.publish(
+            &PackageVersion {
+                vers: "0.2.0".parse().unwrap(),
+                ..foo.clone()
+            },
+            None,
+        )
+        .unwrap();
+        assert_cache_matches_disk(&root, &config, &idx);
+
+        idx.set_yanked(&foo.name, &foo.vers, true, None).unwrap();
+        assert_cache_matches_disk(&root, &config, &idx);
+        assert!(idx.get_package_versions("foo").unwrap()[0].yanked);
+
+        idx.set_yanked(&foo.name, &foo.vers, false, None).unwrap();
+        assert_cache_matches_disk(&root, &config, &idx);
+        assert!(!idx.get_package_versions("foo").unwrap()[0].yanked);
+    }
+
+    #[test]
+    fn test_cache_unchanged_by_failed_publish() {
+        let pkg = PackageVersion {
+            deps: vec![dep_on("bar", None)],
+            ..pkg("foo", "0.1.0")
+        };
+
+        let root = TempDir::new("test_cache_unchanged_by_failed_publish").unwrap();
+
+        let config = Config {
+            dl: String::from("http://localhost/dl"),
+            api: String::from("http://localhost/api"),
+        };
+
+        let idx = PackageIndex::init(&root, &config).unwrap();
+
+        idx.publish(&pkg, None).unwrap();
+        assert!(idx.publish(&pkg, None).is_err());
+
+        assert_eq!(1, idx.get_package_versions("foo").unwrap().len());
+        assert_eq!(1, idx.get_dependents("bar").len());
+        assert_cache_matches_disk(&root, &config, &idx);
+    }
+
+    #[test]
+    fn test_get_package_versions_unknown_crate() {
+        let root = TempDir::new("test_get_package_versions_unknown_crate").unwrap();
+
+        let config = Config {
+            dl: String::from("http://localhost/dl"),
+            api: String::from("http://localhost/api"),
+        };
+
+        let idx = PackageIndex::init(&root, &config).unwrap();
+
+        assert!(matches!(
+            idx.get_package_versions("foo"),
+            Err(PackageIndexError::CrateNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_publishes() {
+        let root = TempDir::new("test_publishes").unwrap();
+        let config = Config {
+            dl: String::from("http://localhost/dl"),
+            api: String::from("http://localhost/api"),
+        };
+        let idx = PackageIndex::init(&root, &config).unwrap();
+        for (name, vers) in &[("foo", "0.1.0"), ("bar", "0.1.0"), ("foo", "0.2.0")] {
+            let pkg = pkg(name, vers);
+            idx.publish(&pkg, None).unwrap();
+        }
+        idx.set_yanked("bar", &"0.1.0".parse().unwrap(), true, None)
+            .unwrap();
+
+        let names = |publishes: Vec<Publish>| -> Vec<String> {
+            publishes
+                .into_iter()
+                .map(|p| format!("{} {}", p.name, p.vers))
+                .collect()
+        };
+
+        let reader = idx.reader();
+        assert_eq!(
+            vec!["foo 0.2.0", "bar 0.1.0", "foo 0.1.0"],
+            names(reader.get_publishes(10))
+        );
+        assert_eq!(vec!["foo 0.2.0"], names(reader.get_publishes(1)));
+        assert_eq!(
+            vec!["bar 0.1.0", "foo 0.1.0"],
+            names(reader.get_new_crates(10))
+        );
+        assert_eq!(
+            vec!["foo 0.2.0", "bar 0.1.0"],
+            names(reader.get_updated_crates(10))
+        );
+        assert!(reader.get_publishes(1)[0].timestamp > 0);
+
+        // The same history is read back from the reflog, even after a squash.
+        idx.squash().unwrap();
+        drop(idx);
+        let idx = PackageIndex::init(&root, &config).unwrap();
+        assert_eq!(
+            vec!["foo 0.2.0", "bar 0.1.0", "foo 0.1.0"],
+            names(idx.reader().get_publishes(10))
+        );
+    }
+
+    #[test]
+    fn test_delete_version() {
+        let root = TempDir::new("test_delete_version").unwrap();
+        let config = Config {
+            dl: String::from("http://localhost/dl"),
+            api: String::from("http://localhost/api"),
+        };
+        let idx = PackageIndex::init(&root, &config).unwrap();
+        for vers in &["0.1.0", "0.2.0"] {
+            let pkg = pkg("foo", vers);
+            idx.publish(&pkg, None).unwrap();
+        }
+
+        let v1: semver::Version = "0.1.0".parse().unwrap();
+        let v2: semver::Version = "0.2.0".parse().unwrap();
+        idx.delete_version("foo", &v1, Some("alice")).unwrap();
+        assert!(matches!(
+            idx.delete_version("foo", &v1, None),
+            Err(PackageIndexError::VersionNotFound(_, _))
+        ));
+
+        let versions: Vec<_> = idx
+            .parse_package_file("foo")
+            .unwrap()
+            .into_iter()
+            .map(|p| p.vers)
+            .collect();
+        assert_eq!(vec![v2.clone()], versions);
+        assert_eq!(
+            vec![v2.clone()],
+            idx.reader()
+                .get_package_versions("foo")
+                .unwrap()
+                .into_iter()
+                .map(|p| p.vers)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(1, idx.reader().get_publishes(10).len());
+
+        // Deleting the last version takes the package file with it.
+        idx.delete_version("foo", &v2, None).unwrap();
+        assert!(!root.path().join("3/f/foo").exists());
+        assert!(idx.reader().list_crates().is_empty());
+        assert!(matches!(
+            idx.delete_version("foo", &v2, None),
+            Err(PackageIndexError::CrateNotFound(_))
+        ));
+
+        let repo = Repository::open(&root).unwrap();
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(Some("delete crate: `foo v0.2.0`"), head.message());
+        assert!(head.tree().unwrap().get_path(Path::new("3/f/foo")).is_err());
+        assert!(repo.statuses(None).unwrap().is_empty());
+
+        // Deleted versions don't come back from the reflog.
+        drop(idx);
+        let idx = PackageIndex::init(&root, &config).unwrap();
+        assert!(idx.reader().get_publishes(10).is_empty());
+    }
+
+    #[test]
+    fn test_squash() {
+        let root = TempDir::new("test_squash").unwrap();
+        let config = Config {
+            dl: String::from("http://localhost/dl"),
+            api: String::from("http://localhost/api"),
+        };
+        let idx = PackageIndex::init(&root, &config).unwrap();
+        idx.publish(&pkg("foo", "0.1.0"), None).unwrap();
+
+        let old = idx.repo.head().unwrap().peel_to_commit().unwrap();
+        let archive = idx.squash().unwrap().unwrap();
+
+        let new = idx.repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(0, new.parent_count());
+        assert_eq!(old.tree_id(), new.tree_id());
+        assert_eq!(old.id(), idx.repo.refname_to_id(&archive).unwrap());
+
+        // Nothing new to squash.
+        assert!(idx.squash().unwrap().is_none());
+
+        // Business as usual afterwards.
+        idx.set_yanked("foo", &"0.1.0".parse().unwrap(), true, None)
+            .unwrap();
+        let head = idx.repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(new.id(), head.parent_id(0).unwrap());
+        assert!(
+            PackageIndex::init(&root, &config)
+                .unwrap()
+                .get_package_versions("foo")
+                .unwrap()[0]
+                .yanked
+        );
+
+        // A second squash straight away, likely within the same second, gets
+        // its own archive ref.
+        let again = idx.squash().unwrap().unwrap();
+        assert_ne!(archive, again);
+        assert_eq!(old.id(), idx.repo.refname_to_id(&archive).unwrap());
+        assert_eq!(head.id(), idx.repo.refname_to_id(&again).unwrap());
+    }
 }
@@ -0,0 +1,272 @@
+//! Serialized access to the index for the web handlers.
+//!
+//! The git repo behind the index can't be shared between threads and every
+//! change to it ends with a commit, so all changes are funneled through a
+//! single writer task. The writer hands each job off to the blocking thread
+//! pool so git never runs on the async executor.
+//!
+//! Reads don't need the repo at all and are served directly by the
+//! [`IndexReader`] held in the handle.
+
+use super::{IndexReader, PackageIndex, PackageVersion, Result};
+use crate::errors::PackageIndexError;
+use crate::metrics;
+use actix_web::web;
+use futures::channel::{mpsc, oneshot};
+use futures::StreamExt;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+type Job = Box<dyn FnOnce(&PackageIndex) + Send>;
+
+/// A cloneable handle to a [`PackageIndex`] owned by the writer task.
+#[derive(Clone)]
+pub struct IndexHandle {
+    reader: IndexReader,
+    jobs: mpsc::UnboundedSender<Job>,
+}
+
+impl IndexHandle {
+    /// Hand the index over to a new writer task.
+    ///
+    /// This must be called from within a running actix system.
+    pub fn spawn(index: PackageIndex) -> Self {
+        let reader = index.reader();
+        let (jobs, mut rx) = mpsc::unbounded::<Job>();
+
+        // The mutex is never contended (jobs run one at a time); it's only here
+        // so the index can be lent to the blocking thread pool for each job.
+        let index = Arc::new(Mutex::new(index));
+        actix_web::rt::spawn(async move {
+            while let Some(job) = rx.next().await {
+                let index = index.clone();
+                let res = web::block(move || {
+                    let index = index.lock().unwrap_or_else(PoisonError::into_inner);
+                    job(&index)
+                })
+                .await;
+
+                if let Err(e) = res {
+                    log::error!("Index write failed to complete: {}", e);
+                }
+            }
+            log::debug!("Index writer shutting down.");
+        });
+
+        IndexHandle { reader, jobs }
+    }
+
+    /// Queue a change to the index, waiting for it to complete.
+    ///
+    /// Changes are applied one at a time in the order they're queued.
+    pub async fn write<F, T>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&PackageIndex) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let queued = Instant::now();
+        self.jobs
+            .unbounded_send(Box::new(move |index| {
+                metrics::INDEX_WAIT.observe(queued.elapsed().as_secs_f64());
+                // The receiver may have given up waiting, which is fine.
+                let _ = tx.send(f(index));
+            }))
+            .map_err(|_| PackageIndexError::WriterUnavailable)?;
+
+        // A job that panics drops the sender without replying.
+        rx.await.map_err(|_| PackageIndexError::WriterUnavailable)?
+    }
+
+    /// Wait for every change queued so far to finish, then stop taking new
+    /// ones.
+    ///
+    /// This is for shutting down: once the server has stopped, draining the
+    /// queue makes sure no change is cut off half way through its commit.
+    pub async fn drain(&self) -> Result<()> {
+        // Jobs run in order, so once this one has run so have all the others.
+        let res = self.write(|_| Ok(())).await;
+        self.jobs.close_channel();
+        res
+    }
+
+    /// See [`PackageIndex::publish`].
+    pub async fn publish(&self, pkg: PackageVersion, author: Option<String>) -> Result<()> {
+        self.write(move |index| index.publish(&pkg, author.as_deref()))
+            .await
+    }
+
+    /// See [`PackageIndex::set_yanked`].
+    pub async fn set_yanked(
+        &self,
+        name: String,
+        version: semver::Version,
+        yanked: bool,
+        author: Option<String>,
+    ) -> Result<bool> {
+        self.write(move |index| index.set_yanked(&name, &version, yanked, author.as_deref()))
+            .await
+    }
+
+    /// See [`PackageIndex::delete_version`].
+    pub async fn delete_version(
+        &self,
+        name: String,
+        version: semver::Version,
+        author: Option<String>,
+    ) -> Result<()> {
+        self.write(move |index| index.delete_version(&name, &version, author.as_deref()))
+            .await
+    }
+
+    /// See [`PackageIndex::squash`].
+    pub async fn squash(&self) -> Result<Option<String>> {
+        self.write(|index| index.squash()).await
+    }
+
+    /// Squash the index every `period`, starting one `period` from now.
+    ///
+    /// This must be called from within a running actix system. A zero
+    /// `period` is refused, rather than squashing in a tight loop.
+    pub fn schedule_squash(&self, period: Duration) {
+        if period.is_zero() {
+            log::error!("Not squashing the index, the interval must be more than zero.");
+            return;
+        }
+        let handle = self.clone();
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(period);
+            // The first tick completes immediately.
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                match handle.squash().await {
+                    Ok(Some(archive)) => {
+                        log::info!("Squashed index history, archived to `{}`.", archive)
+                    }
+                    Ok(None) => log::debug!("No index history to squash."),
+                    Err(e) => log::error!("Failed to squash index history: {}", e),
+                }
+            }
+        });
+    }
+}
+
+impl Deref for IndexHandle {
+    type Target = IndexReader;
+
+    fn deref(&self) -> &Self::Target {
+        &self.reader
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::package_index::Config;
+    use crate::test_helpers::pkg;
+    use tempdir::TempDir;
+
+    fn get_handle(root: &TempDir) -> IndexHandle {
+        let config = Config {
+            dl: String::from("http://localhost/dl"),
+            api: String::from("http://localhost/api"),
+        };
+        IndexHandle::spawn(PackageIndex::init(root, &config).unwrap())
+    }
+
+    #[actix_rt::test]
+    async fn test_writes_are_visible_to_readers() {
+        let root = TempDir::new("test_writes_are_visible_to_readers").unwrap();
+        let handle = get_handle(&root);
+
+        handle.publish(pkg("foo", "0.1.0"), None).await.unwrap();
+        handle
+            .set_yanked("foo".to_string(), "0.1.0".parse().unwrap(), true, None)
+            .await
+            .unwrap();
+
+        let versions = handle.get_package_versions("foo").unwrap();
+        assert_eq!(1, versions.len());
+        assert!(versions[0].yanked);
+    }
+
+    #[actix_rt::test]
+    async fn test_concurrent_publishes_are_serialized() {
+        let root = TempDir::new("test_concurrent_publishes_are_serialized").unwrap();
+        let handle = get_handle(&root);
+
+        let results = futures::future::join_all(
+            (0..10).map(|minor| handle.publish(pkg("foo", &format!("0.{}.0", minor)), None)),
+        )
+        .await;
+
+        assert!(results.iter().all(|res| res.is_ok()));
+        assert_eq!(10, handle.get_package_versions("foo").unwrap().len());
+    }
+
+    #[actix_rt::test]
+    async fn test_drain_finishes_queued_writes() {
+        let root = TempDir::new("test_drain_finishes_queued_writes").unwrap();
+        let handle = get_handle(&root);
+
+        // Queued, but not awaited by anyone.
+        for minor in 0..5 {
+            actix_web::rt::spawn({
+                let handle = handle.clone();
+                async move {
+                    handle
+                        .publish(pkg("foo", &format!("0.{}.0", minor)), None)
+                        .await
+                }
+            });
+        }
+        actix_web::rt::task::yield_now().await;
+        handle.drain().await.unwrap();
+
+        assert_eq!(5, handle.get_package_versions("foo").unwrap().len());
+        assert!(matches!(
+            handle.publish(pkg("foo", "1.0.0"), None).await,
+            Err(PackageIndexError::WriterUnavailable)
+        ));
+    }
+
+    #[actix_rt::test]
+    async fn test_writer_survives_errors() {
+        let root = TempDir::new("test_writer_survives_errors").unwrap();
+        let handle = get_handle(&root);
+
+        handle.publish(pkg("foo", "0.1.0"), None).await.unwrap();
+        assert!(handle.publish(pkg("foo", "0.1.0"), None).await.is_err());
+
+        let panicked: Result<()> = handle.write(|_| panic!("oh no")).await;
+        assert!(matches!(
+            panicked,
+            Err(PackageIndexError::WriterUnavailable)
+        ));
+
+        handle.publish(pkg("foo", "0.2.0"), None).await.unwrap();
+        assert_eq!(2, handle.get_package_versions("foo").unwrap().len());
+    }
+
+    #[actix_rt::test]
+    async fn test_schedule_squash() {
+        let root = TempDir::new("test_schedule_squash").unwrap();
+        let handle = get_handle(&root);
+        handle.publish(pkg("foo", "0.1.0"), None).await.unwrap();
+
+        handle.schedule_squash(Duration::from_millis(50));
+        actix_web::rt::time::sleep(Duration::from_millis(200)).await;
+
+        let repo = git2::Repository::open(root.path()).unwrap();
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(0, head.parent_count());
+        assert!(head.tree().unwrap().get_path("3/f/foo".as_ref()).is_ok());
+
+        // Refused, rather than panicking in the interval.
+        handle.schedule_squash(Duration::ZERO);
+        actix_web::rt::time::sleep(Duration::from_millis(50)).await;
+        handle.publish(pkg("foo", "0.2.0"), None).await.unwrap();
+    }
+}
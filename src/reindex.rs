@@ -0,0 +1,349 @@
+//! Rebuilding the index from crate storage.
+//!
+//! Every `.crate` file carries the manifest it was published with, which has
+//! everything the index records about a version except whether it's yanked.
+//! That comes from the database, where it's known.
+//!
+//! The new index is built next to the old one and only swapped in once it's
+//! complete. The old one is kept, renamed, in case anything in it is needed.
+
+use crate::crate_source;
+use crate::database::Database;
+use crate::errors::EstuaryError;
+use crate::package_index::{Config, Dependency, DependencyKind, PackageIndex, PackageVersion};
+use crate::storage::{self, invalid, sibling, StoredCrate};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// What a reindex did.
+#[derive(Debug, Default)]
+pub struct Summary {
+    /// Versions added to the new index.
+    pub indexed: usize,
+    /// Of those, the ones marked as yanked.
+    pub yanked: usize,
+    /// `.crate` files which couldn't be indexed, and why.
+    pub skipped: Vec<(PathBuf, String)>,
+    /// Where the old index was moved to, if there was one.
+    pub old_index: Option<PathBuf>,
+}
+
+/// Build a fresh index at `index_dir` from the `.crate` files in `crate_dir`.
+///
+/// Versions are published to the new index in the order they were originally
+/// published, as far as the database knows, so the release history reads the
+/// same (though with today's dates).
+pub async fn reindex(
+    index_dir: &Path,
+    crate_dir: &Path,
+    config: &Config,
+    db: &dyn Database,
+) -> Result<Summary, EstuaryError> {
+    let mut summary = Summary::default();
+
+    let published: HashMap<(String, String), usize> = db
+        .list_crate_versions()
+        .await?
+        .into_iter()
+        .enumerate()
+        .map(|(i, key)| (key, i))
+        .collect();
+    let yanked = db.get_yanked_versions().await?;
+
+    let mut stored = vec![];
+    for krate in storage::list_crate_files(crate_dir)? {
+        match krate.vers.parse::<semver::Version>() {
+            Ok(vers) => stored.push((krate, vers)),
+            Err(e) => summary.skipped.push((krate.path, e.to_string())),
+        }
+    }
+    // Anything the database doesn't know about goes last.
+    stored.sort_by_cached_key(|(krate, vers)| {
+        let order = published
+            .get(&(krate.name.clone(), krate.vers.clone()))
+            .copied();
+        (order.is_none(), order, krate.name.clone(), vers.clone())
+    });
+
+    let staging = sibling(index_dir, "reindex");
+    match std::fs::remove_dir_all(&staging) {
+        Ok(()) => log::warn!(
+            "Removed `{}` left by an earlier reindex.",
+            staging.display()
+        ),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    let index = PackageIndex::init(&staging, config)?;
+
+    for (krate, vers) in stored {
+        let mut pkg = match index_entry(&krate, &vers) {
+            Ok(pkg) => pkg,
+            Err(e) => {
+                summary.skipped.push((krate.path, e.to_string()));
+                continue;
+            }
+        };
+        pkg.yanked = yanked.contains(&(krate.name.clone(), krate.vers.clone()));
+        index.publish(&pkg, None)?;
+        summary.indexed += 1;
+        if pkg.yanked {
+            summary.yanked += 1;
+        }
+    }
+    drop(index);
+
+    if index_dir.exists() {
+        let old = sibling(
+            index_dir,
+            &format!("old-{}", chrono::Utc::now().format("%Y%m%d%H%M%S")),
+        );
+        std::fs::rename(index_dir, &old)?;
+        summary.old_index = Some(old);
+    }
+    std::fs::rename(&staging, index_dir)?;
+    Ok(summary)
+}
+
+/// Work out the index entry for a stored `.crate` file.
+pub(crate) fn index_entry(
+    krate: &StoredCrate,
+    vers: &semver::Version,
+) -> io::Result<PackageVersion> {
+    let cksum = format!("{:x}", Sha256::digest(&std::fs::read(&krate.path)?));
+    let manifest = crate_source::read_manifest(&krate.path, &krate.name, vers)?;
+
+    let package = manifest
+        .get("package")
+        .ok_or_else(|| invalid(String::from("Cargo.toml has no [package]")))?;
+    let name = package.get("name").and_then(|v| v.as_str());
+    let manifest_vers = package.get("version").and_then(|v| v.as_str());
+    if name != Some(krate.name.as_str()) || manifest_vers != Some(krate.vers.as_str()) {
+        return Err(invalid(format!(
+            "Cargo.toml is for `{} v{}`",
+            name.unwrap_or("?"),
+            manifest_vers.unwrap_or("?")
+        )));
+    }
+
+    let mut deps = vec![];
+    read_deps(&manifest, None, &mut deps)?;
+    if let Some(targets) = manifest.get("target").and_then(|v| v.as_table()) {
+        for (target, table) in targets {
+            read_deps(table, Some(target), &mut deps)?;
+        }
+    }
+
+    let features = match manifest.get("features") {
+        Some(features) => features
+            .clone()
+            .try_into()
+            .map_err(|e| invalid(format!("bad [features]: {}", e)))?,
+        None => HashMap::new(),
+    };
+
+    Ok(PackageVersion {
+        name: krate.name.clone(),
+        vers: vers.clone(),
+        deps,
+        cksum,
+        features,
+        yanked: false,
+        links: package
+            .get("links")
+            .and_then(|v| v.as_str())
+            .map(String::from),
+        extra: Default::default(),
+    })
+}
+
+/// Collect the dependencies from the dependency tables of a manifest, or of
+/// one of its `[target]` tables.
+fn read_deps(
+    table: &toml::Value,
+    target: Option<&str>,
+    acc: &mut Vec<Dependency>,
+) -> io::Result<()> {
+    // The second spelling of each is from older versions of cargo.
+    let kinds = [
+        ("dependencies", DependencyKind::Normal),
+        ("dev-dependencies", DependencyKind::Dev),
+        ("dev_dependencies", DependencyKind::Dev),
+        ("build-dependencies", DependencyKind::Build),
+        ("build_dependencies", DependencyKind::Build),
+    ];
+    for (key, kind) in kinds {
+        let section = match table.get(key).and_then(|v| v.as_table()) {
+            Some(section) => section,
+            None => continue,
+        };
+        for (name, spec) in section {
+            acc.push(read_dep(name, spec, target, kind.clone())?);
+        }
+    }
+    Ok(())
+}
+
+fn read_dep(
+    name: &str,
+    spec: &toml::Value,
+    target: Option<&str>,
+    kind: DependencyKind,
+) -> io::Result<Dependency> {
+    let str_field = |key: &str| spec.get(key).and_then(|v| v.as_str()).map(String::from);
+    let bool_field = |keys: &[&str], default: bool| {
+        keys.iter()
+            .find_map(|key| spec.get(key).and_then(|v| v.as_bool()))
+            .unwrap_or(default)
+    };
+
+    let req = match spec {
+        toml::Value::String(req) => req.clone(),
+        toml::Value::Table(_) => str_field("version").unwrap_or_else(|| String::from("*")),
+        _ => return Err(invalid(format!("bad dependency `{}`", name))),
+    };
+    let features = match spec.get("features") {
+        Some(features) => features
+            .clone()
+            .try_into()
+            .map_err(|e| invalid(format!("bad features for `{}`: {}", name, e)))?,
+        None => vec![],
+    };
+
+    Ok(Dependency {
+        name: name.to_string(),
+        req,
+        features,
+        optional: bool_field(&["optional"], false),
+        default_features: bool_field(&["default-features", "default_features"], true),
+        target: target.map(String::from),
+        kind,
+        registry: str_field("registry-index"),
+        package: str_field("package"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::NewCrateVersion;
+    use crate::test_helpers;
+
+    const MANIFEST: &str = r#"
+        [package]
+        name = "foo"
+        version = "0.2.0"
+        links = "foo"
+
+        [dependencies.serde]
+        version = "^1.0"
+        features = ["derive"]
+        optional = true
+
+        [dependencies.json]
+        version = "^1"
+        package = "serde_json"
+        default-features = false
+        registry-index = "https://github.com/rust-lang/crates.io-index"
+
+        [dev-dependencies.tempdir]
+        version = "^0.3"
+
+        [target."cfg(windows)".build-dependencies.winres]
+        version = "^0.1"
+
+        [features]
+        default = ["serde"]
+    "#;
+
+    #[actix_rt::test]
+    async fn test_reindex() {
+        let data_root = test_helpers::get_data_root();
+        let settings = test_helpers::get_test_settings(data_root.path()).await;
+        let config = Config {
+            dl: String::new(),
+            api: String::new(),
+        };
+        let db = settings.db.as_ref();
+
+        // The old index, which has lost track of everything.
+        PackageIndex::init(&settings.index_dir, &config).unwrap();
+
+        let foo_1 = test_helpers::make_crate_file(
+            "foo",
+            "0.1.0",
+            &[(
+                "Cargo.toml",
+                b"[package]\nname = \"foo\"\nversion = \"0.1.0\"\n",
+            )],
+        );
+        let foo_2 =
+            test_helpers::make_crate_file("foo", "0.2.0", &[("Cargo.toml", MANIFEST.as_bytes())]);
+        let wrong =
+            test_helpers::make_crate_file("bar", "0.1.0", &[("Cargo.toml", MANIFEST.as_bytes())]);
+        for (name, vers, content) in [
+            ("foo", "0.1.0", &foo_1),
+            ("foo", "0.2.0", &foo_2),
+            ("bar", "0.1.0", &wrong),
+        ] {
+            storage::store_crate_file(&settings.crate_dir, name, &vers.parse().unwrap(), content)
+                .unwrap();
+        }
+
+        // 0.2.0 went out first, then got yanked.
+        let admin = db.get_user(String::from("admin")).await.unwrap().unwrap();
+        let krate = db.create_crate(String::from("foo")).await.unwrap();
+        for vers in ["0.2.0", "0.1.0"] {
+            db.add_crate_version(
+                krate.id,
+                admin.id,
+                NewCrateVersion {
+                    vers: vers.to_string(),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        }
+        db.set_yanked(String::from("foo"), String::from("0.2.0"), true)
+            .await
+            .unwrap();
+
+        let summary = reindex(&settings.index_dir, &settings.crate_dir, &config, db)
+            .await
+            .unwrap();
+        assert_eq!(2, summary.indexed);
+        assert_eq!(1, summary.yanked);
+        assert_eq!(1, summary.skipped.len());
+        assert!(summary.skipped[0]
+            .1
+            .contains("Cargo.toml is for `foo v0.2.0`"));
+        assert!(summary.old_index.unwrap().join(".git").exists());
+
+        let index = PackageIndex::init(&settings.index_dir, &config).unwrap();
+        let versions = index.get_package_versions("foo").unwrap();
+        let order: Vec<_> = versions.iter().map(|v| v.vers.to_string()).collect();
+        assert_eq!(vec!["0.2.0", "0.1.0"], order);
+
+        let foo = &versions[0];
+        assert!(foo.yanked);
+        assert!(!versions[1].yanked);
+        assert_eq!(format!("{:x}", Sha256::digest(&foo_2)), foo.cksum);
+        assert_eq!(Some("foo"), foo.links.as_deref());
+        assert_eq!(vec!["serde"], foo.features["default"]);
+
+        let dep = |name: &str| foo.deps.iter().find(|d| d.name == name).unwrap();
+        assert_eq!(4, foo.deps.len());
+        assert_eq!("^1.0", dep("serde").req);
+        assert_eq!(vec!["derive"], dep("serde").features);
+        assert!(dep("serde").optional);
+        assert_eq!(Some("serde_json"), dep("json").package.as_deref());
+        assert!(!dep("json").default_features);
+        assert!(dep("json").registry.is_some());
+        assert_eq!(DependencyKind::Dev, dep("tempdir").kind);
+        assert_eq!(DependencyKind::Build, dep("winres").kind);
+        assert_eq!(Some("cfg(windows)"), dep("winres").target.as_deref());
+    }
+}
@@ -1,7 +1,24 @@
 use std::fs::{self, OpenOptions};
-use std::io::Write;
+use std::io::{self, Write};
 use std::path::{Path, PathBuf};
 
+/// Where files taken out of crate storage by `estuary fsck --repair` go.
+///
+/// `+` can't appear in crate names, so this can't clash with a crate's dir.
+pub const LOST_AND_FOUND: &str = "lost+found";
+
+/// An `InvalidData` error, for files which aren't what they should be.
+pub fn invalid<M: Into<String>>(msg: M) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+/// `dir` with `.{suffix}` added to its name.
+pub fn sibling(dir: &Path, suffix: &str) -> PathBuf {
+    let mut name = dir.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{}", suffix));
+    dir.with_file_name(name)
+}
+
 pub fn get_crate_file_path<P: AsRef<Path>>(root: P, name: &str, vers: &semver::Version) -> PathBuf {
     let dir = root.as_ref().join(name);
     dir.join(&format!("{}-{}.crate", name, vers))
@@ -25,3 +42,120 @@ pub fn store_crate_file<P: AsRef<Path>>(
     fh.write_all(content)?;
     Ok(())
 }
+
+/// Remove a crate file from storage, along with its directory once it's
+/// empty.
+///
+/// A file which is already gone isn't an error.
+pub fn delete_crate_file<P: AsRef<Path>>(
+    root: P,
+    name: &str,
+    vers: &semver::Version,
+) -> std::io::Result<()> {
+    let fp = get_crate_file_path(root.as_ref(), name, vers);
+    match fs::remove_file(&fp) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    // Fails when there are other versions (or docs) left, which is fine.
+    let _ = fs::remove_dir(fp.parent().unwrap());
+    Ok(())
+}
+
+/// Where the rustdoc output for a crate version is kept, if it was uploaded.
+pub fn get_docs_dir<P: AsRef<Path>>(root: P, name: &str, vers: &semver::Version) -> PathBuf {
+    root.as_ref().join(name).join("docs").join(vers.to_string())
+}
+
+/// Remove the docs for a crate version from storage.
+///
+/// Docs which were never uploaded aren't an error.
+pub fn delete_docs<P: AsRef<Path>>(
+    root: P,
+    name: &str,
+    vers: &semver::Version,
+) -> std::io::Result<()> {
+    let dir = get_docs_dir(root.as_ref(), name, vers);
+    match fs::remove_dir_all(&dir) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    // Fails when docs for other versions are left, which is fine.
+    let _ = fs::remove_dir(dir.parent().unwrap());
+    Ok(())
+}
+
+/// The total size of the files under `root`.
+pub fn dir_size<P: AsRef<Path>>(root: P) -> std::io::Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(root)? {
+        let entry = entry?;
+        let kind = entry.file_type()?;
+        if kind.is_dir() {
+            size += dir_size(entry.path())?;
+        } else if kind.is_file() {
+            size += entry.metadata()?.len();
+        }
+    }
+    Ok(size)
+}
+
+/// A `.crate` file found in storage.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredCrate {
+    pub name: String,
+    /// As it appears in the file name, which may not be a valid version.
+    pub vers: String,
+    pub path: PathBuf,
+}
+
+/// Find every `.crate` file in storage.
+pub fn list_crate_files<P: AsRef<Path>>(root: P) -> std::io::Result<Vec<StoredCrate>> {
+    let mut acc = vec![];
+    let crate_dirs = match fs::read_dir(root) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(acc),
+        Err(e) => return Err(e),
+    };
+    for dir in crate_dirs {
+        let dir = dir?;
+        let name = dir.file_name().to_string_lossy().into_owned();
+        if !dir.file_type()?.is_dir() || name == LOST_AND_FOUND {
+            continue;
+        }
+        let prefix = format!("{}-", name);
+        for file in fs::read_dir(dir.path())? {
+            let path = file?.path();
+            let file_name = path.file_name().unwrap().to_string_lossy().into_owned();
+            if let Some(vers) = file_name
+                .strip_prefix(&prefix)
+                .and_then(|rest| rest.strip_suffix(".crate"))
+            {
+                acc.push(StoredCrate {
+                    name: name.clone(),
+                    vers: vers.to_string(),
+                    path,
+                });
+            }
+        }
+    }
+    acc.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(acc)
+}
+
+/// Move a file out of the way into the lost and found dir, keeping the dir
+/// it was in as part of its new name.
+///
+/// Returns where it ended up.
+pub fn move_to_lost_and_found<P: AsRef<Path>>(root: P, path: &Path) -> std::io::Result<PathBuf> {
+    let root = root.as_ref();
+    let relative = path.strip_prefix(root).unwrap_or(path);
+    let dest = root.join(LOST_AND_FOUND).join(relative);
+    fs::create_dir_all(dest.parent().unwrap())?;
+    fs::rename(path, &dest)?;
+    // Fails when there are other versions (or docs) left, which is fine.
+    let _ = fs::remove_dir(path.parent().unwrap());
+    Ok(dest)
+}
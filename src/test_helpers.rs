@@ -1,9 +1,10 @@
-use crate::database::SqlDatabase;
-use crate::package_index::{Config, PackageIndex};
+use crate::database::{Database, SqlDatabase};
+use crate::package_index::{Config, IndexHandle, PackageIndex, PackageVersion};
 use crate::Settings;
 use actix_web::web;
-use std::path::{Path, PathBuf};
-use std::sync::{Mutex, Arc};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
 use tempdir::TempDir;
 
 /// This is the request body sent to the publish endpoint from an empty bin crate.
@@ -12,24 +13,125 @@ pub const MY_CRATE_0_1_0: &[u8] = include_bytes!(concat!(
     "/test_data/publish-my-crate-body"
 ));
 
+/// Build a request body for the publish endpoint.
+///
+/// The body is the metadata json followed by the `.crate` file, each prefixed
+/// with its length as a little endian `u32`.
+pub fn make_publish_body(metadata: &serde_json::Value, crate_file: &[u8]) -> Vec<u8> {
+    let metadata = serde_json::to_vec(metadata).unwrap();
+    let mut body = vec![];
+    body.extend(&(metadata.len() as u32).to_le_bytes());
+    body.extend(&metadata);
+    body.extend(&(crate_file.len() as u32).to_le_bytes());
+    body.extend(crate_file);
+    body
+}
+
+/// Publish metadata for a crate with the given dependencies and nothing else
+/// of interest.
+pub fn make_publish_metadata(name: &str, vers: &str, deps: serde_json::Value) -> serde_json::Value {
+    serde_json::json!({
+        "name": name,
+        "vers": vers,
+        "deps": deps,
+        "features": {},
+        "authors": [],
+        "description": null,
+        "documentation": null,
+        "homepage": null,
+        "keywords": [],
+        "categories": [],
+        "license": null,
+        "repository": null,
+        "links": null,
+    })
+}
+
+/// An index entry with no dependencies, features or checksum. Anything else
+/// can be filled in with struct update syntax.
+pub fn pkg(name: &str, vers: &str) -> PackageVersion {
+    PackageVersion {
+        name: name.to_string(),
+        vers: vers.parse().unwrap(),
+        deps: vec![],
+        cksum: String::new(),
+        features: Default::default(),
+        yanked: false,
+        links: None,
+        extra: Default::default(),
+    }
+}
+
+/// Build a `.crate` file, a gzipped tarball with the files under
+/// `{name}-{vers}/`.
+pub fn make_crate_file(name: &str, vers: &str, files: &[(&str, &[u8])]) -> Vec<u8> {
+    let gz = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+    let mut builder = tar::Builder::new(gz);
+    for (path, contents) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, format!("{}-{}/{}", name, vers, path), *contents)
+            .unwrap();
+    }
+    builder.into_inner().unwrap().finish().unwrap()
+}
+
 pub fn get_data_root() -> TempDir {
     TempDir::new("estuary_test").unwrap()
 }
 
-pub fn get_test_package_index(data_dir: &Path) -> web::Data<Mutex<PackageIndex>> {
+/// Must be called from within an actix system since the index writer is
+/// spawned as a task.
+pub fn get_test_package_index(data_dir: &Path) -> web::Data<IndexHandle> {
     let config = Config {
         api: String::new(),
         dl: String::new(),
     };
-    web::Data::new(Mutex::new(PackageIndex::init(data_dir, &config).unwrap()))
+    web::Data::new(IndexHandle::spawn(
+        PackageIndex::init(data_dir, &config).unwrap(),
+    ))
+}
+
+/// A fresh, migrated, in-memory database.
+///
+/// Each connection to `sqlite::memory:` gets its own database, so the pool is
+/// limited to a single connection.
+pub async fn get_test_db() -> Arc<SqlDatabase> {
+    let db = SqlDatabase::new("sqlite::memory:", 1, Duration::from_secs(10))
+        .await
+        .unwrap();
+    db.migrate().await.unwrap();
+    Arc::new(db)
 }
 
-pub fn get_test_settings(data_dir: &Path) -> web::Data<Settings> {
+pub async fn get_test_settings(data_dir: &Path) -> web::Data<Settings> {
     let settings = Settings {
-        crate_dir: data_dir.join("crates").to_path_buf(),
-        index_dir: data_dir.join("index").to_path_buf(),
-        git_binary: PathBuf::from("git"),
+        crate_dir: data_dir.join("crates"),
+        index_dir: data_dir.join("index"),
         publish_key: None,
+        base_url: String::from("http://localhost"),
+        redis_uri: None,
+        trusted_proxies: vec![],
+        db: get_test_db().await,
     };
     web::Data::new(settings)
 }
+
+/// Generate an api key for the default admin user, suitable for use as the
+/// value of the `Authorization` header.
+pub async fn get_test_api_key(settings: &Settings) -> String {
+    let admin = settings
+        .db
+        .get_user(String::from("admin"))
+        .await
+        .unwrap()
+        .unwrap();
+    settings
+        .db
+        .generate_api_key(String::from("test"), &admin)
+        .await
+        .unwrap()
+}
@@ -0,0 +1,81 @@
+//! Serving https directly, without a proxy in front.
+
+use crate::errors::ConfigError;
+use rustls::{Certificate, PrivateKey, ServerConfig};
+use rustls_pemfile::Item;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+fn open(key: &'static str, path: &Path) -> Result<BufReader<File>, ConfigError> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| ConfigError::Invalid(key, format!("can't read `{}` ({})", path.display(), e)))
+}
+
+/// Load the certificate chain and private key, both PEM encoded.
+///
+/// The chain should start with the server's own certificate. The key may be
+/// PKCS#8, PKCS#1 (RSA) or SEC1 (EC).
+pub fn load_server_config(cert: &Path, key: &Path) -> Result<ServerConfig, ConfigError> {
+    let certs = rustls_pemfile::certs(&mut open("tls.cert", cert)?)
+        .map_err(|e| ConfigError::Invalid("tls.cert", e.to_string()))?;
+    if certs.is_empty() {
+        return Err(ConfigError::Invalid(
+            "tls.cert",
+            format!("no certificates found in `{}`", cert.display()),
+        ));
+    }
+
+    let mut reader = open("tls.key", key)?;
+    let key = loop {
+        match rustls_pemfile::read_one(&mut reader)
+            .map_err(|e| ConfigError::Invalid("tls.key", e.to_string()))?
+        {
+            Some(Item::PKCS8Key(key)) | Some(Item::RSAKey(key)) | Some(Item::ECKey(key)) => {
+                break key
+            }
+            Some(_) => continue,
+            None => {
+                return Err(ConfigError::Invalid(
+                    "tls.key",
+                    format!("no private key found in `{}`", key.display()),
+                ))
+            }
+        }
+    };
+
+    ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(
+            certs.into_iter().map(Certificate).collect(),
+            PrivateKey(key),
+        )
+        .map_err(|e| ConfigError::Invalid("tls", e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn test_data(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test_data/tls")
+            .join(name)
+    }
+
+    #[test]
+    fn test_load_server_config() {
+        assert!(load_server_config(&test_data("cert.pem"), &test_data("key.pem")).is_ok());
+
+        // Mixing the two up is caught.
+        let err = load_server_config(&test_data("key.pem"), &test_data("cert.pem")).unwrap_err();
+        assert!(matches!(err, ConfigError::Invalid("tls.cert", _)));
+
+        let err =
+            load_server_config(&test_data("cert.pem"), &test_data("missing.pem")).unwrap_err();
+        assert!(matches!(err, ConfigError::Invalid("tls.key", _)));
+    }
+}
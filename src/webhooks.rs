@@ -0,0 +1,218 @@
+//! Notifying webhooks of changes to the registry.
+//!
+//! After a publish, yank or unyank succeeds, every webhook subscribed to the
+//! event is sent a json payload in a `POST` request. The body is signed with
+//! the webhook's secret, HMAC-SHA256 style, and the hex digest is sent in the
+//! `X-Estuary-Signature` header as `sha256=<digest>`.
+//!
+//! Deliveries happen in the background so they never hold up the request
+//! which caused them. Failed deliveries are retried a few times, backing off
+//! between attempts, and every attempt is logged in the database.
+
+use crate::database::{Database, NewWebhookDelivery, Webhook};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Header carrying the signature of the request body.
+pub const SIGNATURE_HEADER: &str = "X-Estuary-Signature";
+/// Header carrying the name of the event.
+pub const EVENT_HEADER: &str = "X-Estuary-Event";
+
+/// How many times to try delivering a payload before giving up.
+const MAX_ATTEMPTS: i32 = 4;
+/// How long to wait before the first retry. Doubles after each attempt.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+/// How long to wait for the receiver to respond.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The changes webhooks can subscribe to.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEvent {
+    Publish,
+    Yank,
+    Unyank,
+}
+
+impl WebhookEvent {
+    pub const ALL: [WebhookEvent; 3] = [
+        WebhookEvent::Publish,
+        WebhookEvent::Yank,
+        WebhookEvent::Unyank,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WebhookEvent::Publish => "publish",
+            WebhookEvent::Yank => "yank",
+            WebhookEvent::Unyank => "unyank",
+        }
+    }
+}
+
+/// The events a webhook is subscribed to, in the form they're stored in.
+pub fn format_events(events: &[WebhookEvent]) -> String {
+    events
+        .iter()
+        .map(WebhookEvent::as_str)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Whether the webhook wants to hear about the event.
+pub fn is_subscribed(webhook: &Webhook, event: WebhookEvent) -> bool {
+    webhook
+        .events
+        .split(',')
+        .any(|e| e.trim() == event.as_str())
+}
+
+/// The body sent to webhooks.
+#[derive(Debug, Serialize)]
+pub struct Payload {
+    pub event: WebhookEvent,
+    #[serde(rename = "crate")]
+    pub crate_name: String,
+    pub version: String,
+    /// The name of the user who made the change.
+    pub user: String,
+    /// When the change was made, in RFC 3339 format.
+    pub timestamp: String,
+}
+
+/// A random secret for a new webhook.
+pub fn generate_secret() -> String {
+    let mut secret = [0; 32];
+    OsRng.fill_bytes(&mut secret);
+    hex::encode(secret)
+}
+
+/// Sign a request body with a webhook's secret.
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    // HMAC accepts keys of any length, so this can't fail.
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Send the payload to every webhook subscribed to its event.
+///
+/// This returns right away, with the deliveries carrying on in the background.
+pub fn notify(db: Arc<dyn Database>, payload: Payload) {
+    actix_web::rt::spawn(async move {
+        let webhooks = match db.get_webhooks().await {
+            Ok(webhooks) => webhooks,
+            Err(e) => {
+                log::error!("Failed to look up webhooks: {}", e);
+                return;
+            }
+        };
+        let body = match serde_json::to_string(&payload) {
+            Ok(body) => body,
+            Err(e) => {
+                log::error!("Failed to serialize webhook payload: {}", e);
+                return;
+            }
+        };
+        let client = reqwest::Client::new();
+        let deliveries = webhooks
+            .into_iter()
+            .filter(|webhook| is_subscribed(webhook, payload.event))
+            .map(|webhook| deliver(db.as_ref(), &client, webhook, payload.event, &body));
+        futures::future::join_all(deliveries).await;
+    });
+}
+
+/// Send a payload to a webhook, retrying until it's accepted or we run out of
+/// attempts.
+async fn deliver(
+    db: &dyn Database,
+    client: &reqwest::Client,
+    webhook: Webhook,
+    event: WebhookEvent,
+    body: &str,
+) {
+    let signature = sign(&webhook.secret, body.as_bytes());
+    let mut delay = RETRY_DELAY;
+
+    for attempt in 1..=MAX_ATTEMPTS {
+        let res = client
+            .post(&webhook.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(SIGNATURE_HEADER, &signature)
+            .header(EVENT_HEADER, event.as_str())
+            .timeout(TIMEOUT)
+            .body(body.to_string())
+            .send()
+            .await;
+
+        let (status, error) = match res {
+            Ok(resp) if resp.status().is_success() => (Some(resp.status()), None),
+            Ok(resp) => (
+                Some(resp.status()),
+                Some(format!("Receiver responded with {}", resp.status())),
+            ),
+            Err(e) => (None, Some(e.to_string())),
+        };
+
+        let delivery = NewWebhookDelivery {
+            webhook_id: webhook.id,
+            event: event.as_str().to_string(),
+            payload: body.to_string(),
+            attempt,
+            status: status.map(|s| i32::from(s.as_u16())),
+            error: error.clone(),
+        };
+        if let Err(e) = db.add_webhook_delivery(delivery).await {
+            log::error!("Failed to log delivery to webhook {}: {}", webhook.id, e);
+        }
+
+        match error {
+            None => return,
+            Some(e) => log::warn!(
+                "Delivery to webhook {} failed (attempt {} of {}): {}",
+                webhook.id,
+                attempt,
+                MAX_ATTEMPTS,
+                e
+            ),
+        }
+
+        if attempt < MAX_ATTEMPTS {
+            actix_web::rt::time::sleep(delay).await;
+            delay *= 2;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign() {
+        // From RFC 4231, test case 2.
+        assert_eq!(
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+            sign("Jefe", b"what do ya want for nothing?")
+        );
+    }
+
+    #[test]
+    fn test_is_subscribed() {
+        let webhook = Webhook {
+            id: 1,
+            url: String::from("http://localhost/hook"),
+            secret: String::from("secret"),
+            events: format_events(&[WebhookEvent::Publish, WebhookEvent::Unyank]),
+            created_at: 0,
+        };
+        assert!(is_subscribed(&webhook, WebhookEvent::Publish));
+        assert!(!is_subscribed(&webhook, WebhookEvent::Yank));
+        assert!(is_subscribed(&webhook, WebhookEvent::Unyank));
+    }
+}